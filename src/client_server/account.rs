@@ -268,7 +268,8 @@ pub fn change_password_route(
         .filter_map(|id| id.ok())
         .filter(|id| id != device_id)
     {
-        db.users.remove_device(&sender_id, &id)?;
+        db.users
+            .remove_device(&sender_id, &id, &db.rooms, &db.globals, &db.sending)?;
     }
 
     Ok(change_password::Response.into())
@@ -354,7 +355,7 @@ pub fn deactivate_route(
             third_party_invite: None,
         };
 
-        db.rooms.append_pdu(
+        db.rooms.build_and_append_pdu(
             PduBuilder {
                 room_id: room_id.clone(),
                 sender: sender_id.clone(),
@@ -366,11 +367,13 @@ pub fn deactivate_route(
             },
             &db.globals,
             &db.account_data,
+            &db.sending,
         )?;
     }
 
     // Remove devices and mark account as deactivated
-    db.users.deactivate_account(&sender_id)?;
+    db.users
+        .deactivate_account(&sender_id, &db.rooms, &db.globals, &db.sending)?;
 
     Ok(deactivate::Response {
         id_server_unbind_result: ThirdPartyIdRemovalStatus::NoSupport,
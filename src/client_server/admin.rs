@@ -0,0 +1,79 @@
+use super::State;
+use crate::{AccessToken, Database, Error, Result};
+use rocket::response::content::Json;
+use ruma::{api::client::error::ErrorKind, RoomId};
+use serde_json::json;
+use std::convert::TryFrom;
+
+#[cfg(feature = "conduit_bin")]
+use rocket::{get, post};
+
+/// # `GET /_conduit/admin/rooms/{roomId}/failed_events`
+///
+/// Lists the events of a room that came over federation and were rejected or soft failed.
+/// Only users listed as `admins` in the config may use this.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_conduit/admin/rooms/<room_id>/failed_events")
+)]
+pub fn get_failed_events_route(
+    db: State<'_, Database<'_>>,
+    room_id: String,
+    access_token: AccessToken,
+) -> Result<Json<String>> {
+    check_admin(&db, access_token.0.as_deref())?;
+
+    let room_id = RoomId::try_from(room_id)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid room id."))?;
+
+    let failed_events = db
+        .rooms
+        .pdu_failures(&room_id)
+        .filter_map(|r| r.ok())
+        .map(|(event_id, failure)| json!({ "event_id": event_id, "failure": failure }))
+        .collect::<Vec<_>>();
+
+    Ok(Json(json!({ "failed_events": failed_events }).to_string()))
+}
+
+/// # `POST /_conduit/admin/media/clear_remote_cache`
+///
+/// Removes all media we downloaded from other servers. It is downloaded again when it is used.
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_conduit/admin/media/clear_remote_cache")
+)]
+pub fn clear_remote_media_cache_route(
+    db: State<'_, Database<'_>>,
+    access_token: AccessToken,
+) -> Result<Json<String>> {
+    check_admin(&db, access_token.0.as_deref())?;
+
+    db.media.clear_remote_cache()?;
+
+    Ok(Json(json!({}).to_string()))
+}
+
+fn check_admin(db: &Database<'_>, access_token: Option<&str>) -> Result<()> {
+    let access_token = access_token.ok_or(Error::BadRequest(
+        ErrorKind::MissingToken,
+        "Missing access token.",
+    ))?;
+
+    let (user_id, _) = db
+        .users
+        .find_from_token(access_token)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::UnknownToken,
+            "Unknown access token.",
+        ))?;
+
+    if !db.globals.is_admin(&user_id) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You are not an admin of this server.",
+        ));
+    }
+
+    Ok(())
+}
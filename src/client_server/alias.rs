@@ -1,11 +1,8 @@
 use super::State;
 use crate::{server_server, ConduitResult, Database, Error, Ruma};
-use ruma::api::{
-    client::{
-        error::ErrorKind,
-        r0::alias::{create_alias, delete_alias, get_alias},
-    },
-    federation,
+use ruma::api::client::{
+    error::ErrorKind,
+    r0::alias::{create_alias, delete_alias, get_alias},
 };
 
 #[cfg(feature = "conduit_bin")]
@@ -51,20 +48,10 @@ pub async fn get_alias_route(
     body: Ruma<get_alias::IncomingRequest>,
 ) -> ConduitResult<get_alias::Response> {
     if body.room_alias.server_name() != db.globals.server_name() {
-        let response = server_server::send_request(
-            &db,
-            body.room_alias.server_name().to_string(),
-            federation::query::get_room_information::v1::Request {
-                room_alias: body.room_alias.to_string(),
-            },
-        )
-        .await?;
+        let (room_id, servers) =
+            server_server::query_remote_alias(&db.globals, &body.room_alias).await?;
 
-        return Ok(get_alias::Response {
-            room_id: response.room_id,
-            servers: response.servers,
-        }
-        .into());
+        return Ok(get_alias::Response { room_id, servers }.into());
     }
 
     let room_id = db
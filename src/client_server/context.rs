@@ -32,10 +32,14 @@ pub fn get_context_route(
         ))?
         .to_room_event();
 
+    // Outliers and soft failed events are not part of the timeline
     let base_token = db
         .rooms
         .get_pdu_count(&body.event_id)?
-        .expect("event still exists");
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Base event not found.",
+        ))?;
 
     let events_before = db
         .rooms
@@ -70,6 +74,15 @@ pub fn get_context_route(
 
     let end_token = events_after.last().map(|(count, _)| count.to_string());
 
+    // The state after the last event we return
+    let state_event_id = events_after
+        .last()
+        .map_or(&body.event_id, |(_, pdu)| &pdu.event_id);
+    let state = match db.rooms.state_after(state_event_id)? {
+        Some(state) => db.rooms.state_pdus(&state)?,
+        None => db.rooms.room_state_full(&body.room_id)?,
+    };
+
     let events_after = events_after
         .into_iter()
         .map(|(_, pdu)| pdu.to_room_event())
@@ -81,12 +94,7 @@ pub fn get_context_route(
         events_before,
         event: Some(base_event),
         events_after,
-        state: db // TODO: State at event
-            .rooms
-            .room_state_full(&body.room_id)?
-            .values()
-            .map(|pdu| pdu.to_state_event())
-            .collect(),
+        state: state.values().map(|pdu| pdu.to_state_event()).collect(),
     }
     .into())
 }
@@ -116,7 +116,13 @@ pub fn delete_device_route(
         return Err(Error::Uiaa(uiaainfo));
     }
 
-    db.users.remove_device(&sender_id, &body.body.device_id)?;
+    db.users.remove_device(
+        &sender_id,
+        &body.body.device_id,
+        &db.rooms,
+        &db.globals,
+        &db.sending,
+    )?;
 
     Ok(delete_device::Response.into())
 }
@@ -163,7 +169,8 @@ pub fn delete_devices_route(
     }
 
     for device_id in &body.devices {
-        db.users.remove_device(&sender_id, &device_id)?
+        db.users
+            .remove_device(&sender_id, &device_id, &db.rooms, &db.globals, &db.sending)?
     }
 
     Ok(delete_devices::Response.into())
@@ -37,8 +37,10 @@ pub async fn get_public_rooms_filtered_route(
         .clone()
         .filter(|server| server != &db.globals.server_name().as_str())
     {
+        server_server::check_federation_allowed(&db.globals, &other_server)?;
+
         let response = server_server::send_request(
-            &db,
+            &db.globals,
             other_server,
             federation::directory::get_public_rooms::v1::Request {
                 limit: body.limit,
@@ -258,6 +260,7 @@ pub async fn get_public_rooms_route(
             },
         sender_id,
         device_id,
+        origin,
         json_body,
     } = body;
 
@@ -278,6 +281,7 @@ pub async fn get_public_rooms_route(
             },
             sender_id,
             device_id,
+            origin,
             json_body,
         },
     )
@@ -1,19 +1,32 @@
 use super::{State, SESSION_ID_LENGTH};
-use crate::{utils, ConduitResult, Database, Error, Ruma};
+use crate::{server_server, utils, ConduitResult, Database, Error, Result, Ruma};
+use log::warn;
+use rocket::futures::future::join_all;
 use ruma::{
-    api::client::{
-        error::ErrorKind,
-        r0::{
-            keys::{
-                claim_keys, get_key_changes, get_keys, upload_keys, upload_signatures,
-                upload_signing_keys,
+    api::{
+        client::{
+            error::ErrorKind,
+            r0::{
+                keys::{
+                    claim_keys, get_key_changes, get_keys, upload_keys, upload_signatures,
+                    upload_signing_keys, OneTimeKey,
+                },
+                uiaa::{AuthFlow, UiaaInfo},
             },
-            uiaa::{AuthFlow, UiaaInfo},
         },
+        federation,
     },
-    encryption::UnsignedDeviceInfo,
+    encryption::{DeviceKeys, UnsignedDeviceInfo},
+    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, ServerName, UserId,
 };
-use std::collections::{BTreeMap, HashSet};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::Duration,
+};
+
+/// How long we wait for other servers to answer key queries and claims.
+const FEDERATION_KEYS_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post};
@@ -39,8 +52,14 @@ pub fn upload_keys_route(
     if let Some(device_keys) = &body.device_keys {
         // This check is needed to assure that signatures are kept
         if db.users.get_device_keys(sender_id, device_id)?.is_none() {
-            db.users
-                .add_device_keys(sender_id, device_id, device_keys, &db.rooms, &db.globals)?;
+            db.users.add_device_keys(
+                sender_id,
+                device_id,
+                device_keys,
+                &db.rooms,
+                &db.globals,
+                &db.sending,
+            )?;
         }
     }
 
@@ -54,7 +73,7 @@ pub fn upload_keys_route(
     feature = "conduit_bin",
     post("/_matrix/client/r0/keys/query", data = "<body>")
 )]
-pub fn get_keys_route(
+pub async fn get_keys_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_keys::IncomingRequest>,
 ) -> ConduitResult<get_keys::Response> {
@@ -64,49 +83,24 @@ pub fn get_keys_route(
     let mut self_signing_keys = BTreeMap::new();
     let mut user_signing_keys = BTreeMap::new();
     let mut device_keys = BTreeMap::new();
+    let mut failures = BTreeMap::new();
 
-    for (user_id, device_ids) in &body.device_keys {
-        if device_ids.is_empty() {
-            let mut container = BTreeMap::new();
-            for device_id in db.users.all_device_ids(user_id) {
-                let device_id = device_id?;
-                if let Some(mut keys) = db.users.get_device_keys(user_id, &device_id)? {
-                    let metadata = db
-                        .users
-                        .get_device_metadata(user_id, &device_id)?
-                        .ok_or_else(|| {
-                            Error::bad_database("all_device_keys contained nonexistent device.")
-                        })?;
-
-                    keys.unsigned = Some(UnsignedDeviceInfo {
-                        device_display_name: metadata.display_name,
-                    });
-
-                    container.insert(device_id, keys);
-                }
-            }
-            device_keys.insert(user_id.clone(), container);
-        } else {
-            for device_id in device_ids {
-                let mut container = BTreeMap::new();
-                if let Some(mut keys) = db.users.get_device_keys(&user_id.clone(), &device_id)? {
-                    let metadata = db.users.get_device_metadata(user_id, &device_id)?.ok_or(
-                        Error::BadRequest(
-                            ErrorKind::InvalidParam,
-                            "Tried to get keys for nonexistent device.",
-                        ),
-                    )?;
-
-                    keys.unsigned = Some(UnsignedDeviceInfo {
-                        device_display_name: metadata.display_name,
-                    });
+    let mut remote_queries = HashMap::new();
 
-                    container.insert(device_id.clone(), keys);
-                }
-                device_keys.insert(user_id.clone(), container);
-            }
+    for (user_id, device_ids) in &body.device_keys {
+        if user_id.server_name() != db.globals.server_name() {
+            remote_queries
+                .entry(user_id.server_name().to_owned())
+                .or_insert_with(BTreeMap::new)
+                .insert(user_id.clone(), device_ids.clone());
+            continue;
         }
 
+        device_keys.insert(
+            user_id.clone(),
+            get_local_device_keys(&db, user_id, device_ids)?,
+        );
+
         if let Some(master_key) = db.users.get_master_key(user_id, sender_id)? {
             master_keys.insert(user_id.clone(), master_key);
         }
@@ -120,47 +114,235 @@ pub fn get_keys_route(
         }
     }
 
+    // Servers are asked in parallel, so one slow server doesn't delay the others
+    let db = &*db;
+    let results = join_all(
+        remote_queries
+            .into_iter()
+            .map(|(server, queries)| async move {
+                let result = tokio::time::timeout(
+                    FEDERATION_KEYS_TIMEOUT,
+                    get_remote_device_keys(db, &server, queries),
+                )
+                .await;
+                (server, result)
+            }),
+    )
+    .await;
+
+    for (server, result) in results {
+        match result {
+            Ok(Ok(keys)) => device_keys.extend(keys),
+            Ok(Err(e)) => {
+                warn!("Failed to query keys from {}: {}", server, e);
+                failures.insert(server.to_string(), json!({ "status": 503 }));
+            }
+            Err(_) => {
+                failures.insert(server.to_string(), json!({ "status": 504 }));
+            }
+        }
+    }
+
     Ok(get_keys::Response {
         master_keys,
         self_signing_keys,
         user_signing_keys,
         device_keys,
-        failures: BTreeMap::new(),
+        failures,
     }
     .into())
 }
 
+/// Returns the keys of the given devices of one of our users, or the keys of all their devices
+/// if no device ids are given.
+pub fn get_local_device_keys(
+    db: &Database<'_>,
+    user_id: &UserId,
+    device_ids: &[Box<DeviceId>],
+) -> Result<BTreeMap<Box<DeviceId>, DeviceKeys>> {
+    let device_ids = if device_ids.is_empty() {
+        db.users
+            .all_device_ids(user_id)
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        device_ids.to_vec()
+    };
+
+    let mut container = BTreeMap::new();
+    for device_id in device_ids {
+        if let Some(mut keys) = db.users.get_device_keys(user_id, &device_id)? {
+            let metadata =
+                db.users
+                    .get_device_metadata(user_id, &device_id)?
+                    .ok_or(Error::BadRequest(
+                        ErrorKind::InvalidParam,
+                        "Tried to get keys for nonexistent device.",
+                    ))?;
+
+            keys.unsigned = Some(UnsignedDeviceInfo {
+                device_display_name: metadata.display_name,
+            });
+
+            container.insert(device_id, keys);
+        }
+    }
+
+    Ok(container)
+}
+
+/// Asks another server for the device keys of its users. We request the whole device list of
+/// users without device ids.
+async fn get_remote_device_keys(
+    db: &Database<'_>,
+    server: &ServerName,
+    queries: BTreeMap<UserId, Vec<Box<DeviceId>>>,
+) -> Result<BTreeMap<UserId, BTreeMap<Box<DeviceId>, DeviceKeys>>> {
+    let mut device_keys = BTreeMap::new();
+    let mut device_queries = BTreeMap::new();
+
+    for (user_id, device_ids) in queries {
+        if !device_ids.is_empty() {
+            device_queries.insert(user_id, device_ids);
+            continue;
+        }
+
+        let response = server_server::send_request(
+            &db.globals,
+            server.to_string(),
+            federation::device::get_devices::v1::Request {
+                user_id: user_id.clone(),
+            },
+        )
+        .await?;
+
+        let mut container = BTreeMap::new();
+        for device in response.devices {
+            let mut keys = device.keys;
+            keys.unsigned = Some(UnsignedDeviceInfo {
+                device_display_name: device.device_display_name,
+            });
+            container.insert(device.device_id, keys);
+        }
+        device_keys.insert(user_id, container);
+    }
+
+    if !device_queries.is_empty() {
+        let response = server_server::send_request(
+            &db.globals,
+            server.to_string(),
+            federation::keys::get_keys::v1::Request {
+                device_keys: device_queries,
+            },
+        )
+        .await?;
+
+        // Servers can only answer for their own users
+        device_keys.extend(
+            response
+                .device_keys
+                .into_iter()
+                .filter(|(user_id, _)| user_id.server_name() == server),
+        );
+    }
+
+    Ok(device_keys)
+}
+
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/keys/claim", data = "<body>")
 )]
-pub fn claim_keys_route(
+pub async fn claim_keys_route(
     db: State<'_, Database<'_>>,
     body: Ruma<claim_keys::Request>,
 ) -> ConduitResult<claim_keys::Response> {
     let mut one_time_keys = BTreeMap::new();
+    let mut failures = BTreeMap::new();
+
+    let mut remote_claims = HashMap::new();
+
     for (user_id, map) in &body.one_time_keys {
-        let mut container = BTreeMap::new();
-        for (device_id, key_algorithm) in map {
-            if let Some(one_time_keys) =
-                db.users
-                    .take_one_time_key(user_id, device_id, key_algorithm, &db.globals)?
-            {
-                let mut c = BTreeMap::new();
-                c.insert(one_time_keys.0, one_time_keys.1);
-                container.insert(device_id.clone(), c);
+        if user_id.server_name() != db.globals.server_name() {
+            remote_claims
+                .entry(user_id.server_name().to_owned())
+                .or_insert_with(BTreeMap::new)
+                .insert(user_id.clone(), map.clone());
+            continue;
+        }
+
+        one_time_keys.insert(
+            user_id.clone(),
+            claim_local_one_time_keys(&db, user_id, map)?,
+        );
+    }
+
+    let db = &*db;
+    let results = join_all(
+        remote_claims
+            .into_iter()
+            .map(|(server, claims)| async move {
+                let result = tokio::time::timeout(
+                    FEDERATION_KEYS_TIMEOUT,
+                    server_server::send_request(
+                        &db.globals,
+                        server.to_string(),
+                        federation::keys::claim_keys::v1::Request {
+                            one_time_keys: claims,
+                        },
+                    ),
+                )
+                .await;
+                (server, result)
+            }),
+    )
+    .await;
+
+    for (server, result) in results {
+        match result {
+            Ok(Ok(response)) => one_time_keys.extend(
+                response
+                    .one_time_keys
+                    .into_iter()
+                    .filter(|(user_id, _)| user_id.server_name() == &*server),
+            ),
+            Ok(Err(e)) => {
+                warn!("Failed to claim keys from {}: {}", server, e);
+                failures.insert(server.to_string(), json!({ "status": 503 }));
+            }
+            Err(_) => {
+                failures.insert(server.to_string(), json!({ "status": 504 }));
             }
         }
-        one_time_keys.insert(user_id.clone(), container);
     }
 
     Ok(claim_keys::Response {
-        failures: BTreeMap::new(),
+        failures,
         one_time_keys,
     }
     .into())
 }
 
+/// Takes one one-time key of each given device of one of our users.
+pub fn claim_local_one_time_keys(
+    db: &Database<'_>,
+    user_id: &UserId,
+    devices: &BTreeMap<Box<DeviceId>, DeviceKeyAlgorithm>,
+) -> Result<BTreeMap<Box<DeviceId>, BTreeMap<DeviceKeyId, OneTimeKey>>> {
+    let mut container = BTreeMap::new();
+    for (device_id, key_algorithm) in devices {
+        if let Some(one_time_key) =
+            db.users
+                .take_one_time_key(user_id, device_id, key_algorithm, &db.globals)?
+        {
+            let mut c = BTreeMap::new();
+            c.insert(one_time_key.0, one_time_key.1);
+            container.insert(device_id.clone(), c);
+        }
+    }
+
+    Ok(container)
+}
+
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/unstable/keys/device_signing/upload", data = "<body>")
@@ -210,6 +392,7 @@ pub fn upload_signing_keys_route(
             &body.user_signing_key,
             &db.rooms,
             &db.globals,
+            &db.sending,
         )?;
     }
 
@@ -266,6 +449,7 @@ pub fn upload_signatures_route(
                     &sender_id,
                     &db.rooms,
                     &db.globals,
+                    &db.sending,
                 )?;
             }
         }
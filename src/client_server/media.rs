@@ -1,8 +1,14 @@
 use super::State;
-use crate::{database::media::FileMeta, utils, ConduitResult, Database, Error, Ruma};
-use ruma::api::client::{
-    error::ErrorKind,
-    r0::media::{create_content, get_content, get_content_thumbnail, get_media_config},
+use crate::{
+    database::media::FileMeta, server_server, utils, ConduitResult, Database, Error, Result, Ruma,
+};
+use log::warn;
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        r0::media::{create_content, get_content, get_content_thumbnail, get_media_config},
+    },
+    ServerName,
 };
 
 #[cfg(feature = "conduit_bin")]
@@ -51,19 +57,32 @@ pub fn create_content_route(
         data = "<body>"
     )
 )]
-pub fn get_content_route(
+pub async fn get_content_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_content::Request>,
     _server_name: String,
     _media_id: String,
 ) -> ConduitResult<get_content::Response> {
+    let mxc = format!("mxc://{}/{}", body.server_name, body.media_id);
+
+    let file_meta = if &*body.server_name == db.globals.server_name() {
+        db.media.get(mxc)?
+    } else {
+        get_remote_content(
+            &db,
+            mxc,
+            &body.server_name,
+            &body.media_id,
+            body.allow_remote,
+        )
+        .await?
+    };
+
     if let Some(FileMeta {
         filename,
         content_type,
         file,
-    }) = db
-        .media
-        .get(format!("mxc://{}/{}", body.server_name, body.media_id))?
+    }) = file_meta
     {
         Ok(get_content::Response {
             file,
@@ -83,25 +102,100 @@ pub fn get_content_route(
         data = "<body>"
     )
 )]
-pub fn get_content_thumbnail_route(
+pub async fn get_content_thumbnail_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_content_thumbnail::Request>,
     _server_name: String,
     _media_id: String,
 ) -> ConduitResult<get_content_thumbnail::Response> {
+    let mxc = format!("mxc://{}/{}", body.server_name, body.media_id);
+    let width = body
+        .width
+        .try_into()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Width is invalid."))?;
+    let height = body
+        .height
+        .try_into()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Height is invalid."))?;
+
+    let file_meta = if &*body.server_name == db.globals.server_name() {
+        db.media.get_thumbnail(mxc, width, height)?
+    } else {
+        // Thumbnails of remote media are generated from the cached original
+        get_remote_content(
+            &db,
+            mxc.clone(),
+            &body.server_name,
+            &body.media_id,
+            body.allow_remote,
+        )
+        .await?;
+        db.media.get_remote_thumbnail(mxc, width, height)?
+    };
+
     if let Some(FileMeta {
         content_type, file, ..
-    }) = db.media.get_thumbnail(
-        format!("mxc://{}/{}", body.server_name, body.media_id),
-        body.width
-            .try_into()
-            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Width is invalid."))?,
-        body.height
-            .try_into()
-            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Width is invalid."))?,
-    )? {
+    }) = file_meta
+    {
         Ok(get_content_thumbnail::Response { file, content_type }.into())
     } else {
         Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."))
     }
 }
+
+/// Returns media of another server from the remote media cache. Media we don't have yet is
+/// downloaded from its server, unless the client doesn't allow that.
+async fn get_remote_content(
+    db: &Database<'_>,
+    mxc: String,
+    server_name: &ServerName,
+    media_id: &str,
+    allow_remote: bool,
+) -> Result<Option<FileMeta>> {
+    if let Some(file_meta) = db.media.get_remote(mxc.clone())? {
+        return Ok(Some(file_meta));
+    }
+
+    if !allow_remote {
+        return Ok(None);
+    }
+
+    // Other servers can't make us store more than our own users could upload
+    let response = server_server::send_request_with_limit(
+        &db.globals,
+        server_name.to_string(),
+        get_content::Request {
+            allow_remote: false,
+            server_name: server_name.to_owned(),
+            media_id: media_id.to_owned(),
+        },
+        Some(db.globals.max_request_size().into()),
+    )
+    .await
+    .map_err(|e| {
+        warn!("Failed to fetch {} from {}: {}", mxc, server_name, e);
+        Error::BadRequest(ErrorKind::NotFound, "Remote media could not be fetched.")
+    })?;
+
+    if response.file.len() as u64 > u64::from(db.globals.max_request_size()) {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Remote media is too large.",
+        ));
+    }
+
+    let filename = Some(response.content_disposition).filter(|f| !f.is_empty());
+
+    db.media.create_remote(
+        mxc,
+        filename.as_ref(),
+        &response.content_type,
+        &response.file,
+    )?;
+
+    Ok(Some(FileMeta {
+        filename,
+        content_type: response.content_type,
+        file: response.file,
+    }))
+}
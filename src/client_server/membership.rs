@@ -1,7 +1,9 @@
 use super::State;
 use crate::{
-    client_server, pdu::PduBuilder, server_server, utils, ConduitResult, Database, Error, Ruma,
+    client_server, pdu::PduBuilder, room_version::RoomVersion, server_server, utils, ConduitResult,
+    Database, Error, PduEvent, Result, Ruma,
 };
+use log::warn;
 use ruma::{
     api::{
         client::{
@@ -18,13 +20,17 @@ use ruma::{
         federation,
     },
     events::{room::member, EventType},
-    EventId, Raw, RoomId, RoomVersionId,
+    Raw, RoomId, RoomVersionId, ServerName, UserId,
 };
 use std::{collections::BTreeMap, convert::TryFrom};
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post};
 
+/// How often an invite to a remote user is built again when the room changes while the invited
+/// server signs it.
+const MAX_INVITE_ATTEMPTS: usize = 3;
+
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/rooms/<_>/join", data = "<body>")
@@ -37,23 +43,43 @@ pub async fn join_room_by_id_route(
 
     // Ask a remote server if we don't have this room
     if !db.rooms.exists(&body.room_id)? && body.room_id.server_name() != db.globals.server_name() {
+        server_server::check_federation_allowed(&db.globals, body.room_id.server_name().as_str())?;
+
         let make_join_response = server_server::send_request(
-            &db,
+            &db.globals,
             body.room_id.server_name().to_string(),
             federation::membership::create_join_event_template::v1::Request {
                 room_id: body.room_id.clone(),
                 user_id: sender_id.clone(),
-                ver: vec![RoomVersionId::Version5, RoomVersionId::Version6],
+                ver: RoomVersion::supported(),
             },
         )
         .await?;
 
+        // Servers that don't send a room version are in a version 1 room
+        let room_version = RoomVersion::new(
+            make_join_response
+                .room_version
+                .as_ref()
+                .unwrap_or(&RoomVersionId::Version1),
+        )
+        .ok_or(Error::BadServerResponse(
+            "Remote room has a room version this server does not support.",
+        ))?;
+
         let mut join_event_stub_value =
             serde_json::from_str::<serde_json::Value>(make_join_response.event.json().get())
                 .map_err(|_| {
                     Error::BadServerResponse("Invalid make_join event json received from server.")
                 })?;
 
+        // We sign the event, so it has to be exactly the join we asked for
+        if !is_membership_template(&join_event_stub_value, &body.room_id, sender_id, "join") {
+            return Err(Error::BadServerResponse(
+                "Server sent an invalid make_join event.",
+            ));
+        }
+
         let join_event_stub =
             join_event_stub_value
                 .as_object_mut()
@@ -70,39 +96,117 @@ pub async fn join_room_by_id_route(
             utils::millis_since_unix_epoch().into(),
         );
 
-        // Generate event id
-        let event_id = EventId::try_from(&*format!(
-            "${}",
-            ruma::signatures::reference_hash(&join_event_stub_value)
-                .expect("ruma can calculate reference hashes")
-        ))
-        .expect("ruma's reference hashes are valid event ids");
-
-        // We don't leave the event id into the pdu because that's only allowed in v1 or v2 rooms
-        let join_event_stub = join_event_stub_value.as_object_mut().unwrap();
-        join_event_stub.remove("event_id");
-
-        ruma::signatures::hash_and_sign_event(
-            db.globals.server_name().as_str(),
+        let event_id = room_version.hash_and_sign_event(
+            db.globals.server_name(),
             db.globals.keypair(),
             &mut join_event_stub_value,
-        )
-        .expect("event is valid, we just created it");
+        );
 
         let send_join_response = server_server::send_request(
-            &db,
+            &db.globals,
             body.room_id.server_name().to_string(),
             federation::membership::create_join_event::v2::Request {
                 room_id: body.room_id.clone(),
                 event_id,
-                pdu_stub: serde_json::from_value::<Raw<_>>(join_event_stub_value)
-                    .expect("Raw::from_value always works"),
+                pdu_stub: PduEvent::convert_to_outgoing_federation_event(
+                    join_event_stub_value.clone(),
+                    &room_version,
+                ),
             },
         )
         .await?;
 
-        dbg!(send_join_response);
-        todo!("Take send_join_response and 'create' the room using that data");
+        let mut pub_key_map = BTreeMap::new();
+        let mut state = Vec::new();
+
+        // Verify all events we got. They are stored as outliers, so they can be found when we
+        // need them for auth checks later
+        for (pdu, is_state) in send_join_response
+            .room_state
+            .auth_chain
+            .iter()
+            .map(|pdu| (pdu, false))
+            .chain(
+                send_join_response
+                    .room_state
+                    .state
+                    .iter()
+                    .map(|pdu| (pdu, true)),
+            )
+        {
+            let value = serde_json::from_str::<serde_json::Value>(pdu.json().get())
+                .expect("converting raw jsons to values always works");
+            let event_id = server_server::calculate_event_id(&value, &room_version)?;
+
+            let value = match server_server::verify_pdu(
+                &db.globals,
+                &mut pub_key_map,
+                &room_version,
+                &event_id,
+                value,
+            )
+            .await
+            {
+                Ok(value) => value,
+                Err(e) => {
+                    warn!("Dropping pdu {} from send_join response: {}", event_id, e);
+                    continue;
+                }
+            };
+
+            if value.get("room_id").and_then(|r| r.as_str()) != Some(&body.room_id.to_string()) {
+                warn!(
+                    "Dropping pdu {} from send_join response: Wrong room",
+                    event_id
+                );
+                continue;
+            }
+
+            db.rooms.add_pdu_outlier(&value)?;
+
+            if is_state {
+                state.push(value);
+            }
+        }
+
+        if !state
+            .iter()
+            .any(|pdu| pdu.get("type").and_then(|t| t.as_str()) == Some("m.room.create"))
+        {
+            return Err(Error::BadServerResponse(
+                "Room state from send_join response has no create event.",
+            ));
+        }
+
+        let mutex = db.rooms.room_mutex(&body.room_id);
+        let _lock = mutex.lock().unwrap();
+
+        db.rooms.force_state(
+            &body.room_id,
+            &state,
+            &db.globals,
+            &db.account_data,
+            &db.sending,
+        )?;
+
+        // Now we can add our own join event to the timeline
+        let join_pdu = serde_json::from_value::<PduEvent>(join_event_stub_value.clone())
+            .map_err(|_| Error::BadServerResponse("Invalid join event received from server."))?;
+
+        let count = db.globals.next_count()?;
+        db.rooms.append_pdu(
+            &join_pdu,
+            &join_event_stub_value,
+            count,
+            &db.globals,
+            &db.account_data,
+            &db.sending,
+        )?;
+
+        return Ok(join_room_by_id::Response {
+            room_id: body.room_id.clone(),
+        }
+        .into());
     }
 
     let event = member::MemberEventContent {
@@ -113,7 +217,7 @@ pub async fn join_room_by_id_route(
         third_party_invite: None,
     };
 
-    db.rooms.append_pdu(
+    db.rooms.build_and_append_pdu(
         PduBuilder {
             room_id: body.room_id.clone(),
             sender: sender_id.clone(),
@@ -125,6 +229,7 @@ pub async fn join_room_by_id_route(
         },
         &db.globals,
         &db.account_data,
+        &db.sending,
     )?;
 
     Ok(join_room_by_id::Response {
@@ -151,6 +256,7 @@ pub async fn join_room_by_id_or_alias_route(
                     body: alias::get_alias::IncomingRequest { room_alias },
                     sender_id: body.sender_id.clone(),
                     device_id: body.device_id.clone(),
+                    origin: body.origin.clone(),
                     json_body: None,
                 },
             )
@@ -163,6 +269,7 @@ pub async fn join_room_by_id_or_alias_route(
     let body = Ruma {
         sender_id: body.sender_id.clone(),
         device_id: body.device_id.clone(),
+        origin: body.origin.clone(),
         json_body: None,
         body: join_room_by_id::IncomingRequest {
             room_id,
@@ -180,12 +287,24 @@ pub async fn join_room_by_id_or_alias_route(
     feature = "conduit_bin",
     post("/_matrix/client/r0/rooms/<_>/leave", data = "<body>")
 )]
-pub fn leave_room_route(
+pub async fn leave_room_route(
     db: State<'_, Database<'_>>,
     body: Ruma<leave_room::IncomingRequest>,
 ) -> ConduitResult<leave_room::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
+    // Without any of our users in the room, our state of it is incomplete or outdated
+    let is_resident = db
+        .rooms
+        .room_members(&body.room_id)
+        .filter_map(|r| r.ok())
+        .any(|user_id| user_id.server_name() == db.globals.server_name());
+
+    if !is_resident {
+        leave_remote_room(&db, sender_id, &body.room_id).await?;
+        return Ok(leave_room::Response.into());
+    }
+
     let mut event = serde_json::from_value::<Raw<member::MemberEventContent>>(
         db.rooms
             .room_state_get(
@@ -205,7 +324,7 @@ pub fn leave_room_route(
 
     event.membership = member::MembershipState::Leave;
 
-    db.rooms.append_pdu(
+    db.rooms.build_and_append_pdu(
         PduBuilder {
             room_id: body.room_id.clone(),
             sender: sender_id.clone(),
@@ -217,47 +336,321 @@ pub fn leave_room_route(
         },
         &db.globals,
         &db.account_data,
+        &db.sending,
     )?;
 
     Ok(leave_room::Response.into())
 }
 
+/// Leaves a room we are not part of with make_leave and send_leave, e.g. to reject an invite.
+async fn leave_remote_room(db: &Database<'_>, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+    if !db.rooms.is_invited(user_id, room_id)? && !db.rooms.is_joined(user_id, room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::BadState,
+            "Cannot leave a room you are not a member of.",
+        ));
+    }
+
+    // The server of the user who invited us is part of the room, the server of the room id
+    // might be as well
+    let mut servers = Vec::new();
+    if let Some((_, invite_state)) = db.rooms.invite_state(user_id, room_id)? {
+        for event in invite_state {
+            let event = serde_json::from_str::<serde_json::Value>(event.json().get())
+                .expect("converting raw jsons to values always works");
+
+            if event.get("type").and_then(|t| t.as_str()) == Some("m.room.member")
+                && event.get("state_key").and_then(|s| s.as_str()) == Some(user_id.as_str())
+            {
+                if let Some(sender) = event
+                    .get("sender")
+                    .and_then(|s| s.as_str())
+                    .and_then(|s| UserId::try_from(s).ok())
+                {
+                    servers.push(sender.server_name().to_owned());
+                }
+            }
+        }
+    }
+    if !servers
+        .iter()
+        .any(|server| server.as_str() == room_id.server_name().as_str())
+    {
+        servers.push(room_id.server_name().to_owned());
+    }
+
+    let mut last_error = Error::BadServerResponse("No server could be asked to leave the room.");
+    for server in servers {
+        match leave_room_over_federation(db, &server, user_id, room_id).await {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                warn!("Failed to leave {} over {}: {}", room_id, server, e);
+                last_error = e;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+async fn leave_room_over_federation(
+    db: &Database<'_>,
+    server: &ServerName,
+    user_id: &UserId,
+    room_id: &RoomId,
+) -> Result<()> {
+    let make_leave_response = server_server::send_request(
+        &db.globals,
+        server.to_string(),
+        federation::membership::create_leave_event_template::v1::Request {
+            room_id: room_id.clone(),
+            user_id: user_id.clone(),
+        },
+    )
+    .await?;
+
+    // Servers that don't send a room version are in a version 1 room
+    let room_version = RoomVersion::new(
+        make_leave_response
+            .room_version
+            .as_ref()
+            .unwrap_or(&RoomVersionId::Version1),
+    )
+    .ok_or(Error::BadServerResponse(
+        "Remote room has a room version this server does not support.",
+    ))?;
+
+    let mut leave_event_stub_value = serde_json::from_str::<serde_json::Value>(
+        make_leave_response.event.json().get(),
+    )
+    .map_err(|_| Error::BadServerResponse("Invalid make_leave event json received from server."))?;
+
+    // We sign the event, so it has to be exactly the leave we asked for
+    if !is_membership_template(&leave_event_stub_value, room_id, user_id, "leave") {
+        return Err(Error::BadServerResponse(
+            "Server sent an invalid make_leave event.",
+        ));
+    }
+
+    let leave_event_stub =
+        leave_event_stub_value
+            .as_object_mut()
+            .ok_or(Error::BadServerResponse(
+                "Invalid make leave event object received from server.",
+            ))?;
+
+    leave_event_stub.insert(
+        "origin".to_owned(),
+        db.globals.server_name().to_owned().to_string().into(),
+    );
+    leave_event_stub.insert(
+        "origin_server_ts".to_owned(),
+        utils::millis_since_unix_epoch().into(),
+    );
+
+    let event_id = room_version.hash_and_sign_event(
+        db.globals.server_name(),
+        db.globals.keypair(),
+        &mut leave_event_stub_value,
+    );
+
+    server_server::send_request(
+        &db.globals,
+        server.to_string(),
+        federation::membership::create_leave_event::v2::Request {
+            room_id: room_id.clone(),
+            event_id,
+            pdu_stub: PduEvent::convert_to_outgoing_federation_event(
+                leave_event_stub_value.clone(),
+                &room_version,
+            ),
+        },
+    )
+    .await?;
+
+    db.rooms
+        .add_remote_leave(user_id, room_id, &leave_event_stub_value, &db.globals)?;
+
+    Ok(())
+}
+
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/rooms/<_>/invite", data = "<body>")
 )]
-pub fn invite_user_route(
+pub async fn invite_user_route(
     db: State<'_, Database<'_>>,
     body: Ruma<invite_user::Request>,
 ) -> ConduitResult<invite_user::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
     if let invite_user::InvitationRecipient::UserId { user_id } = &body.recipient {
-        db.rooms.append_pdu(
-            PduBuilder {
-                room_id: body.room_id.clone(),
-                sender: sender_id.clone(),
-                event_type: EventType::RoomMember,
-                content: serde_json::to_value(member::MemberEventContent {
-                    membership: member::MembershipState::Invite,
-                    displayname: db.users.displayname(&user_id)?,
-                    avatar_url: db.users.avatar_url(&user_id)?,
-                    is_direct: None,
-                    third_party_invite: None,
-                })
-                .expect("event is valid, we just created it"),
-                unsigned: None,
-                state_key: Some(user_id.to_string()),
-                redacts: None,
+        let pdu_builder = PduBuilder {
+            room_id: body.room_id.clone(),
+            sender: sender_id.clone(),
+            event_type: EventType::RoomMember,
+            content: serde_json::to_value(member::MemberEventContent {
+                membership: member::MembershipState::Invite,
+                displayname: db.users.displayname(&user_id)?,
+                avatar_url: db.users.avatar_url(&user_id)?,
+                is_direct: None,
+                third_party_invite: None,
+            })
+            .expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: Some(user_id.to_string()),
+            redacts: None,
+        };
+
+        if user_id.server_name() == db.globals.server_name() {
+            db.rooms.build_and_append_pdu(
+                pdu_builder,
+                &db.globals,
+                &db.account_data,
+                &db.sending,
+            )?;
+        } else {
+            invite_remote_user(&db, user_id, pdu_builder).await?;
+        }
+
+        Ok(invite_user::Response.into())
+    } else {
+        Err(Error::BadRequest(ErrorKind::NotFound, "User not found."))
+    }
+}
+
+/// Checks that a membership event template of another server is the membership event of
+/// `user_id` in `room_id` that we asked for.
+fn is_membership_template(
+    template: &serde_json::Value,
+    room_id: &RoomId,
+    user_id: &UserId,
+    membership: &str,
+) -> bool {
+    template.get("type").and_then(|t| t.as_str()) == Some("m.room.member")
+        && template.get("room_id").and_then(|r| r.as_str()) == Some(room_id.as_str())
+        && template.get("sender").and_then(|s| s.as_str()) == Some(user_id.as_str())
+        && template.get("state_key").and_then(|s| s.as_str()) == Some(user_id.as_str())
+        && template
+            .get("content")
+            .and_then(|c| c.get("membership"))
+            .and_then(|m| m.as_str())
+            == Some(membership)
+}
+
+/// Lets the server of the invited user countersign the invite before we append it.
+async fn invite_remote_user(
+    db: &Database<'_>,
+    user_id: &UserId,
+    pdu_builder: PduBuilder,
+) -> Result<()> {
+    let room_id = pdu_builder.room_id.clone();
+    if !db
+        .rooms
+        .server_allowed_by_acl(&room_id, user_id.server_name())?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The user's server is denied by the room's server ACL.",
+        ));
+    }
+
+    let room_version = db.rooms.room_version_rules(&room_id)?;
+
+    for _ in 0..MAX_INVITE_ATTEMPTS {
+        // The lock can't be held while we wait for the other server
+        let (pdu, pdu_json, leaves) = {
+            let mutex = db.rooms.room_mutex(&room_id);
+            let _lock = mutex.lock().unwrap();
+
+            let leaves = db.rooms.get_pdu_leaves(&room_id)?;
+            let mut pdu = db.rooms.create_pdu(pdu_builder.clone(), &db.globals)?;
+            let pdu_json = db.rooms.sign_pdu(&mut pdu, &db.globals)?;
+            (pdu, pdu_json, leaves)
+        };
+
+        let response = server_server::send_request(
+            &db.globals,
+            user_id.server_name().to_string(),
+            federation::membership::create_invite::v2::Request {
+                room_id: room_id.clone(),
+                event_id: pdu.event_id.clone(),
+                room_version: room_version.id.clone(),
+                event: PduEvent::convert_to_outgoing_federation_event(pdu_json, &room_version),
+                invite_room_state: db.rooms.stripped_state(&room_id)?,
             },
+        )
+        .await?;
+
+        let value = serde_json::from_str::<serde_json::Value>(response.event.json().get())
+            .expect("converting raw jsons to values always works");
+
+        if server_server::calculate_event_id(&value, &room_version)? != pdu.event_id {
+            return Err(Error::BadServerResponse("Server changed the invite event."));
+        }
+
+        let signed_by_invited_server = value
+            .get("signatures")
+            .and_then(|signatures| signatures.get(user_id.server_name().as_str()))
+            .is_some();
+        if !signed_by_invited_server {
+            return Err(Error::BadServerResponse(
+                "Server did not sign the invite event.",
+            ));
+        }
+
+        let mut pub_key_map = BTreeMap::new();
+        let value = server_server::verify_pdu(
+            &db.globals,
+            &mut pub_key_map,
+            &room_version,
+            &pdu.event_id,
+            value,
+        )
+        .await
+        .map_err(|e| {
+            warn!("Invalid invite event {}: {}", pdu.event_id, e);
+            Error::BadServerResponse("Invite event could not be verified.")
+        })?;
+
+        let pdu = serde_json::from_value::<PduEvent>(value.clone())
+            .map_err(|_| Error::BadServerResponse("Invalid invite event."))?;
+
+        let mutex = db.rooms.room_mutex(&room_id);
+        let _lock = mutex.lock().unwrap();
+
+        // The invite references the latest events from when it was built. If the room got new
+        // events while we waited, appending it would fork the room, so we build it again
+        if db.rooms.get_pdu_leaves(&room_id)? != leaves {
+            continue;
+        }
+
+        if !db.rooms.auth_check(&pdu)? {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Event is not authorized.",
+            ));
+        }
+
+        let count = db.globals.next_count()?;
+        let pdu_id = db.rooms.append_pdu(
+            &pdu,
+            &value,
+            count,
             &db.globals,
             &db.account_data,
+            &db.sending,
         )?;
+        db.rooms
+            .send_to_room_servers(&pdu, &pdu_id, &db.globals, &db.sending)?;
 
-        Ok(invite_user::Response.into())
-    } else {
-        Err(Error::BadRequest(ErrorKind::NotFound, "User not found."))
+        return Ok(());
     }
+
+    Err(Error::BadRequest(
+        ErrorKind::BadState,
+        "The room changed too often while the invite was being signed.",
+    ))
 }
 
 #[cfg_attr(
@@ -290,7 +683,7 @@ pub fn kick_user_route(
     event.membership = ruma::events::room::member::MembershipState::Leave;
     // TODO: reason
 
-    db.rooms.append_pdu(
+    db.rooms.build_and_append_pdu(
         PduBuilder {
             room_id: body.room_id.clone(),
             sender: sender_id.clone(),
@@ -302,6 +695,7 @@ pub fn kick_user_route(
         },
         &db.globals,
         &db.account_data,
+        &db.sending,
     )?;
 
     Ok(kick_user::Response.into())
@@ -345,7 +739,7 @@ pub fn ban_user_route(
             },
         )?;
 
-    db.rooms.append_pdu(
+    db.rooms.build_and_append_pdu(
         PduBuilder {
             room_id: body.room_id.clone(),
             sender: sender_id.clone(),
@@ -357,6 +751,7 @@ pub fn ban_user_route(
         },
         &db.globals,
         &db.account_data,
+        &db.sending,
     )?;
 
     Ok(ban_user::Response.into())
@@ -391,7 +786,7 @@ pub fn unban_user_route(
 
     event.membership = ruma::events::room::member::MembershipState::Leave;
 
-    db.rooms.append_pdu(
+    db.rooms.build_and_append_pdu(
         PduBuilder {
             room_id: body.room_id.clone(),
             sender: sender_id.clone(),
@@ -403,6 +798,7 @@ pub fn unban_user_route(
         },
         &db.globals,
         &db.account_data,
+        &db.sending,
     )?;
 
     Ok(unban_user::Response.into())
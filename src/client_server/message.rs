@@ -1,13 +1,20 @@
 use super::State;
-use crate::{pdu::PduBuilder, utils, ConduitResult, Database, Error, Ruma};
+use crate::{
+    pdu::PduBuilder, server_server, utils, ConduitResult, Database, Error, PduEvent, Result, Ruma,
+};
+use log::warn;
 use ruma::{
     api::client::{
         error::ErrorKind,
         r0::message::{get_message_events, send_message_event},
     },
-    EventId,
+    events::{AnyStateEvent, EventType},
+    EventId, Raw,
+};
+use std::{
+    collections::HashSet,
+    convert::{TryFrom, TryInto},
 };
-use std::convert::{TryFrom, TryInto};
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, put};
@@ -48,7 +55,7 @@ pub fn send_message_event_route(
     let mut unsigned = serde_json::Map::new();
     unsigned.insert("transaction_id".to_owned(), body.txn_id.clone().into());
 
-    let event_id = db.rooms.append_pdu(
+    let event_id = db.rooms.build_and_append_pdu(
         PduBuilder {
             room_id: body.room_id.clone(),
             sender: sender_id.clone(),
@@ -66,6 +73,7 @@ pub fn send_message_event_route(
         },
         &db.globals,
         &db.account_data,
+        &db.sending,
     )?;
 
     db.transaction_ids
@@ -77,7 +85,7 @@ pub fn send_message_event_route(
     feature = "conduit_bin",
     get("/_matrix/client/r0/rooms/<_>/messages", data = "<body>")
 )]
-pub fn get_message_events_route(
+pub async fn get_message_events_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_message_events::Request>,
 ) -> ConduitResult<get_message_events::Response> {
@@ -116,6 +124,8 @@ pub fn get_message_events_route(
 
             let end_token = events_after.last().map(|(count, _)| count.to_string());
 
+            let state = member_state(&db, events_after.first(), &events_after)?;
+
             let events_after = events_after
                 .into_iter()
                 .map(|(_, pdu)| pdu.to_room_event())
@@ -125,21 +135,41 @@ pub fn get_message_events_route(
                 start: Some(body.from.clone()),
                 end: end_token,
                 chunk: events_after,
-                state: Vec::new(),
+                state,
             }
             .into())
         }
         get_message_events::Direction::Backward => {
-            let events_before = db
-                .rooms
-                .pdus_until(&sender_id, &body.room_id, from)
-                .take(limit)
-                .filter_map(|r| r.ok()) // Filter out buggy events
-                .take_while(|&(k, _)| Some(Ok(k)) != to) // Stop at `to`
-                .collect::<Vec<_>>();
+            let get_events_before = || {
+                db.rooms
+                    .pdus_until(&sender_id, &body.room_id, from)
+                    .take(limit)
+                    .filter_map(|r| r.ok()) // Filter out buggy events
+                    .take_while(|&(k, _)| Some(Ok(k)) != to) // Stop at `to`
+                    .collect::<Vec<_>>()
+            };
+
+            let mut events_before = get_events_before();
+
+            // We reached the start of our timeline, so we ask other servers for older events
+            if events_before.len() < limit && to.is_none() {
+                match server_server::backfill(
+                    &db,
+                    &body.room_id,
+                    (limit - events_before.len()) as u32,
+                )
+                .await
+                {
+                    Ok(0) => {}
+                    Ok(_) => events_before = get_events_before(),
+                    Err(e) => warn!("Failed to backfill {}: {}", body.room_id, e),
+                }
+            }
 
             let start_token = events_before.last().map(|(count, _)| count.to_string());
 
+            let state = member_state(&db, events_before.last(), &events_before)?;
+
             let events_before = events_before
                 .into_iter()
                 .map(|(_, pdu)| pdu.to_room_event())
@@ -149,9 +179,47 @@ pub fn get_message_events_route(
                 start: Some(body.from.clone()),
                 end: start_token,
                 chunk: events_before,
-                state: Vec::new(),
+                state,
             }
             .into())
         }
     }
 }
+
+/// Returns the member events of all senders in the chunk, taken from the state before the
+/// oldest event in the chunk.
+fn member_state(
+    db: &Database<'_>,
+    oldest: Option<&(u64, PduEvent)>,
+    chunk: &[(u64, PduEvent)],
+) -> Result<Vec<Raw<AnyStateEvent>>> {
+    let oldest = match oldest {
+        Some((_, pdu)) => pdu,
+        None => return Ok(Vec::new()),
+    };
+    let state = db.rooms.state_before(&oldest.event_id)?;
+
+    let senders = chunk
+        .iter()
+        .map(|(_, pdu)| pdu.sender.to_string())
+        .collect::<HashSet<_>>();
+
+    let mut member_events = Vec::new();
+    for sender in senders {
+        let pdu = match &state {
+            Some(state) => match state.get(&(EventType::RoomMember, sender.clone())) {
+                Some(event_id) => db.rooms.get_pdu(event_id)?,
+                None => None,
+            },
+            None => db
+                .rooms
+                .room_state_get(&oldest.room_id, &EventType::RoomMember, &sender)?,
+        };
+
+        if let Some(pdu) = pdu {
+            member_events.push(pdu.to_state_event());
+        }
+    }
+
+    Ok(member_events)
+}
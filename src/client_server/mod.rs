@@ -1,4 +1,5 @@
 mod account;
+mod admin;
 mod alias;
 mod backup;
 mod capabilities;
@@ -30,6 +31,7 @@ mod user_directory;
 mod voip;
 
 pub use account::*;
+pub use admin::*;
 pub use alias::*;
 pub use backup::*;
 pub use capabilities::*;
@@ -37,7 +37,9 @@ pub fn set_presence_route(
                 },
                 sender: sender_id.clone(),
             },
+            &db.rooms,
             &db.globals,
+            &db.sending,
         )?;
     }
 
@@ -1,5 +1,5 @@
 use super::State;
-use crate::{pdu::PduBuilder, utils, ConduitResult, Database, Error, Ruma};
+use crate::{pdu::PduBuilder, server_server, utils, ConduitResult, Database, Error, Ruma};
 use ruma::{
     api::client::{
         error::ErrorKind,
@@ -31,7 +31,7 @@ pub fn set_displayname_route(
     // Send a new membership event and presence update into all joined rooms
     for room_id in db.rooms.rooms_joined(&sender_id) {
         let room_id = room_id?;
-        db.rooms.append_pdu(
+        db.rooms.build_and_append_pdu(
             PduBuilder {
                 room_id: room_id.clone(),
                 sender: sender_id.clone(),
@@ -64,6 +64,7 @@ pub fn set_displayname_route(
             },
             &db.globals,
             &db.account_data,
+            &db.sending,
         )?;
 
         // Presence update
@@ -85,7 +86,9 @@ pub fn set_displayname_route(
                 },
                 sender: sender_id.clone(),
             },
+            &db.rooms,
             &db.globals,
+            &db.sending,
         )?;
     }
 
@@ -96,10 +99,17 @@ pub fn set_displayname_route(
     feature = "conduit_bin",
     get("/_matrix/client/r0/profile/<_>/displayname", data = "<body>")
 )]
-pub fn get_displayname_route(
+pub async fn get_displayname_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_display_name::Request>,
 ) -> ConduitResult<get_display_name::Response> {
+    if body.user_id.server_name() != db.globals.server_name() {
+        let (displayname, _) =
+            server_server::query_remote_profile(&db.globals, &body.user_id).await?;
+
+        return Ok(get_display_name::Response { displayname }.into());
+    }
+
     Ok(get_display_name::Response {
         displayname: db.users.displayname(&body.user_id)?,
     }
@@ -134,7 +144,7 @@ pub fn set_avatar_url_route(
     // Send a new membership event and presence update into all joined rooms
     for room_id in db.rooms.rooms_joined(&sender_id) {
         let room_id = room_id?;
-        db.rooms.append_pdu(
+        db.rooms.build_and_append_pdu(
             PduBuilder {
                 room_id: room_id.clone(),
                 sender: sender_id.clone(),
@@ -167,6 +177,7 @@ pub fn set_avatar_url_route(
             },
             &db.globals,
             &db.account_data,
+            &db.sending,
         )?;
 
         // Presence update
@@ -188,7 +199,9 @@ pub fn set_avatar_url_route(
                 },
                 sender: sender_id.clone(),
             },
+            &db.rooms,
             &db.globals,
+            &db.sending,
         )?;
     }
 
@@ -199,10 +212,17 @@ pub fn set_avatar_url_route(
     feature = "conduit_bin",
     get("/_matrix/client/r0/profile/<_>/avatar_url", data = "<body>")
 )]
-pub fn get_avatar_url_route(
+pub async fn get_avatar_url_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_avatar_url::Request>,
 ) -> ConduitResult<get_avatar_url::Response> {
+    if body.user_id.server_name() != db.globals.server_name() {
+        let (_, avatar_url) =
+            server_server::query_remote_profile(&db.globals, &body.user_id).await?;
+
+        return Ok(get_avatar_url::Response { avatar_url }.into());
+    }
+
     Ok(get_avatar_url::Response {
         avatar_url: db.users.avatar_url(&body.user_id)?,
     }
@@ -213,10 +233,21 @@ pub fn get_avatar_url_route(
     feature = "conduit_bin",
     get("/_matrix/client/r0/profile/<_>", data = "<body>")
 )]
-pub fn get_profile_route(
+pub async fn get_profile_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_profile::Request>,
 ) -> ConduitResult<get_profile::Response> {
+    if body.user_id.server_name() != db.globals.server_name() {
+        let (displayname, avatar_url) =
+            server_server::query_remote_profile(&db.globals, &body.user_id).await?;
+
+        return Ok(get_profile::Response {
+            avatar_url,
+            displayname,
+        }
+        .into());
+    }
+
     if !db.users.exists(&body.user_id)? {
         // Return 404 if this user doesn't exist
         return Err(Error::BadRequest(
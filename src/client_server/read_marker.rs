@@ -68,7 +68,9 @@ pub fn set_read_marker_route(
                     room_id: body.room_id.clone(),
                 },
             )),
+            &db.rooms,
             &db.globals,
+            &db.sending,
         )?;
     }
     Ok(set_read_marker::Response.into())
@@ -1,5 +1,5 @@
 use super::State;
-use crate::{pdu::PduBuilder, ConduitResult, Database, Error, Ruma};
+use crate::{pdu::PduBuilder, room_version::RoomVersion, ConduitResult, Database, Error, Ruma};
 use ruma::{
     api::client::{
         error::ErrorKind,
@@ -53,10 +53,19 @@ pub fn create_room_route(
         .creation_content
         .as_ref()
         .and_then(|c| c.predecessor.clone());
-    content.room_version = RoomVersionId::Version6;
+    content.room_version = match &body.room_version {
+        Some(room_version) if RoomVersion::new(room_version).is_some() => room_version.clone(),
+        Some(_) => {
+            return Err(Error::BadRequest(
+                ErrorKind::UnsupportedRoomVersion,
+                "This server does not support that room version.",
+            ))
+        }
+        None => RoomVersion::default_id(),
+    };
 
     // 1. The room create event
-    db.rooms.append_pdu(
+    db.rooms.build_and_append_pdu(
         PduBuilder {
             room_id: room_id.clone(),
             sender: sender_id.clone(),
@@ -68,10 +77,11 @@ pub fn create_room_route(
         },
         &db.globals,
         &db.account_data,
+        &db.sending,
     )?;
 
     // 2. Let the room creator join
-    db.rooms.append_pdu(
+    db.rooms.build_and_append_pdu(
         PduBuilder {
             room_id: room_id.clone(),
             sender: sender_id.clone(),
@@ -90,6 +100,7 @@ pub fn create_room_route(
         },
         &db.globals,
         &db.account_data,
+        &db.sending,
     )?;
 
     // 3. Power levels
@@ -120,7 +131,7 @@ pub fn create_room_route(
         })
         .expect("event is valid, we just created it")
     };
-    db.rooms.append_pdu(
+    db.rooms.build_and_append_pdu(
         PduBuilder {
             room_id: room_id.clone(),
             sender: sender_id.clone(),
@@ -132,6 +143,7 @@ pub fn create_room_route(
         },
         &db.globals,
         &db.account_data,
+        &db.sending,
     )?;
 
     // 4. Events set by preset
@@ -144,7 +156,7 @@ pub fn create_room_route(
     });
 
     // 4.1 Join Rules
-    db.rooms.append_pdu(
+    db.rooms.build_and_append_pdu(
         PduBuilder {
             room_id: room_id.clone(),
             sender: sender_id.clone(),
@@ -166,10 +178,11 @@ pub fn create_room_route(
         },
         &db.globals,
         &db.account_data,
+        &db.sending,
     )?;
 
     // 4.2 History Visibility
-    db.rooms.append_pdu(
+    db.rooms.build_and_append_pdu(
         PduBuilder {
             room_id: room_id.clone(),
             sender: sender_id.clone(),
@@ -184,10 +197,11 @@ pub fn create_room_route(
         },
         &db.globals,
         &db.account_data,
+        &db.sending,
     )?;
 
     // 4.3 Guest Access
-    db.rooms.append_pdu(
+    db.rooms.build_and_append_pdu(
         PduBuilder {
             room_id: room_id.clone(),
             sender: sender_id.clone(),
@@ -210,6 +224,7 @@ pub fn create_room_route(
         },
         &db.globals,
         &db.account_data,
+        &db.sending,
     )?;
 
     // 5. Events listed in initial_state
@@ -224,7 +239,7 @@ pub fn create_room_route(
             continue;
         }
 
-        db.rooms.append_pdu(
+        db.rooms.build_and_append_pdu(
             PduBuilder {
                 room_id: room_id.clone(),
                 sender: sender_id.clone(),
@@ -238,12 +253,13 @@ pub fn create_room_route(
             },
             &db.globals,
             &db.account_data,
+            &db.sending,
         )?;
     }
 
     // 6. Events implied by name and topic
     if let Some(name) = &body.name {
-        db.rooms.append_pdu(
+        db.rooms.build_and_append_pdu(
             PduBuilder {
                 room_id: room_id.clone(),
                 sender: sender_id.clone(),
@@ -260,11 +276,12 @@ pub fn create_room_route(
             },
             &db.globals,
             &db.account_data,
+            &db.sending,
         )?;
     }
 
     if let Some(topic) = &body.topic {
-        db.rooms.append_pdu(
+        db.rooms.build_and_append_pdu(
             PduBuilder {
                 room_id: room_id.clone(),
                 sender: sender_id.clone(),
@@ -279,12 +296,13 @@ pub fn create_room_route(
             },
             &db.globals,
             &db.account_data,
+            &db.sending,
         )?;
     }
 
     // 7. Events implied by invite (and TODO: invite_3pid)
     for user in &body.invite {
-        db.rooms.append_pdu(
+        db.rooms.build_and_append_pdu(
             PduBuilder {
                 room_id: room_id.clone(),
                 sender: sender_id.clone(),
@@ -303,6 +321,7 @@ pub fn create_room_route(
             },
             &db.globals,
             &db.account_data,
+            &db.sending,
         )?;
     }
 
@@ -335,6 +354,15 @@ pub fn get_room_event_route(
         ));
     }
 
+    // Soft failed events are never shown to clients
+    if db
+        .rooms
+        .pdu_failure(&body.room_id, &body.event_id)?
+        .is_some()
+    {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found."));
+    }
+
     Ok(get_room_event::Response {
         event: db
             .rooms
@@ -360,10 +388,7 @@ pub fn upgrade_room_route(
     let new_version =
         RoomVersionId::try_from(body.new_version.clone()).expect("invalid room version id");
 
-    if !matches!(
-        new_version,
-        RoomVersionId::Version5 | RoomVersionId::Version6
-    ) {
+    if RoomVersion::new(&new_version).is_none() {
         return Err(Error::BadRequest(
             ErrorKind::UnsupportedRoomVersion,
             "This server does not support that room version.",
@@ -375,7 +400,7 @@ pub fn upgrade_room_route(
 
     // Send a m.room.tombstone event to the old room to indicate that it is not intended to be used any further
     // Fail if the sender does not have the required permissions
-    let tombstone_event_id = db.rooms.append_pdu(
+    let tombstone_event_id = db.rooms.build_and_append_pdu(
         PduBuilder {
             room_id: body.room_id.clone(),
             sender: sender_id.clone(),
@@ -391,6 +416,7 @@ pub fn upgrade_room_route(
         },
         &db.globals,
         &db.account_data,
+        &db.sending,
     )?;
 
     // Get the old room federations status
@@ -418,7 +444,7 @@ pub fn upgrade_room_route(
     create_event_content.room_version = new_version;
     create_event_content.predecessor = predecessor;
 
-    db.rooms.append_pdu(
+    db.rooms.build_and_append_pdu(
         PduBuilder {
             room_id: replacement_room.clone(),
             sender: sender_id.clone(),
@@ -431,10 +457,11 @@ pub fn upgrade_room_route(
         },
         &db.globals,
         &db.account_data,
+        &db.sending,
     )?;
 
     // Join the new room
-    db.rooms.append_pdu(
+    db.rooms.build_and_append_pdu(
         PduBuilder {
             room_id: replacement_room.clone(),
             sender: sender_id.clone(),
@@ -453,6 +480,7 @@ pub fn upgrade_room_route(
         },
         &db.globals,
         &db.account_data,
+        &db.sending,
     )?;
 
     // Recommended transferable state events list from the specs
@@ -475,7 +503,7 @@ pub fn upgrade_room_route(
             None => continue, // Skipping missing events.
         };
 
-        db.rooms.append_pdu(
+        db.rooms.build_and_append_pdu(
             PduBuilder {
                 room_id: replacement_room.clone(),
                 sender: sender_id.clone(),
@@ -487,6 +515,7 @@ pub fn upgrade_room_route(
             },
             &db.globals,
             &db.account_data,
+            &db.sending,
         )?;
     }
 
@@ -518,7 +547,7 @@ pub fn upgrade_room_route(
 
     // Modify the power levels in the old room to prevent sending of events and inviting new users
     db.rooms
-        .append_pdu(
+        .build_and_append_pdu(
             PduBuilder {
                 room_id: body.room_id.clone(),
                 sender: sender_id.clone(),
@@ -531,6 +560,7 @@ pub fn upgrade_room_route(
             },
             &db.globals,
             &db.account_data,
+            &db.sending,
         )
         .ok();
 
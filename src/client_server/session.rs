@@ -142,7 +142,8 @@ pub fn logout_route(
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
     let device_id = body.device_id.as_ref().expect("user is authenticated");
 
-    db.users.remove_device(&sender_id, device_id)?;
+    db.users
+        .remove_device(&sender_id, device_id, &db.rooms, &db.globals, &db.sending)?;
 
     Ok(logout::Response.into())
 }
@@ -168,7 +169,8 @@ pub fn logout_all_route(
 
     for device_id in db.users.all_device_ids(sender_id) {
         if let Ok(device_id) = device_id {
-            db.users.remove_device(&sender_id, &device_id)?;
+            db.users
+                .remove_device(&sender_id, &device_id, &db.rooms, &db.globals, &db.sending)?;
         }
     }
 
@@ -64,7 +64,7 @@ pub fn send_state_event_for_key_route(
         }
     }
 
-    let event_id = db.rooms.append_pdu(
+    let event_id = db.rooms.build_and_append_pdu(
         PduBuilder {
             room_id: body.room_id.clone(),
             sender: sender_id.clone(),
@@ -76,6 +76,7 @@ pub fn send_state_event_for_key_route(
         },
         &db.globals,
         &db.account_data,
+        &db.sending,
     )?;
 
     Ok(send_state_event_for_key::Response { event_id }.into())
@@ -99,6 +100,7 @@ pub fn send_state_event_for_empty_key_route(
             },
         sender_id,
         device_id,
+        origin,
         json_body,
     } = body;
 
@@ -114,6 +116,7 @@ pub fn send_state_event_for_empty_key_route(
                 },
                 sender_id,
                 device_id,
+                origin,
                 json_body,
             },
         )?
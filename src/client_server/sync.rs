@@ -385,6 +385,26 @@ pub async fn sync_events_route(
     let mut left_rooms = BTreeMap::new();
     for room_id in db.rooms.rooms_left(&sender_id) {
         let room_id = room_id?;
+
+        // We only know the leave event of rooms on other servers we left without being in them
+        if let Some((count, leave_event)) = db.rooms.remote_leave(&sender_id, &room_id)? {
+            if count > since {
+                left_rooms.insert(
+                    room_id.clone(),
+                    sync_events::LeftRoom {
+                        account_data: sync_events::AccountData { events: Vec::new() },
+                        timeline: sync_events::Timeline {
+                            limited: false,
+                            prev_batch: Some(next_batch.clone()),
+                            events: vec![leave_event.to_sync_room_event()],
+                        },
+                        state: sync_events::State { events: Vec::new() },
+                    },
+                );
+            }
+            continue;
+        }
+
         let pdus = db.rooms.pdus_since(&sender_id, &room_id, since)?;
         let room_events = pdus
             .filter_map(|pdu| pdu.ok()) // Filter out buggy events
@@ -452,6 +472,22 @@ pub async fn sync_events_route(
     let mut invited_rooms = BTreeMap::new();
     for room_id in db.rooms.rooms_invited(&sender_id) {
         let room_id = room_id?;
+
+        // We only know the stripped state of rooms on other servers we were invited to
+        if let Some((count, invite_state)) = db.rooms.invite_state(&sender_id, &room_id)? {
+            if count > since {
+                invited_rooms.insert(
+                    room_id.clone(),
+                    sync_events::InvitedRoom {
+                        invite_state: sync_events::InviteState {
+                            events: invite_state,
+                        },
+                    },
+                );
+            }
+            continue;
+        }
+
         let mut invited_since_last_sync = false;
         for pdu in db.rooms.pdus_since(&sender_id, &room_id, since)? {
             let pdu = pdu?;
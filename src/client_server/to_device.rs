@@ -4,6 +4,8 @@ use ruma::api::client::{
     error::ErrorKind,
     r0::to_device::{self, send_event_to_device},
 };
+use serde_json::json;
+use std::collections::HashMap;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::put;
@@ -28,7 +30,35 @@ pub fn send_event_to_device_route(
         return Ok(send_event_to_device::Response.into());
     }
 
+    // Messages to users of other servers are sent in one edu per server
+    let mut remote_messages = HashMap::new();
+
     for (target_user_id, map) in &body.messages {
+        if target_user_id.server_name() != db.globals.server_name() {
+            let mut messages = serde_json::Map::new();
+            for (target_device_id_maybe, event) in map {
+                let target_device_id = match target_device_id_maybe {
+                    to_device::DeviceIdOrAllDevices::DeviceId(target_device_id) => {
+                        target_device_id.to_string()
+                    }
+                    to_device::DeviceIdOrAllDevices::AllDevices => "*".to_owned(),
+                };
+
+                messages.insert(
+                    target_device_id,
+                    serde_json::from_str(event.get()).map_err(|_| {
+                        Error::BadRequest(ErrorKind::InvalidParam, "Event is invalid")
+                    })?,
+                );
+            }
+
+            remote_messages
+                .entry(target_user_id.server_name().to_owned())
+                .or_insert_with(serde_json::Map::new)
+                .insert(target_user_id.to_string(), messages.into());
+            continue;
+        }
+
         for (target_device_id_maybe, event) in map {
             match target_device_id_maybe {
                 to_device::DeviceIdOrAllDevices::DeviceId(target_device_id) => {
@@ -62,6 +92,22 @@ pub fn send_event_to_device_route(
         }
     }
 
+    for (server, messages) in remote_messages {
+        db.sending.send_edu(
+            &server,
+            &json!({
+                "edu_type": "m.direct_to_device",
+                "content": {
+                    "sender": sender_id,
+                    "type": body.event_type,
+                    "message_id": body.txn_id,
+                    "messages": messages,
+                }
+            }),
+            &db.globals,
+        )?;
+    }
+
     // Save transaction id with empty data
     db.transaction_ids
         .add_txnid(sender_id, device_id, &body.txn_id, &[])?;
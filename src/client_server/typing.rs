@@ -21,12 +21,18 @@ pub fn create_typing_event_route(
             &body.room_id,
             body.timeout.map(|d| d.as_millis() as u64).unwrap_or(30000)
                 + utils::millis_since_unix_epoch(),
+            &db.rooms,
             &db.globals,
+            &db.sending,
         )?;
     } else {
-        db.rooms
-            .edus
-            .typing_remove(&sender_id, &body.room_id, &db.globals)?;
+        db.rooms.edus.typing_remove(
+            &sender_id,
+            &body.room_id,
+            &db.rooms,
+            &db.globals,
+            &db.sending,
+        )?;
     }
 
     Ok(create_typing_event::Response.into())
@@ -3,6 +3,7 @@ pub mod globals;
 pub mod key_backups;
 pub mod media;
 pub mod rooms;
+pub mod sending;
 pub mod transaction_ids;
 pub mod uiaa;
 pub mod users;
@@ -10,7 +11,11 @@ pub mod users;
 use crate::{Error, Result};
 use directories::ProjectDirs;
 use log::info;
-use std::fs::remove_dir_all;
+use std::{
+    collections::HashMap,
+    fs::remove_dir_all,
+    sync::{Arc, Mutex},
+};
 
 use futures::StreamExt;
 use rocket::{futures, Config};
@@ -25,6 +30,7 @@ pub struct Database<'a> {
     pub media: media::Media,
     pub key_backups: key_backups::KeyBackups,
     pub transaction_ids: transaction_ids::TransactionIds,
+    pub sending: sending::Sending,
     pub _db: sled::Db,
 }
 
@@ -68,7 +74,11 @@ impl<'a> Database<'a> {
         info!("Opened sled database at {}", path);
 
         Ok(Self {
-            globals: globals::Globals::load(db.open_tree("global")?, config)?,
+            globals: globals::Globals::load(
+                db.open_tree("global")?,
+                db.open_tree("servername_signingkeys")?,
+                config,
+            )?,
             users: users::Users {
                 userid_password: db.open_tree("userid_password")?,
                 userid_displayname: db.open_tree("userid_displayname")?,
@@ -79,6 +89,7 @@ impl<'a> Database<'a> {
                 onetimekeyid_onetimekeys: db.open_tree("onetimekeyid_onetimekeys")?,
                 userid_lastonetimekeyupdate: db.open_tree("userid_lastonetimekeyupdate")?,
                 keychangeid_userid: db.open_tree("devicekeychangeid_userid")?,
+                userid_devicelistversion: db.open_tree("userid_devicelistversion")?,
                 keyid_key: db.open_tree("keyid_key")?,
                 userid_masterkeyid: db.open_tree("userid_masterkeyid")?,
                 userid_selfsigningkeyid: db.open_tree("userid_selfsigningkeyid")?,
@@ -101,7 +112,12 @@ impl<'a> Database<'a> {
                 },
                 pduid_pdu: db.open_tree("pduid_pdu")?,
                 eventid_pduid: db.open_tree("eventid_pduid")?,
+                eventid_outlierpdu: db.open_tree("eventid_outlierpdu")?,
+                eventid_rejectedpdu: db.open_tree("eventid_rejectedpdu")?,
+                roomeventid_pdufailure: db.open_tree("roomeventid_pdufailure")?,
                 roomid_pduleaves: db.open_tree("roomid_pduleaves")?,
+                eventid_stateids: db.open_tree("eventid_stateids")?,
+                stateid_delta: db.open_tree("stateid_delta")?,
                 roomstateid_pdu: db.open_tree("roomstateid_pdu")?,
 
                 alias_roomid: db.open_tree("alias_roomid")?,
@@ -115,13 +131,17 @@ impl<'a> Database<'a> {
                 roomuseroncejoinedids: db.open_tree("roomuseroncejoinedids")?,
                 userroomid_invited: db.open_tree("userroomid_invited")?,
                 roomuserid_invited: db.open_tree("roomuserid_invited")?,
+                userroomid_invitestate: db.open_tree("userroomid_invitestate")?,
                 userroomid_left: db.open_tree("userroomid_left")?,
+                userroomid_remoteleave: db.open_tree("userroomid_remoteleave")?,
+                roomid_mutex: Arc::new(Mutex::new(HashMap::new())),
             },
             account_data: account_data::AccountData {
                 roomuserdataid_accountdata: db.open_tree("roomuserdataid_accountdata")?,
             },
             media: media::Media {
                 mediaid_file: db.open_tree("mediaid_file")?,
+                remote_mediaid_file: db.open_tree("remote_mediaid_file")?,
             },
             key_backups: key_backups::KeyBackups {
                 backupid_algorithm: db.open_tree("backupid_algorithm")?,
@@ -131,6 +151,11 @@ impl<'a> Database<'a> {
             transaction_ids: transaction_ids::TransactionIds {
                 userdevicetxnid_response: db.open_tree("userdevicetxnid_response")?,
             },
+            sending: sending::Sending {
+                servernamepduids: db.open_tree("servernamepduids")?,
+                servernameeduids: db.open_tree("servernameeduids")?,
+                servername_retrystate: db.open_tree("servername_retrystate")?,
+            },
             _db: db,
         })
     }
@@ -1,22 +1,57 @@
 use crate::{utils, Error, Result};
-use ruma::ServerName;
-use std::convert::TryInto;
+use ruma::{
+    api::federation::discovery::{OldVerifyKey, ServerKey},
+    RoomAliasId, RoomId, ServerName, UserId,
+};
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    sync::{Arc, RwLock},
+    time::Instant,
+};
 
 pub const COUNTER: &str = "c";
+/// Counts down for pdus from before the start of a room's timeline.
+pub const BACKFILL_COUNTER: &str = "b";
+/// Backfilled pdus get counts below this and new pdus get counts above it, so history always
+/// fits below the timeline of a room.
+pub const BACKFILL_COUNT_START: u64 = u64::MAX / 2;
 
+/// Destination = Actual destination, Host header, Valid until
+type DestinationCache = HashMap<String, (String, String, Instant)>;
+/// Alias = Room id, Servers, Valid until
+type AliasCache = HashMap<RoomAliasId, (RoomId, Vec<String>, Instant)>;
+/// UserId = Displayname, Avatar url, Valid until
+type ProfileCache = HashMap<UserId, (Option<String>, Option<String>, Instant)>;
+
+#[derive(Clone)]
 pub struct Globals<'a> {
     pub(super) globals: sled::Tree,
-    keypair: ruma::signatures::Ed25519KeyPair,
+    pub(super) servername_signingkeys: sled::Tree,
+    keypair: Arc<ruma::signatures::Ed25519KeyPair>,
     reqwest_client: reqwest::Client,
     server_name: Box<ServerName>,
     max_request_size: u32,
     registration_disabled: bool,
     encryption_disabled: bool,
     jwt_decoding_key: jsonwebtoken::DecodingKey<'a>,
+    well_known_server: Option<String>,
+    trusted_servers: Vec<Box<ServerName>>,
+    allow_federation: bool,
+    federation_allowlist: Option<Vec<Box<ServerName>>>,
+    federation_denylist: Vec<Box<ServerName>>,
+    admins: Vec<UserId>,
+    actual_destination_cache: Arc<RwLock<DestinationCache>>,
+    remote_alias_cache: Arc<RwLock<AliasCache>>,
+    remote_profile_cache: Arc<RwLock<ProfileCache>>,
 }
 
 impl Globals<'_> {
-    pub fn load(globals: sled::Tree, config: &rocket::Config) -> Result<Self> {
+    pub fn load(
+        globals: sled::Tree,
+        servername_signingkeys: sled::Tree,
+        config: &rocket::Config,
+    ) -> Result<Self> {
         let keypair = ruma::signatures::Ed25519KeyPair::new(
             &*globals
                 .update_and_fetch("keypair", utils::generate_keypair)?
@@ -25,6 +60,14 @@ impl Globals<'_> {
         )
         .map_err(|_| Error::bad_database("Private or public keys are invalid."))?;
 
+        // Servers that started before backfill counts existed continue above them
+        globals.update_and_fetch(COUNTER, |old| {
+            let count = old
+                .and_then(|bytes| utils::u64_from_bytes(bytes).ok())
+                .unwrap_or(0);
+            Some(count.max(BACKFILL_COUNT_START).to_be_bytes().to_vec())
+        })?;
+
         let jwt_secret = config
             .get_str("jwt_secret")
             .map(std::string::ToString::to_string)
@@ -36,7 +79,8 @@ impl Globals<'_> {
 
         Ok(Self {
             globals,
-            keypair,
+            servername_signingkeys,
+            keypair: Arc::new(keypair),
             reqwest_client: reqwest::Client::new(),
             server_name: config
                 .get_str("server_name")
@@ -54,6 +98,54 @@ impl Globals<'_> {
             registration_disabled: config.get_bool("registration_disabled").unwrap_or(false),
             encryption_disabled: config.get_bool("encryption_disabled").unwrap_or(false),
             jwt_decoding_key,
+            well_known_server: config
+                .get_str("well_known_server")
+                .ok()
+                .map(std::string::ToString::to_string),
+            trusted_servers: config
+                .get_slice("trusted_servers")
+                .map(|servers| {
+                    servers
+                        .iter()
+                        .filter_map(|server| server.as_str())
+                        .filter_map(|server| Box::<ServerName>::try_from(server).ok())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            allow_federation: config.get_bool("allow_federation").unwrap_or(true),
+            federation_allowlist: config
+                .get_slice("federation_allowlist")
+                .ok()
+                .map(|servers| {
+                    servers
+                        .iter()
+                        .filter_map(|server| server.as_str())
+                        .filter_map(|server| Box::<ServerName>::try_from(server).ok())
+                        .collect()
+                }),
+            federation_denylist: config
+                .get_slice("federation_denylist")
+                .map(|servers| {
+                    servers
+                        .iter()
+                        .filter_map(|server| server.as_str())
+                        .filter_map(|server| Box::<ServerName>::try_from(server).ok())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            admins: config
+                .get_slice("admins")
+                .map(|admins| {
+                    admins
+                        .iter()
+                        .filter_map(|admin| admin.as_str())
+                        .filter_map(|admin| UserId::try_from(admin).ok())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            actual_destination_cache: Arc::new(RwLock::new(HashMap::new())),
+            remote_alias_cache: Arc::new(RwLock::new(HashMap::new())),
+            remote_profile_cache: Arc::new(RwLock::new(HashMap::new())),
         })
     }
 
@@ -77,6 +169,27 @@ impl Globals<'_> {
         .map_err(|_| Error::bad_database("Count has invalid bytes."))?)
     }
 
+    /// Returns the next count for a backfilled pdu. These counts get lower with every call.
+    pub fn next_backfill_count(&self) -> Result<u64> {
+        let count = utils::u64_from_bytes(
+            &self
+                .globals
+                .update_and_fetch(
+                    BACKFILL_COUNTER,
+                    utils::decrement_from(BACKFILL_COUNT_START - 1),
+                )?
+                .expect("utils::decrement_from will always put in a value"),
+        )
+        .map_err(|_| Error::bad_database("Count has invalid bytes."))?;
+
+        // Zero is the count clients start syncing from
+        if count == 0 {
+            return Err(Error::bad_database("No backfill counts left."));
+        }
+
+        Ok(count)
+    }
+
     pub fn current_count(&self) -> Result<u64> {
         self.globals.get(COUNTER)?.map_or(Ok(0_u64), |bytes| {
             Ok(utils::u64_from_bytes(&bytes)
@@ -103,4 +216,156 @@ impl Globals<'_> {
     pub fn jwt_decoding_key(&self) -> &jsonwebtoken::DecodingKey<'_> {
         &self.jwt_decoding_key
     }
+
+    /// Returns the server other servers should contact instead of us, if it is configured.
+    pub fn well_known_server(&self) -> Option<&str> {
+        self.well_known_server.as_deref()
+    }
+
+    /// Returns the servers we ask for signing keys when a server can't be reached itself.
+    pub fn trusted_servers(&self) -> &[Box<ServerName>] {
+        &self.trusted_servers
+    }
+
+    /// Checks if this server may talk to another server over federation.
+    pub fn federation_allowed(&self, server: &ServerName) -> bool {
+        self.allow_federation
+            && self
+                .federation_allowlist
+                .as_ref()
+                .map_or(true, |allowlist| allowlist.iter().any(|s| &**s == server))
+            && !self.federation_denylist.iter().any(|s| &**s == server)
+    }
+
+    /// Checks if a user may use the admin api of this server.
+    pub fn is_admin(&self, user_id: &UserId) -> bool {
+        self.admins.contains(user_id)
+    }
+
+    /// Stores the signing keys of a server.
+    ///
+    /// Keys we knew before are kept as old verify keys, so older events can still be verified.
+    pub fn add_signing_key(&self, origin: &ServerName, new_keys: ServerKey) -> Result<()> {
+        let keys = match self.signing_keys_for(origin)? {
+            Some(stored_keys) => {
+                // A notary might give us an older response than the one we have
+                let (mut keys, other) = if stored_keys.valid_until_ts > new_keys.valid_until_ts {
+                    (stored_keys, new_keys)
+                } else {
+                    (new_keys, stored_keys)
+                };
+
+                for (key_id, verify_key) in other.verify_keys {
+                    if !keys.verify_keys.contains_key(&key_id) {
+                        keys.old_verify_keys.entry(key_id).or_insert(OldVerifyKey {
+                            expired_ts: other.valid_until_ts,
+                            key: verify_key.key,
+                        });
+                    }
+                }
+
+                for (key_id, old_key) in other.old_verify_keys {
+                    if !keys.verify_keys.contains_key(&key_id) {
+                        keys.old_verify_keys.entry(key_id).or_insert(old_key);
+                    }
+                }
+
+                keys
+            }
+            None => new_keys,
+        };
+
+        self.servername_signingkeys.insert(
+            origin.as_str().as_bytes(),
+            &*serde_json::to_string(&keys).expect("ServerKey::to_string always works"),
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the stored signing keys of a server, which might be expired.
+    pub fn signing_keys_for(&self, origin: &ServerName) -> Result<Option<ServerKey>> {
+        self.servername_signingkeys
+            .get(origin.as_str().as_bytes())?
+            .map_or(Ok(None), |bytes| {
+                Ok(Some(serde_json::from_slice(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid signing keys in db.")
+                })?))
+            })
+    }
+
+    /// Returns the actual destination and Host header of a server if they are still cached.
+    pub fn cached_destination(&self, destination: &str) -> Option<(String, String)> {
+        self.actual_destination_cache
+            .read()
+            .unwrap()
+            .get(destination)
+            .filter(|(_, _, valid_until)| Instant::now() < *valid_until)
+            .map(|(actual_destination, host, _)| (actual_destination.clone(), host.clone()))
+    }
+
+    /// Caches the result of resolving a server name until `valid_until`.
+    pub fn cache_destination(
+        &self,
+        destination: String,
+        actual_destination: String,
+        host: String,
+        valid_until: Instant,
+    ) {
+        self.actual_destination_cache
+            .write()
+            .unwrap()
+            .insert(destination, (actual_destination, host, valid_until));
+    }
+
+    /// Returns the room id and servers of a remote alias if they are still cached.
+    pub fn cached_remote_alias(&self, alias: &RoomAliasId) -> Option<(RoomId, Vec<String>)> {
+        self.remote_alias_cache
+            .read()
+            .unwrap()
+            .get(alias)
+            .filter(|(_, _, valid_until)| Instant::now() < *valid_until)
+            .map(|(room_id, servers, _)| (room_id.clone(), servers.clone()))
+    }
+
+    /// Caches the room id and servers of a remote alias until `valid_until`.
+    pub fn cache_remote_alias(
+        &self,
+        alias: RoomAliasId,
+        room_id: RoomId,
+        servers: Vec<String>,
+        valid_until: Instant,
+    ) {
+        self.remote_alias_cache
+            .write()
+            .unwrap()
+            .insert(alias, (room_id, servers, valid_until));
+    }
+
+    /// Returns the displayname and avatar url of a remote user if they are still cached.
+    pub fn cached_remote_profile(
+        &self,
+        user_id: &UserId,
+    ) -> Option<(Option<String>, Option<String>)> {
+        self.remote_profile_cache
+            .read()
+            .unwrap()
+            .get(user_id)
+            .filter(|(_, _, valid_until)| Instant::now() < *valid_until)
+            .map(|(displayname, avatar_url, _)| (displayname.clone(), avatar_url.clone()))
+    }
+
+    /// Caches the displayname and avatar url of a remote user until `valid_until`.
+    pub fn cache_remote_profile(
+        &self,
+        user_id: UserId,
+        displayname: Option<String>,
+        avatar_url: Option<String>,
+        valid_until: Instant,
+    ) {
+        self.remote_profile_cache
+            .write()
+            .unwrap()
+            .insert(user_id, (displayname, avatar_url, valid_until));
+    }
 }
@@ -9,6 +9,7 @@ pub struct FileMeta {
 
 pub struct Media {
     pub(super) mediaid_file: sled::Tree, // MediaId = MXC + WidthHeight + Filename + ContentType
+    pub(super) remote_mediaid_file: sled::Tree, // Cache of media from other servers, same keys
 }
 
 impl Media {
@@ -19,6 +20,59 @@ impl Media {
         filename: Option<&String>,
         content_type: &str,
         file: &[u8],
+    ) -> Result<()> {
+        Self::create_in(&self.mediaid_file, mxc, filename, content_type, file)
+    }
+
+    /// Downloads a file.
+    pub fn get(&self, mxc: String) -> Result<Option<FileMeta>> {
+        Self::get_from(&self.mediaid_file, mxc)
+    }
+
+    /// Downloads a file's thumbnail.
+    pub fn get_thumbnail(&self, mxc: String, width: u32, height: u32) -> Result<Option<FileMeta>> {
+        Self::get_thumbnail_from(&self.mediaid_file, mxc, width, height)
+    }
+
+    /// Adds a file we downloaded from another server to the remote media cache.
+    pub fn create_remote(
+        &self,
+        mxc: String,
+        filename: Option<&String>,
+        content_type: &str,
+        file: &[u8],
+    ) -> Result<()> {
+        Self::create_in(&self.remote_mediaid_file, mxc, filename, content_type, file)
+    }
+
+    /// Returns a file from the remote media cache.
+    pub fn get_remote(&self, mxc: String) -> Result<Option<FileMeta>> {
+        Self::get_from(&self.remote_mediaid_file, mxc)
+    }
+
+    /// Returns a thumbnail of a file in the remote media cache.
+    pub fn get_remote_thumbnail(
+        &self,
+        mxc: String,
+        width: u32,
+        height: u32,
+    ) -> Result<Option<FileMeta>> {
+        Self::get_thumbnail_from(&self.remote_mediaid_file, mxc, width, height)
+    }
+
+    /// Removes all files and thumbnails from the remote media cache. Our own uploads are kept.
+    pub fn clear_remote_cache(&self) -> Result<()> {
+        self.remote_mediaid_file.clear()?;
+
+        Ok(())
+    }
+
+    fn create_in(
+        tree: &sled::Tree,
+        mxc: String,
+        filename: Option<&String>,
+        content_type: &str,
+        file: &[u8],
     ) -> Result<()> {
         let mut key = mxc.as_bytes().to_vec();
         key.push(0xff);
@@ -29,20 +83,19 @@ impl Media {
         key.push(0xff);
         key.extend_from_slice(content_type.as_bytes());
 
-        self.mediaid_file.insert(key, file)?;
+        tree.insert(key, file)?;
 
         Ok(())
     }
 
-    /// Downloads a file.
-    pub fn get(&self, mxc: String) -> Result<Option<FileMeta>> {
+    fn get_from(tree: &sled::Tree, mxc: String) -> Result<Option<FileMeta>> {
         let mut prefix = mxc.as_bytes().to_vec();
         prefix.push(0xff);
         prefix.extend_from_slice(&0_u32.to_be_bytes()); // Width = 0 if it's not a thumbnail
         prefix.extend_from_slice(&0_u32.to_be_bytes()); // Height = 0 if it's not a thumbnail
         prefix.push(0xff);
 
-        if let Some(r) = self.mediaid_file.scan_prefix(&prefix).next() {
+        if let Some(r) = tree.scan_prefix(&prefix).next() {
             let (key, file) = r?;
             let mut parts = key.rsplit(|&b| b == 0xff);
 
@@ -75,8 +128,12 @@ impl Media {
         }
     }
 
-    /// Downloads a file's thumbnail.
-    pub fn get_thumbnail(&self, mxc: String, width: u32, height: u32) -> Result<Option<FileMeta>> {
+    fn get_thumbnail_from(
+        tree: &sled::Tree,
+        mxc: String,
+        width: u32,
+        height: u32,
+    ) -> Result<Option<FileMeta>> {
         let mut main_prefix = mxc.as_bytes().to_vec();
         main_prefix.push(0xff);
 
@@ -90,7 +147,7 @@ impl Media {
         original_prefix.extend_from_slice(&0_u32.to_be_bytes()); // Height = 0 if it's not a thumbnail
         original_prefix.push(0xff);
 
-        if let Some(r) = self.mediaid_file.scan_prefix(&thumbnail_prefix).next() {
+        if let Some(r) = tree.scan_prefix(&thumbnail_prefix).next() {
             // Using saved thumbnail
             let (key, file) = r?;
             let mut parts = key.rsplit(|&b| b == 0xff);
@@ -120,7 +177,7 @@ impl Media {
                 content_type,
                 file: file.to_vec(),
             }))
-        } else if let Some(r) = self.mediaid_file.scan_prefix(&original_prefix).next() {
+        } else if let Some(r) = tree.scan_prefix(&original_prefix).next() {
             // Generate a thumbnail
             let (key, file) = r?;
             let mut parts = key.rsplit(|&b| b == 0xff);
@@ -164,7 +221,7 @@ impl Media {
                     widthheight,
                 );
 
-                self.mediaid_file.insert(thumbnail_key, &*thumbnail_bytes)?;
+                tree.insert(thumbnail_key, &*thumbnail_bytes)?;
 
                 Ok(Some(FileMeta {
                     filename,
@@ -2,33 +2,67 @@ mod edus;
 
 pub use edus::RoomEdus;
 
-use crate::{pdu::PduBuilder, utils, Error, PduEvent, Result};
+use crate::{
+    event_auth,
+    pdu::PduBuilder,
+    room_version::RoomVersion,
+    stateres::{self, StateMap},
+    utils, Error, PduEvent, Result,
+};
 use log::error;
 use ruma::{
     api::client::error::ErrorKind,
     events::{
         ignored_user_list,
-        room::{
-            join_rules, member,
-            power_levels::{self, PowerLevelsEventContent},
-        },
-        EventType,
+        room::{create, member, server_acl},
+        AnyStrippedStateEvent, EventType,
     },
-    EventId, Raw, RoomAliasId, RoomId, UserId,
+    EventId, Raw, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
 };
+use serde::{Deserialize, Serialize};
 use sled::IVec;
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{HashMap, HashSet},
     convert::{TryFrom, TryInto},
     mem,
+    sync::{Arc, Mutex, Weak},
 };
 
+/// After this many deltas, a state group is stored as a full snapshot again.
+const MAX_STATE_DELTA_DEPTH: u32 = 100;
+
+/// A state group, stored as the changes to its parent state group.
+#[derive(Deserialize, Serialize)]
+struct StateDelta {
+    parent: Option<u64>,
+    depth: u32,
+    added: Vec<(EventType, String, EventId)>,
+    removed: Vec<(EventType, String)>,
+}
+
+/// Why a pdu from another server was not added to the timeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PduFailure {
+    /// The pdu is not allowed by its auth events or the state before it, so it is never used.
+    Rejected,
+    /// The pdu is allowed by the state before it, but not by the current state of the room. It
+    /// stays in the event graph, but clients don't see it and it doesn't change the room state.
+    SoftFailed,
+}
+
+#[derive(Clone)]
 pub struct Rooms {
     pub edus: edus::RoomEdus,
     pub(super) pduid_pdu: sled::Tree, // PduId = RoomId + Count
     pub(super) eventid_pduid: sled::Tree,
+    pub(super) eventid_outlierpdu: sled::Tree, // Pdus that are not part of the timeline
+    pub(super) eventid_rejectedpdu: sled::Tree,
+    pub(super) roomeventid_pdufailure: sled::Tree, // RoomEventId = RoomId + EventId
     pub(super) roomid_pduleaves: sled::Tree,
-    pub(super) roomstateid_pdu: sled::Tree, // RoomStateId = Room + StateType + StateKey
+    pub(super) eventid_stateids: sled::Tree, // StateIds = StateId before + StateId after
+    pub(super) stateid_delta: sled::Tree,    // Delta = Parent StateId + Added + Removed
+    pub(super) roomstateid_pdu: sled::Tree,  // RoomStateId = Room + StateType + StateKey
 
     pub(super) alias_roomid: sled::Tree,
     pub(super) aliasid_alias: sled::Tree, // AliasId = RoomId + Count
@@ -41,7 +75,12 @@ pub struct Rooms {
     pub(super) roomuseroncejoinedids: sled::Tree,
     pub(super) userroomid_invited: sled::Tree,
     pub(super) roomuserid_invited: sled::Tree,
+    pub(super) userroomid_invitestate: sled::Tree, // InviteState = Count + Stripped state json
     pub(super) userroomid_left: sled::Tree,
+    pub(super) userroomid_remoteleave: sled::Tree, // RemoteLeave = Count + EventId
+
+    /// Serializes appends to the timeline of each room, so concurrent events don't fork it.
+    pub(super) roomid_mutex: Arc<Mutex<HashMap<RoomId, Weak<Mutex<()>>>>>,
 }
 
 impl Rooms {
@@ -136,6 +175,27 @@ impl Rooms {
         })
     }
 
+    /// Returns the version of a room, which is found in its create event.
+    pub fn room_version(&self, room_id: &RoomId) -> Result<RoomVersionId> {
+        let create_event = self
+            .room_state_get(room_id, &EventType::RoomCreate, "")?
+            .ok_or_else(|| Error::bad_database("Found room without m.room.create event."))?;
+
+        Ok(
+            serde_json::from_value::<Raw<create::CreateEventContent>>(create_event.content)
+                .expect("Raw::from_value always works")
+                .deserialize()
+                .map_err(|_| Error::bad_database("Invalid create event in db."))?
+                .room_version,
+        )
+    }
+
+    /// Returns the rules of the version of a room.
+    pub fn room_version_rules(&self, room_id: &RoomId) -> Result<RoomVersion> {
+        RoomVersion::new(&self.room_version(room_id)?)
+            .ok_or_else(|| Error::bad_database("Room in db has an unsupported room version."))
+    }
+
     /// Returns the `count` of this pdu's id.
     pub fn get_pdu_count(&self, event_id: &EventId) -> Result<Option<u64>> {
         self.eventid_pduid
@@ -151,15 +211,28 @@ impl Rooms {
     }
 
     /// Returns the json of a pdu.
+    ///
+    /// This also finds outliers, which are not part of the timeline.
     pub fn get_pdu_json(&self, event_id: &EventId) -> Result<Option<serde_json::Value>> {
         self.eventid_pduid
             .get(event_id.to_string().as_bytes())?
-            .map_or(Ok(None), |pdu_id| {
-                Ok(Some(
-                    serde_json::from_slice(&self.pduid_pdu.get(pdu_id)?.ok_or_else(|| {
+            .map_or_else(
+                || {
+                    Ok::<_, Error>(
+                        self.eventid_outlierpdu
+                            .get(event_id.to_string().as_bytes())?,
+                    )
+                },
+                |pdu_id| {
+                    Ok(Some(self.pduid_pdu.get(pdu_id)?.ok_or_else(|| {
                         Error::bad_database("eventid_pduid points to nonexistent pdu.")
-                    })?)
-                    .map_err(|_| Error::bad_database("Invalid PDU in db."))?,
+                    })?))
+                },
+            )?
+            .map_or(Ok(None), |pdu| {
+                Ok(Some(
+                    serde_json::from_slice(&pdu)
+                        .map_err(|_| Error::bad_database("Invalid PDU in db."))?,
                 ))
             })
     }
@@ -172,18 +245,234 @@ impl Rooms {
     }
 
     /// Returns the pdu.
+    ///
+    /// This also finds outliers, which are not part of the timeline.
     pub fn get_pdu(&self, event_id: &EventId) -> Result<Option<PduEvent>> {
-        self.eventid_pduid
-            .get(event_id.to_string().as_bytes())?
-            .map_or(Ok(None), |pdu_id| {
-                Ok(Some(
-                    serde_json::from_slice(&self.pduid_pdu.get(pdu_id)?.ok_or_else(|| {
-                        Error::bad_database("eventid_pduid points to nonexistent pdu.")
-                    })?)
+        self.get_pdu_json(event_id)?.map_or(Ok(None), |pdu_json| {
+            Ok(Some(
+                serde_json::from_value(pdu_json)
                     .map_err(|_| Error::bad_database("Invalid PDU in db."))?,
+            ))
+        })
+    }
+
+    /// Stores a pdu that is not part of the timeline, e.g. because it is only needed for the
+    /// auth chain of other events.
+    pub fn add_pdu_outlier(&self, pdu_json: &serde_json::Value) -> Result<()> {
+        let event_id = pdu_json
+            .get("event_id")
+            .and_then(|event_id| event_id.as_str())
+            .ok_or_else(|| Error::BadServerResponse("Outlier pdu has no event id."))?;
+
+        self.eventid_outlierpdu
+            .insert(event_id.as_bytes(), &*pdu_json.to_string())?;
+
+        Ok(())
+    }
+
+    /// Returns the oldest pdu in the timeline of a room.
+    pub fn first_pdu_in_room(&self, room_id: &RoomId) -> Result<Option<(u64, PduEvent)>> {
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.pduid_pdu
+            .scan_prefix(&prefix)
+            .next()
+            .map_or(Ok(None), |r| {
+                let (pdu_id, pdu) = r?;
+                Ok(Some((
+                    utils::u64_from_bytes(&pdu_id[prefix.len()..])
+                        .map_err(|_| Error::bad_database("Invalid pdu id in db."))?,
+                    serde_json::from_slice(&pdu)
+                        .map_err(|_| Error::bad_database("Invalid PDU in db."))?,
+                )))
+            })
+    }
+
+    /// Adds a pdu from the history of a room before the start of our timeline. This doesn't
+    /// change the leaves or the state of the room.
+    ///
+    /// The count has to be lower than the counts of all pdus in the room's timeline.
+    pub fn add_backfilled_pdu(
+        &self,
+        pdu: &PduEvent,
+        pdu_json: &serde_json::Value,
+        count: u64,
+    ) -> Result<()> {
+        let mut pdu_id = pdu.room_id.to_string().as_bytes().to_vec();
+        pdu_id.push(0xff);
+        pdu_id.extend_from_slice(&count.to_be_bytes());
+
+        self.pduid_pdu.insert(&pdu_id, &*pdu_json.to_string())?;
+        self.eventid_pduid
+            .insert(pdu.event_id.to_string(), pdu_id)?;
+
+        // State events might have been stored as outliers when we joined the room
+        self.eventid_outlierpdu.remove(pdu.event_id.to_string())?;
+
+        Ok(())
+    }
+
+    /// Stores a pdu that was rejected, so it is never used or processed again.
+    pub fn add_rejected_pdu(&self, pdu: &PduEvent, pdu_json: &serde_json::Value) -> Result<()> {
+        self.eventid_rejectedpdu
+            .insert(pdu.event_id.to_string(), &*pdu_json.to_string())?;
+        self.set_pdu_failure(pdu, PduFailure::Rejected)
+    }
+
+    /// Stores a pdu that was soft failed. It is kept with its state, so later events can still
+    /// reference it, but it is not part of the timeline.
+    pub fn add_soft_failed_pdu(
+        &self,
+        pdu: &PduEvent,
+        pdu_json: &serde_json::Value,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<()> {
+        self.save_event_state(pdu, globals)?;
+        self.add_pdu_outlier(pdu_json)?;
+        self.set_pdu_failure(pdu, PduFailure::SoftFailed)
+    }
+
+    fn set_pdu_failure(&self, pdu: &PduEvent, failure: PduFailure) -> Result<()> {
+        let mut key = pdu.room_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(pdu.event_id.to_string().as_bytes());
+
+        self.roomeventid_pdufailure.insert(
+            key,
+            &*serde_json::to_string(&failure).expect("PduFailure::to_string always works"),
+        )?;
+
+        Ok(())
+    }
+
+    /// Checks if a pdu was rejected.
+    pub fn is_rejected(&self, event_id: &EventId) -> Result<bool> {
+        Ok(self
+            .eventid_rejectedpdu
+            .contains_key(event_id.to_string())?)
+    }
+
+    /// Returns why a pdu was not added to the timeline, if it was rejected or soft failed.
+    pub fn pdu_failure(&self, room_id: &RoomId, event_id: &EventId) -> Result<Option<PduFailure>> {
+        let mut key = room_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(event_id.to_string().as_bytes());
+
+        self.roomeventid_pdufailure
+            .get(key)?
+            .map_or(Ok(None), |failure| {
+                Ok(Some(serde_json::from_slice(&failure).map_err(|_| {
+                    Error::bad_database("Invalid pdu failure in db.")
+                })?))
+            })
+    }
+
+    /// Returns all pdus of a room that were rejected or soft failed.
+    pub fn pdu_failures(
+        &self,
+        room_id: &RoomId,
+    ) -> impl Iterator<Item = Result<(EventId, PduFailure)>> {
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.roomeventid_pdufailure
+            .scan_prefix(&prefix)
+            .map(move |r| {
+                let (key, failure) = r?;
+                Ok((
+                    EventId::try_from(utils::string_from_bytes(&key[prefix.len()..]).map_err(
+                        |_| {
+                            Error::bad_database(
+                                "Event id in roomeventid_pdufailure is invalid unicode.",
+                            )
+                        },
+                    )?)
+                    .map_err(|_| {
+                        Error::bad_database("Event id in roomeventid_pdufailure is invalid.")
+                    })?,
+                    serde_json::from_slice(&failure)
+                        .map_err(|_| Error::bad_database("Invalid pdu failure in db."))?,
                 ))
             })
     }
+
+    /// Replaces the current state of a room and updates the memberships of all users in it.
+    ///
+    /// This is used when we join a room that lives on another server and only get the state
+    /// instead of the whole history.
+    pub fn force_state(
+        &self,
+        room_id: &RoomId,
+        state: &[serde_json::Value],
+        globals: &super::globals::Globals<'_>,
+        account_data: &super::account_data::AccountData,
+        sending: &super::sending::Sending,
+    ) -> Result<()> {
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        for key in self.roomstateid_pdu.scan_prefix(&prefix).keys() {
+            self.roomstateid_pdu.remove(key?)?;
+        }
+
+        let mut state_pdus = Vec::new();
+        for pdu_json in state {
+            let pdu = serde_json::from_value::<PduEvent>(pdu_json.clone())
+                .map_err(|_| Error::BadServerResponse("Invalid state pdu."))?;
+            let state_key = pdu
+                .state_key
+                .as_ref()
+                .ok_or_else(|| Error::BadServerResponse("State pdu has no state key."))?;
+
+            let mut key = prefix.clone();
+            key.extend_from_slice(pdu.kind.to_string().as_bytes());
+            key.push(0xff);
+            key.extend_from_slice(state_key.as_bytes());
+            self.roomstateid_pdu.insert(key, &*pdu_json.to_string())?;
+
+            state_pdus.push(pdu);
+        }
+
+        // Memberships are updated after the whole state is known, because they need the create
+        // event
+        for pdu in state_pdus
+            .into_iter()
+            .filter(|pdu| pdu.kind == EventType::RoomMember)
+        {
+            if let Some(target_user_id) = pdu
+                .state_key
+                .as_ref()
+                .and_then(|state_key| UserId::try_from(state_key.clone()).ok())
+            {
+                if let Ok(member_content) =
+                    serde_json::from_value::<member::MemberEventContent>(pdu.content.clone())
+                {
+                    self.update_membership(
+                        room_id,
+                        &target_user_id,
+                        member_content,
+                        &pdu.sender,
+                        account_data,
+                        globals,
+                        sending,
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+    /// Returns the json of a pdu by its pdu id.
+    pub fn get_pdu_json_from_id(&self, pdu_id: &IVec) -> Result<Option<serde_json::Value>> {
+        self.pduid_pdu.get(pdu_id)?.map_or(Ok(None), |pdu| {
+            Ok(Some(
+                serde_json::from_slice(&pdu)
+                    .map_err(|_| Error::bad_database("Invalid PDU in db."))?,
+            ))
+        })
+    }
+
     /// Returns the pdu.
     pub fn get_pdu_from_id(&self, pdu_id: &IVec) -> Result<Option<PduEvent>> {
         self.pduid_pdu.get(pdu_id)?.map_or(Ok(None), |pdu| {
@@ -210,6 +499,30 @@ impl Rooms {
         }
     }
 
+    /// Selects the current state events that authorize a new event, as described in the auth
+    /// events selection rules of the spec.
+    pub fn get_auth_events(
+        &self,
+        room_id: &RoomId,
+        kind: &EventType,
+        sender: &UserId,
+        state_key: Option<&str>,
+        content: &serde_json::Value,
+    ) -> Result<Vec<EventId>> {
+        let auth_types = event_auth::auth_types(kind, sender, state_key, content);
+
+        let mut auth_events = Vec::new();
+        for (event_type, state_key) in auth_types {
+            if let Some(pdu) = self.room_state_get(room_id, &event_type, &state_key)? {
+                if !auth_events.contains(&pdu.event_id) {
+                    auth_events.push(pdu.event_id);
+                }
+            }
+        }
+
+        Ok(auth_events)
+    }
+
     /// Returns the leaf pdus of a room.
     pub fn get_pdu_leaves(&self, room_id: &RoomId) -> Result<Vec<EventId>> {
         let mut prefix = room_id.to_string().as_bytes().to_vec();
@@ -236,8 +549,8 @@ impl Rooms {
         Ok(events)
     }
 
-    /// Replace the leaves of a room with a new event.
-    pub fn replace_pdu_leaves(&self, room_id: &RoomId, event_id: &EventId) -> Result<()> {
+    /// Replace the leaves of a room.
+    pub fn replace_pdu_leaves(&self, room_id: &RoomId, event_ids: &[EventId]) -> Result<()> {
         let mut prefix = room_id.to_string().as_bytes().to_vec();
         prefix.push(0xff);
 
@@ -245,21 +558,418 @@ impl Rooms {
             self.roomid_pduleaves.remove(key?)?;
         }
 
-        prefix.extend_from_slice(event_id.to_string().as_bytes());
-        self.roomid_pduleaves
-            .insert(&prefix, &*event_id.to_string())?;
+        for event_id in event_ids {
+            let mut key = prefix.clone();
+            key.extend_from_slice(event_id.to_string().as_bytes());
+            self.roomid_pduleaves.insert(&key, &*event_id.to_string())?;
+        }
 
         Ok(())
     }
 
-    /// Creates a new persisted data unit and adds it to a room.
-    #[allow(clippy::blocks_in_if_conditions)]
+    /// Returns the ids of the state groups before and after an event.
+    fn event_state_ids(&self, event_id: &EventId) -> Result<Option<(u64, u64)>> {
+        self.eventid_stateids
+            .get(event_id.to_string())?
+            .map_or(Ok(None), |bytes| {
+                let before = utils::u64_from_bytes(&bytes[..mem::size_of::<u64>()]);
+                let after = utils::u64_from_bytes(&bytes[mem::size_of::<u64>()..]);
+                match (before, after) {
+                    (Ok(before), Ok(after)) => Ok(Some((before, after))),
+                    _ => Err(Error::bad_database("Invalid state ids in db.")),
+                }
+            })
+    }
+
+    /// Returns the state before an event, if the event was appended to the timeline.
+    pub fn state_before(&self, event_id: &EventId) -> Result<Option<StateMap>> {
+        self.event_state_ids(event_id)?
+            .map_or(Ok(None), |(before, _)| Ok(Some(self.load_state(before)?)))
+    }
+
+    /// Returns the state after an event, if the event was appended to the timeline.
+    pub fn state_after(&self, event_id: &EventId) -> Result<Option<StateMap>> {
+        self.event_state_ids(event_id)?
+            .map_or(Ok(None), |(_, after)| Ok(Some(self.load_state(after)?)))
+    }
+
+    /// Returns the pdus of a state. Events we don't know are skipped.
+    pub fn state_pdus(&self, state: &StateMap) -> Result<HashMap<(EventType, String), PduEvent>> {
+        let mut pdus = HashMap::new();
+        for (key, event_id) in state {
+            if let Some(pdu) = self.get_pdu(event_id)? {
+                pdus.insert(key.clone(), pdu);
+            }
+        }
+        Ok(pdus)
+    }
+
+    /// Loads a state group by applying all deltas from the last full snapshot.
+    fn load_state(&self, state_id: u64) -> Result<StateMap> {
+        let mut deltas = Vec::new();
+        let mut current = Some(state_id);
+        while let Some(state_id) = current {
+            let delta = serde_json::from_slice::<StateDelta>(
+                &self
+                    .stateid_delta
+                    .get(state_id.to_be_bytes())?
+                    .ok_or_else(|| Error::bad_database("State group does not exist."))?,
+            )
+            .map_err(|_| Error::bad_database("Invalid state delta in db."))?;
+
+            current = delta.parent;
+            deltas.push(delta);
+        }
+
+        let mut state = StateMap::new();
+        for delta in deltas.into_iter().rev() {
+            for (event_type, state_key) in delta.removed {
+                state.remove(&(event_type, state_key));
+            }
+            for (event_type, state_key, event_id) in delta.added {
+                state.insert((event_type, state_key), event_id);
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Stores a state group as a delta to the `base` state group and returns its id. If the
+    /// state didn't change, the base is reused.
+    fn save_state(
+        &self,
+        base: Option<(u64, &StateMap)>,
+        state: &StateMap,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<u64> {
+        let empty = StateMap::new();
+
+        let (parent, depth, base_state) = match base {
+            Some((base_id, base_state)) if base_state == state => return Ok(base_id),
+            Some((base_id, base_state)) => {
+                let base_depth = serde_json::from_slice::<StateDelta>(
+                    &self
+                        .stateid_delta
+                        .get(base_id.to_be_bytes())?
+                        .ok_or_else(|| Error::bad_database("State group does not exist."))?,
+                )
+                .map_err(|_| Error::bad_database("Invalid state delta in db."))?
+                .depth;
+
+                // Long chains of deltas are slow to load, so we start over with a full snapshot
+                if base_depth >= MAX_STATE_DELTA_DEPTH {
+                    (None, 0, &empty)
+                } else {
+                    (Some(base_id), base_depth + 1, base_state)
+                }
+            }
+            None => (None, 0, &empty),
+        };
+
+        let delta = StateDelta {
+            parent,
+            depth,
+            added: state
+                .iter()
+                .filter(|(key, event_id)| base_state.get(key) != Some(event_id))
+                .map(|((event_type, state_key), event_id)| {
+                    (event_type.clone(), state_key.clone(), event_id.clone())
+                })
+                .collect(),
+            removed: base_state
+                .keys()
+                .filter(|key| !state.contains_key(key))
+                .cloned()
+                .collect(),
+        };
+
+        let state_id = globals.next_count()?;
+        self.stateid_delta.insert(
+            state_id.to_be_bytes(),
+            &*serde_json::to_string(&delta).expect("StateDelta::to_string always works"),
+        )?;
+
+        Ok(state_id)
+    }
+
+    /// Resolves the states after the given events. Events that were never appended are replaced
+    /// by the current room state.
+    ///
+    /// Also returns one of the state groups, which the result can be stored relative to.
+    #[allow(clippy::type_complexity)]
+    fn resolve_states_after(
+        &self,
+        room_id: &RoomId,
+        event_ids: &[EventId],
+    ) -> Result<(Option<(u64, StateMap)>, StateMap)> {
+        let mut state_groups = Vec::new();
+        let mut needs_current_state = event_ids.is_empty();
+
+        for event_id in event_ids {
+            match self.event_state_ids(event_id)? {
+                Some((_, after)) => {
+                    if !state_groups.iter().any(|(state_id, _)| *state_id == after) {
+                        state_groups.push((after, self.load_state(after)?));
+                    }
+                }
+                None => needs_current_state = true,
+            }
+        }
+
+        let mut state_sets = state_groups
+            .iter()
+            .map(|(_, state)| state.clone())
+            .collect::<Vec<_>>();
+
+        if needs_current_state {
+            state_sets.push(
+                self.room_state_full(room_id)?
+                    .into_iter()
+                    .map(|(key, pdu)| (key, pdu.event_id))
+                    .collect(),
+            );
+        }
+
+        let resolved = stateres::resolve(self, &state_sets)?;
+
+        Ok((state_groups.into_iter().next(), resolved))
+    }
+
+    /// Replaces the current state of a room and updates the memberships that changed.
+    fn set_room_state(
+        &self,
+        room_id: &RoomId,
+        state: &StateMap,
+        globals: &super::globals::Globals<'_>,
+        account_data: &super::account_data::AccountData,
+        sending: &super::sending::Sending,
+    ) -> Result<()> {
+        let current_state = self.room_state_full(room_id)?;
+
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        for (event_type, state_key) in current_state.keys() {
+            if !state.contains_key(&(event_type.clone(), state_key.clone())) {
+                let mut key = prefix.clone();
+                key.extend_from_slice(event_type.to_string().as_bytes());
+                key.push(0xff);
+                key.extend_from_slice(state_key.as_bytes());
+                self.roomstateid_pdu.remove(key)?;
+            }
+        }
+
+        let mut changed_members = Vec::new();
+        for ((event_type, state_key), event_id) in state {
+            if current_state
+                .get(&(event_type.clone(), state_key.clone()))
+                .map_or(false, |pdu| &pdu.event_id == event_id)
+            {
+                continue;
+            }
+
+            let pdu_json = self
+                .get_pdu_json(event_id)?
+                .ok_or_else(|| Error::bad_database("Room state contains unknown event."))?;
+
+            let mut key = prefix.clone();
+            key.extend_from_slice(event_type.to_string().as_bytes());
+            key.push(0xff);
+            key.extend_from_slice(state_key.as_bytes());
+            self.roomstateid_pdu.insert(key, &*pdu_json.to_string())?;
+
+            if *event_type == EventType::RoomMember {
+                changed_members.push(
+                    serde_json::from_value::<PduEvent>(pdu_json)
+                        .map_err(|_| Error::bad_database("Invalid PDU in db."))?,
+                );
+            }
+        }
+
+        // Memberships are updated after the whole state is known, because they need the create
+        // event
+        for pdu in changed_members {
+            let target_user_id = UserId::try_from(pdu.state_key.clone().unwrap_or_default())
+                .map_err(|_| Error::bad_database("Member event has invalid state key."))?;
+
+            // Update our membership info, we do this here incase a user is invited
+            // and immediately leaves we need the DB to record the invite event for auth
+            self.update_membership(
+                room_id,
+                &target_user_id,
+                serde_json::from_value::<member::MemberEventContent>(pdu.content.clone()).map_err(
+                    |_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid member event content."),
+                )?,
+                &pdu.sender,
+                account_data,
+                globals,
+                sending,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks if the pdu is allowed by the auth rules, using the current room state.
+    pub fn auth_check(&self, pdu: &PduEvent) -> Result<bool> {
+        event_auth::auth_check(pdu, &|event_type, state_key| {
+            self.room_state_get(&pdu.room_id, event_type, state_key)
+        })
+    }
+
+    /// Returns the state before a pdu that was not stored yet, which is the resolved state after
+    /// its prev events.
+    pub fn state_at_prev_events(&self, pdu: &PduEvent) -> Result<StateMap> {
+        Ok(self.resolve_states_after(&pdu.room_id, &pdu.prev_events)?.1)
+    }
+
+    /// Stores the state groups before and after a pdu and returns the state after it.
+    fn save_event_state(
+        &self,
+        pdu: &PduEvent,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<StateMap> {
+        let (base, state_before) = self.resolve_states_after(&pdu.room_id, &pdu.prev_events)?;
+        let before_id = self.save_state(
+            base.as_ref().map(|(state_id, state)| (*state_id, state)),
+            &state_before,
+            globals,
+        )?;
+
+        let mut state = state_before.clone();
+        let after_id = if let Some(state_key) = &pdu.state_key {
+            state.insert((pdu.kind.clone(), state_key.clone()), pdu.event_id.clone());
+            self.save_state(Some((before_id, &state_before)), &state, globals)?
+        } else {
+            before_id
+        };
+
+        let mut state_ids = before_id.to_be_bytes().to_vec();
+        state_ids.extend_from_slice(&after_id.to_be_bytes());
+        self.eventid_stateids
+            .insert(pdu.event_id.to_string(), state_ids)?;
+
+        Ok(state)
+    }
+
+    /// Returns the mutex that has to be held while creating and appending pdus in a room.
+    pub fn room_mutex(&self, room_id: &RoomId) -> Arc<Mutex<()>> {
+        let mut roomid_mutex = self.roomid_mutex.lock().unwrap();
+
+        // Mutexes are dropped when nobody uses them anymore, so only rooms in use have entries
+        roomid_mutex.retain(|_, mutex| mutex.strong_count() > 0);
+
+        if let Some(mutex) = roomid_mutex.get(room_id).and_then(Weak::upgrade) {
+            return mutex;
+        }
+
+        let mutex = Arc::new(Mutex::new(()));
+        roomid_mutex.insert(room_id.clone(), Arc::downgrade(&mutex));
+        mutex
+    }
+
+    /// Persists a pdu that was already checked and updates the room's leaves, state and
+    /// memberships. Returns the pdu id.
+    ///
+    /// The caller has to hold the room mutex (see `room_mutex`).
     pub fn append_pdu(
         &self,
-        pdu_builder: PduBuilder,
+        pdu: &PduEvent,
+        pdu_json: &serde_json::Value,
+        count: u64,
         globals: &super::globals::Globals<'_>,
         account_data: &super::account_data::AccountData,
-    ) -> Result<EventId> {
+        sending: &super::sending::Sending,
+    ) -> Result<Vec<u8>> {
+        let mut state = self.save_event_state(pdu, globals)?;
+
+        // The new event replaces all leaves it references
+        let mut leaves = self
+            .get_pdu_leaves(&pdu.room_id)?
+            .into_iter()
+            .filter(|event_id| !pdu.prev_events.contains(event_id))
+            .collect::<Vec<_>>();
+        leaves.push(pdu.event_id.clone());
+        self.replace_pdu_leaves(&pdu.room_id, &leaves)?;
+
+        let mut pdu_id = pdu.room_id.to_string().as_bytes().to_vec();
+        pdu_id.push(0xff);
+        pdu_id.extend_from_slice(&count.to_be_bytes());
+
+        self.pduid_pdu.insert(&pdu_id, &*pdu_json.to_string())?;
+
+        self.eventid_pduid
+            .insert(pdu.event_id.to_string(), pdu_id.clone())?;
+
+        // If the room has forks, its current state is the resolved state of all of them
+        if leaves.len() > 1 {
+            state = self.resolve_states_after(&pdu.room_id, &leaves)?.1;
+        }
+        self.set_room_state(&pdu.room_id, &state, globals, account_data, sending)?;
+
+        match pdu.kind {
+            EventType::RoomRedaction => {
+                if let Some(redact_id) = &pdu.redacts {
+                    // We don't care if the redacted event is unknown to us or in another room
+                    if let Some(redacted) = self
+                        .get_pdu(redact_id)?
+                        .filter(|redacted| redacted.room_id == pdu.room_id)
+                    {
+                        // Newer room versions check redactions when they are applied, the
+                        // redaction event itself is kept either way
+                        let allowed = self
+                            .room_version_rules(&pdu.room_id)?
+                            .redaction_auth_on_receive
+                            || {
+                                let state_before = self.state_at_prev_events(pdu)?;
+                                event_auth::redaction_allowed(
+                                    pdu,
+                                    &redacted,
+                                    &|event_type, state_key| {
+                                        state_before
+                                            .get(&(event_type.clone(), state_key.to_owned()))
+                                            .map_or(Ok(None), |event_id| self.get_pdu(event_id))
+                                    },
+                                )?
+                            };
+
+                        if allowed {
+                            self.redact_pdu(&redact_id, &pdu)?;
+                        }
+                    }
+                }
+            }
+            EventType::RoomMessage => {
+                if let Some(body) = pdu.content.get("body").and_then(|b| b.as_str()) {
+                    for word in body
+                        .split_terminator(|c: char| !c.is_alphanumeric())
+                        .map(str::to_lowercase)
+                    {
+                        let mut key = pdu.room_id.to_string().as_bytes().to_vec();
+                        key.push(0xff);
+                        key.extend_from_slice(word.as_bytes());
+                        key.push(0xff);
+                        key.extend_from_slice(&pdu_id);
+                        self.tokenids.insert(key, &[])?;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Ok(pdu_id)
+    }
+
+    /// Creates a new pdu on top of the current leaves of the room and makes sure it is
+    /// authorized. The event id, hashes and signatures are not filled in yet.
+    ///
+    /// The caller has to hold the room mutex until the pdu is appended, otherwise another pdu
+    /// could be created on top of the same leaves.
+    pub fn create_pdu(
+        &self,
+        pdu_builder: PduBuilder,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<PduEvent> {
         let PduBuilder {
             room_id,
             sender,
@@ -269,224 +979,13 @@ impl Rooms {
             state_key,
             redacts,
         } = pdu_builder;
-        // TODO: Make sure this isn't called twice in parallel
         let prev_events = self.get_pdu_leaves(&room_id)?;
 
-        // Is the event authorized?
-        if let Some(state_key) = &state_key {
-            let power_levels = self
-                .room_state_get(&room_id, &EventType::RoomPowerLevels, "")?
-                .map_or_else(
-                    || {
-                        Ok::<_, Error>(power_levels::PowerLevelsEventContent {
-                            ban: 50.into(),
-                            events: BTreeMap::new(),
-                            events_default: 0.into(),
-                            invite: 50.into(),
-                            kick: 50.into(),
-                            redact: 50.into(),
-                            state_default: 0.into(),
-                            users: BTreeMap::new(),
-                            users_default: 0.into(),
-                            notifications:
-                                ruma::events::room::power_levels::NotificationPowerLevels {
-                                    room: 50.into(),
-                                },
-                        })
-                    },
-                    |power_levels| {
-                        Ok(serde_json::from_value::<Raw<PowerLevelsEventContent>>(
-                            power_levels.content,
-                        )
-                        .expect("Raw::from_value always works.")
-                        .deserialize()
-                        .map_err(|_| Error::bad_database("Invalid PowerLevels event in db."))?)
-                    },
-                )?;
-            let sender_membership = self
-                .room_state_get(&room_id, &EventType::RoomMember, &sender.to_string())?
-                .map_or(Ok::<_, Error>(member::MembershipState::Leave), |pdu| {
-                    Ok(
-                        serde_json::from_value::<Raw<member::MemberEventContent>>(pdu.content)
-                            .expect("Raw::from_value always works.")
-                            .deserialize()
-                            .map_err(|_| Error::bad_database("Invalid Member event in db."))?
-                            .membership,
-                    )
-                })?;
-
-            let sender_power = power_levels.users.get(&sender).map_or_else(
-                || {
-                    if sender_membership != member::MembershipState::Join {
-                        None
-                    } else {
-                        Some(&power_levels.users_default)
-                    }
-                },
-                // If it's okay, wrap with Some(_)
-                Some,
-            );
-
-            // Is the event allowed?
-            if !match event_type {
-                EventType::RoomEncryption => {
-                    // Don't allow encryption events when it's disabled
-                    !globals.encryption_disabled()
-                }
-                EventType::RoomMember => {
-                    let target_user_id = UserId::try_from(&**state_key).map_err(|_| {
-                        Error::BadRequest(
-                            ErrorKind::InvalidParam,
-                            "State key of member event does not contain user id.",
-                        )
-                    })?;
-
-                    let current_membership = self
-                        .room_state_get(
-                            &room_id,
-                            &EventType::RoomMember,
-                            &target_user_id.to_string(),
-                        )?
-                        .map_or(Ok::<_, Error>(member::MembershipState::Leave), |pdu| {
-                            Ok(serde_json::from_value::<Raw<member::MemberEventContent>>(
-                                pdu.content,
-                            )
-                            .expect("Raw::from_value always works.")
-                            .deserialize()
-                            .map_err(|_| Error::bad_database("Invalid Member event in db."))?
-                            .membership)
-                        })?;
-
-                    let target_membership =
-                        serde_json::from_value::<Raw<member::MemberEventContent>>(content.clone())
-                            .expect("Raw::from_value always works.")
-                            .deserialize()
-                            .map_err(|_| Error::bad_database("Invalid Member event in db."))?
-                            .membership;
-
-                    let target_power = power_levels.users.get(&target_user_id).map_or_else(
-                        || {
-                            if target_membership != member::MembershipState::Join {
-                                None
-                            } else {
-                                Some(&power_levels.users_default)
-                            }
-                        },
-                        // If it's okay, wrap with Some(_)
-                        Some,
-                    );
-
-                    let join_rules =
-                        self.room_state_get(&room_id, &EventType::RoomJoinRules, "")?
-                            .map_or(Ok::<_, Error>(join_rules::JoinRule::Public), |pdu| {
-                                Ok(serde_json::from_value::<
-                                    Raw<join_rules::JoinRulesEventContent>,
-                                >(pdu.content)
-                                .expect("Raw::from_value always works.")
-                                .deserialize()
-                                .map_err(|_| {
-                                    Error::bad_database("Database contains invalid JoinRules event")
-                                })?
-                                .join_rule)
-                            })?;
-
-                    if target_membership == member::MembershipState::Join {
-                        let mut prev_events = prev_events.iter();
-                        let prev_event = self
-                            .get_pdu(prev_events.next().ok_or(Error::BadRequest(
-                                ErrorKind::Unknown,
-                                "Membership can't be the first event",
-                            ))?)?
-                            .ok_or_else(|| {
-                                Error::bad_database("PDU leaf points to invalid event!")
-                            })?;
-                        if prev_event.kind == EventType::RoomCreate
-                            && prev_event.prev_events.is_empty()
-                        {
-                            true
-                        } else if sender != target_user_id {
-                            false
-                        } else if let member::MembershipState::Ban = current_membership {
-                            false
-                        } else {
-                            join_rules == join_rules::JoinRule::Invite
-                                && (current_membership == member::MembershipState::Join
-                                    || current_membership == member::MembershipState::Invite)
-                                || join_rules == join_rules::JoinRule::Public
-                        }
-                    } else if target_membership == member::MembershipState::Invite {
-                        if let Some(third_party_invite_json) = content.get("third_party_invite") {
-                            if current_membership == member::MembershipState::Ban {
-                                false
-                            } else {
-                                let _third_party_invite =
-                                    serde_json::from_value::<member::ThirdPartyInvite>(
-                                        third_party_invite_json.clone(),
-                                    )
-                                    .map_err(|_| {
-                                        Error::BadRequest(
-                                            ErrorKind::InvalidParam,
-                                            "ThirdPartyInvite is invalid",
-                                        )
-                                    })?;
-                                todo!("handle third party invites");
-                            }
-                        } else if sender_membership != member::MembershipState::Join
-                            || current_membership == member::MembershipState::Join
-                            || current_membership == member::MembershipState::Ban
-                        {
-                            false
-                        } else {
-                            sender_power
-                                .filter(|&p| p >= &power_levels.invite)
-                                .is_some()
-                        }
-                    } else if target_membership == member::MembershipState::Leave {
-                        if sender == target_user_id {
-                            current_membership == member::MembershipState::Join
-                                || current_membership == member::MembershipState::Invite
-                        } else if sender_membership != member::MembershipState::Join
-                            || current_membership == member::MembershipState::Ban
-                                && sender_power.filter(|&p| p < &power_levels.ban).is_some()
-                        {
-                            false
-                        } else {
-                            sender_power.filter(|&p| p >= &power_levels.kick).is_some()
-                                && target_power < sender_power
-                        }
-                    } else if target_membership == member::MembershipState::Ban {
-                        if sender_membership != member::MembershipState::Join {
-                            false
-                        } else {
-                            sender_power.filter(|&p| p >= &power_levels.ban).is_some()
-                                && target_power < sender_power
-                        }
-                    } else {
-                        false
-                    }
-                }
-                EventType::RoomCreate => prev_events.is_empty(),
-                // Not allow any of the following events if the sender is not joined.
-                _ if sender_membership != member::MembershipState::Join => false,
-                _ => {
-                    // TODO
-                    sender_power.unwrap_or(&power_levels.users_default)
-                        >= &power_levels.state_default
-                }
-            } {
-                error!("Unauthorized");
-                // Not authorized
-                return Err(Error::BadRequest(
-                    ErrorKind::Forbidden,
-                    "Event is not authorized",
-                ));
-            }
-        } else if !self.is_joined(&sender, &room_id)? {
-            // TODO: auth rules apply to all events, not only those with a state key
-            error!("Unauthorized");
+        // Don't allow encryption events when it's disabled
+        if event_type == EventType::RoomEncryption && globals.encryption_disabled() {
             return Err(Error::BadRequest(
                 ErrorKind::Forbidden,
-                "Event is not authorized",
+                "Encryption is disabled on this server.",
             ));
         }
 
@@ -509,7 +1008,15 @@ impl Rooms {
             }
         }
 
-        let mut pdu = PduEvent {
+        let auth_events = self.get_auth_events(
+            &room_id,
+            &event_type,
+            &sender,
+            state_key.as_deref(),
+            &content,
+        )?;
+
+        let pdu = PduEvent {
             event_id: EventId::try_from("$thiswillbefilledinlater").expect("we know this is valid"),
             room_id: room_id.clone(),
             sender: sender.clone(),
@@ -517,115 +1024,171 @@ impl Rooms {
             origin_server_ts: utils::millis_since_unix_epoch()
                 .try_into()
                 .expect("time is valid"),
-            kind: event_type.clone(),
-            content: content.clone(),
-            state_key: state_key.clone(),
+            kind: event_type,
+            content,
+            state_key,
             prev_events,
             depth: depth
                 .try_into()
                 .map_err(|_| Error::bad_database("Depth is invalid"))?,
-            auth_events: Vec::new(),
-            redacts: redacts.clone(),
+            auth_events,
+            redacts,
             unsigned,
+            // The content hash is calculated when the event is signed
             hashes: ruma::events::pdu::EventHash {
-                sha256: "aaa".to_owned(),
+                sha256: String::new(),
             },
             signatures: HashMap::new(),
         };
 
-        // Generate event id
-        pdu.event_id = EventId::try_from(&*format!(
-            "${}",
-            ruma::signatures::reference_hash(
-                &serde_json::to_value(&pdu).expect("event is valid, we just created it")
-            )
-            .expect("ruma can calculate reference hashes")
-        ))
-        .expect("ruma's reference hashes are valid event ids");
+        // Is the event authorized?
+        if !self.auth_check(&pdu)? {
+            error!("Unauthorized");
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Event is not authorized",
+            ));
+        }
 
-        let mut pdu_json = serde_json::to_value(&pdu).expect("event is valid, we just created it");
-        ruma::signatures::hash_and_sign_event(
-            globals.server_name().as_str(),
-            globals.keypair(),
-            &mut pdu_json,
-        )
-        .expect("event is valid, we just created it");
+        Ok(pdu)
+    }
 
-        self.replace_pdu_leaves(&room_id, &pdu.event_id)?;
+    /// Creates a new persisted data unit and adds it to a room.
+    pub fn build_and_append_pdu(
+        &self,
+        pdu_builder: PduBuilder,
+        globals: &super::globals::Globals<'_>,
+        account_data: &super::account_data::AccountData,
+        sending: &super::sending::Sending,
+    ) -> Result<EventId> {
+        let mutex = self.room_mutex(&pdu_builder.room_id);
+        let _lock = mutex.lock().unwrap();
+
+        self.build_and_append_pdu_locked(pdu_builder, globals, account_data, sending)
+    }
+
+    /// Like `build_and_append_pdu`, but the caller already holds the room mutex.
+    fn build_and_append_pdu_locked(
+        &self,
+        pdu_builder: PduBuilder,
+        globals: &super::globals::Globals<'_>,
+        account_data: &super::account_data::AccountData,
+        sending: &super::sending::Sending,
+    ) -> Result<EventId> {
+        let mut pdu = self.create_pdu(pdu_builder, globals)?;
+        let pdu_json = self.sign_pdu(&mut pdu, globals)?;
 
         // Increment the last index and use that
         // This is also the next_batch/since value
-        let index = globals.next_count()?;
+        let count = globals.next_count()?;
 
-        let mut pdu_id = room_id.to_string().as_bytes().to_vec();
-        pdu_id.push(0xff);
-        pdu_id.extend_from_slice(&index.to_be_bytes());
+        let pdu_id = self.append_pdu(&pdu, &pdu_json, count, globals, account_data, sending)?;
 
-        self.pduid_pdu.insert(&pdu_id, &*pdu_json.to_string())?;
+        self.send_to_room_servers(&pdu, &pdu_id, globals, sending)?;
 
-        self.eventid_pduid
-            .insert(pdu.event_id.to_string(), pdu_id.clone())?;
+        self.edus
+            .private_read_set(&pdu.room_id, &pdu.sender, count, &globals)?;
 
-        if let Some(state_key) = &pdu.state_key {
-            let mut key = room_id.to_string().as_bytes().to_vec();
-            key.push(0xff);
-            key.extend_from_slice(pdu.kind.to_string().as_bytes());
-            key.push(0xff);
-            key.extend_from_slice(state_key.as_bytes());
-            self.roomstateid_pdu.insert(key, &*pdu_json.to_string())?;
+        Ok(pdu.event_id)
+    }
+
+    /// Fills in the event id, hashes and our signature of a pdu from `create_pdu` and returns
+    /// its json.
+    pub fn sign_pdu(
+        &self,
+        pdu: &mut PduEvent,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<serde_json::Value> {
+        // The create event is the first event, so the room doesn't know its version yet
+        let room_version = if pdu.kind == EventType::RoomCreate {
+            serde_json::from_value::<Raw<create::CreateEventContent>>(pdu.content.clone())
+                .expect("Raw::from_value always works")
+                .deserialize()
+                .ok()
+                .and_then(|content| RoomVersion::new(&content.room_version))
+                .ok_or(Error::BadRequest(
+                    ErrorKind::UnsupportedRoomVersion,
+                    "This server does not support that room version.",
+                ))?
+        } else {
+            self.room_version_rules(&pdu.room_id)?
+        };
+
+        let mut pdu_json = serde_json::to_value(&pdu).expect("event is valid, we just created it");
+
+        pdu.event_id = room_version.hash_and_sign_event(
+            globals.server_name(),
+            globals.keypair(),
+            &mut pdu_json,
+        );
+
+        pdu.hashes = serde_json::from_value(pdu_json["hashes"].clone())
+            .expect("ruma adds valid hashes to the event");
+
+        Ok(pdu_json)
+    }
+
+    /// Queues a pdu for sending to all servers in the room, except for ours and the server that
+    /// created the pdu.
+    pub fn send_to_room_servers(
+        &self,
+        pdu: &PduEvent,
+        pdu_id: &[u8],
+        globals: &super::globals::Globals<'_>,
+        sending: &super::sending::Sending,
+    ) -> Result<()> {
+        let mut servers = self.room_servers(&pdu.room_id)?;
+        if pdu.kind == EventType::RoomMember {
+            // Make sure users that were kicked or banned also get the event
+            if let Some(target_user_id) = pdu
+                .state_key
+                .as_ref()
+                .and_then(|state_key| UserId::try_from(state_key.clone()).ok())
+            {
+                servers.insert(target_user_id.server_name().to_owned());
+            }
         }
 
-        match event_type {
-            EventType::RoomRedaction => {
-                if let Some(redact_id) = &redacts {
-                    self.redact_pdu(&redact_id, &pdu)?;
-                }
+        for server in servers
+            .iter()
+            .filter(|server| &***server != globals.server_name() && &***server != &*pdu.origin)
+        {
+            // Servers denied by the room's ACL don't get our events
+            if !self.server_allowed_by_acl(&pdu.room_id, server)? {
+                continue;
             }
-            EventType::RoomMember => {
-                if let Some(state_key) = state_key {
-                    // if the state_key fails
-                    let target_user_id = UserId::try_from(state_key)
-                        .expect("This state_key was previously validated");
-                    // Update our membership info, we do this here incase a user is invited
-                    // and immediately leaves we need the DB to record the invite event for auth
-                    self.update_membership(
-                        &room_id,
-                        &target_user_id,
-                        serde_json::from_value::<member::MemberEventContent>(content).map_err(
-                            |_| {
-                                Error::BadRequest(
-                                    ErrorKind::InvalidParam,
-                                    "Invalid redaction event content.",
-                                )
-                            },
-                        )?,
-                        &sender,
-                        account_data,
-                        globals,
-                    )?;
-                }
+
+            sending.send_pdu(&server, &pdu_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the auth chain of the given events, which are all events that are referenced
+    /// through `auth_events`, recursively.
+    pub fn auth_chain(&self, event_ids: &[EventId]) -> Result<Vec<serde_json::Value>> {
+        let mut chain = Vec::new();
+        let mut seen = HashSet::new();
+        let mut todo = event_ids
+            .iter()
+            .filter_map(|event_id| Some(self.get_pdu(event_id).ok()??.auth_events))
+            .flatten()
+            .collect::<Vec<_>>();
+
+        while let Some(event_id) = todo.pop() {
+            if !seen.insert(event_id.clone()) {
+                continue;
             }
-            EventType::RoomMessage => {
-                if let Some(body) = content.get("body").and_then(|b| b.as_str()) {
-                    for word in body
-                        .split_terminator(|c: char| !c.is_alphanumeric())
-                        .map(str::to_lowercase)
-                    {
-                        let mut key = room_id.to_string().as_bytes().to_vec();
-                        key.push(0xff);
-                        key.extend_from_slice(word.as_bytes());
-                        key.push(0xff);
-                        key.extend_from_slice(&pdu_id);
-                        self.tokenids.insert(key, &[])?;
-                    }
-                }
+
+            if let Some(pdu_json) = self.get_pdu_json(&event_id)? {
+                let pdu = serde_json::from_value::<PduEvent>(pdu_json.clone())
+                    .map_err(|_| Error::bad_database("Invalid PDU in db."))?;
+                todo.extend(pdu.auth_events);
+                chain.push(pdu_json);
             }
-            _ => {}
         }
-        self.edus
-            .private_read_set(&room_id, &sender, index, &globals)?;
 
-        Ok(pdu.event_id)
+        Ok(chain)
     }
 
     /// Returns an iterator over all PDUs in a room.
@@ -751,7 +1314,7 @@ impl Rooms {
             let mut pdu = self
                 .get_pdu_from_id(&pdu_id)?
                 .ok_or_else(|| Error::bad_database("PDU ID points to invalid PDU."))?;
-            pdu.redact(&reason)?;
+            pdu.redact(&reason, &self.room_version_rules(&pdu.room_id)?)?;
             self.replace_pdu(&pdu_id, &pdu)?;
             Ok(())
         } else {
@@ -771,6 +1334,7 @@ impl Rooms {
         sender: &UserId,
         account_data: &super::account_data::AccountData,
         globals: &super::globals::Globals<'_>,
+        sending: &super::sending::Sending,
     ) -> Result<()> {
         let membership = member_content.membership;
         let mut userroom_id = user_id.to_string().as_bytes().to_vec();
@@ -872,7 +1436,9 @@ impl Rooms {
                 self.roomuserid_joined.insert(&roomuser_id, &[])?;
                 self.userroomid_invited.remove(&userroom_id)?;
                 self.roomuserid_invited.remove(&roomuser_id)?;
+                self.userroomid_invitestate.remove(&userroom_id)?;
                 self.userroomid_left.remove(&userroom_id)?;
+                self.userroomid_remoteleave.remove(&userroom_id)?;
             }
             member::MembershipState::Invite => {
                 // We want to know if the sender is ignored by the receiver
@@ -889,7 +1455,8 @@ impl Rooms {
                 if is_ignored {
                     member_content.membership = member::MembershipState::Leave;
 
-                    self.append_pdu(
+                    // Memberships are updated while the room mutex is held
+                    self.build_and_append_pdu_locked(
                         PduBuilder {
                             room_id: room_id.clone(),
                             sender: user_id.clone(),
@@ -902,6 +1469,7 @@ impl Rooms {
                         },
                         globals,
                         account_data,
+                        sending,
                     )?;
 
                     return Ok(());
@@ -918,6 +1486,8 @@ impl Rooms {
                 self.roomuserid_joined.remove(&roomuser_id)?;
                 self.userroomid_invited.remove(&userroom_id)?;
                 self.roomuserid_invited.remove(&roomuser_id)?;
+                self.userroomid_invitestate.remove(&userroom_id)?;
+                self.userroomid_remoteleave.remove(&userroom_id)?;
             }
             _ => {}
         }
@@ -925,13 +1495,169 @@ impl Rooms {
         Ok(())
     }
 
+    /// Marks a local user as invited to a room on another server. The stripped state is shown
+    /// to the user instead of the room state, which we don't have.
+    pub fn add_remote_invite(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        invite_state: &[Raw<AnyStrippedStateEvent>],
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<()> {
+        let mut userroom_id = user_id.to_string().as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.to_string().as_bytes());
+
+        let mut roomuser_id = room_id.to_string().as_bytes().to_vec();
+        roomuser_id.push(0xff);
+        roomuser_id.extend_from_slice(user_id.to_string().as_bytes());
+
+        let mut value = globals.next_count()?.to_be_bytes().to_vec();
+        value.extend_from_slice(
+            serde_json::to_string(invite_state)
+                .expect("stripped state is valid json")
+                .as_bytes(),
+        );
+
+        self.userroomid_invitestate.insert(&userroom_id, value)?;
+        self.userroomid_invited.insert(&userroom_id, &[])?;
+        self.roomuserid_invited.insert(&roomuser_id, &[])?;
+        self.userroomid_joined.remove(&userroom_id)?;
+        self.roomuserid_joined.remove(&roomuser_id)?;
+        self.userroomid_left.remove(&userroom_id)?;
+        self.userroomid_remoteleave.remove(&userroom_id)?;
+
+        Ok(())
+    }
+
+    /// Marks a local user as having left a room we are not part of, e.g. after rejecting an
+    /// invite. The leave event is stored as an outlier, so it can be shown to the user.
+    pub fn add_remote_leave(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        leave_event_json: &serde_json::Value,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<()> {
+        let event_id = leave_event_json
+            .get("event_id")
+            .and_then(|event_id| event_id.as_str())
+            .ok_or_else(|| Error::BadServerResponse("Leave event has no event id."))?;
+
+        self.add_pdu_outlier(leave_event_json)?;
+
+        let mut userroom_id = user_id.to_string().as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.to_string().as_bytes());
+
+        let mut roomuser_id = room_id.to_string().as_bytes().to_vec();
+        roomuser_id.push(0xff);
+        roomuser_id.extend_from_slice(user_id.to_string().as_bytes());
+
+        let mut value = globals.next_count()?.to_be_bytes().to_vec();
+        value.extend_from_slice(event_id.as_bytes());
+
+        self.userroomid_remoteleave.insert(&userroom_id, value)?;
+        self.userroomid_left.insert(&userroom_id, &[])?;
+        self.userroomid_invited.remove(&userroom_id)?;
+        self.roomuserid_invited.remove(&roomuser_id)?;
+        self.userroomid_invitestate.remove(&userroom_id)?;
+        self.userroomid_joined.remove(&userroom_id)?;
+        self.roomuserid_joined.remove(&roomuser_id)?;
+
+        Ok(())
+    }
+
+    /// Returns the leave event of a user in a room we are not part of and the count at which
+    /// the user left.
+    pub fn remote_leave(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Option<(u64, PduEvent)>> {
+        let mut userroom_id = user_id.to_string().as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.to_string().as_bytes());
+
+        self.userroomid_remoteleave
+            .get(userroom_id)?
+            .map_or(Ok(None), |value| {
+                if value.len() < mem::size_of::<u64>() {
+                    return Err(Error::bad_database("Invalid remote leave in db."));
+                }
+                let (count, event_id) = value.split_at(mem::size_of::<u64>());
+
+                let event_id = EventId::try_from(
+                    utils::string_from_bytes(event_id)
+                        .map_err(|_| Error::bad_database("Invalid remote leave in db."))?,
+                )
+                .map_err(|_| Error::bad_database("Invalid remote leave in db."))?;
+
+                Ok(Some((
+                    utils::u64_from_bytes(count)
+                        .map_err(|_| Error::bad_database("Invalid remote leave in db."))?,
+                    self.get_pdu(&event_id)?
+                        .ok_or_else(|| Error::bad_database("Remote leave event is missing."))?,
+                )))
+            })
+    }
+
+    /// Returns the stripped state of an invite from another server and the count at which the
+    /// user was invited.
+    pub fn invite_state(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Option<(u64, Vec<Raw<AnyStrippedStateEvent>>)>> {
+        let mut userroom_id = user_id.to_string().as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.to_string().as_bytes());
+
+        self.userroomid_invitestate
+            .get(userroom_id)?
+            .map_or(Ok(None), |value| {
+                if value.len() < mem::size_of::<u64>() {
+                    return Err(Error::bad_database("Invalid invite state in db."));
+                }
+                let (count, state) = value.split_at(mem::size_of::<u64>());
+
+                Ok(Some((
+                    utils::u64_from_bytes(count)
+                        .map_err(|_| Error::bad_database("Invalid invite state in db."))?,
+                    serde_json::from_slice(state)
+                        .map_err(|_| Error::bad_database("Invalid invite state in db."))?,
+                )))
+            })
+    }
+
+    /// Returns the state events that are shown to users who are invited to a room, so they can
+    /// decide whether to join.
+    pub fn stripped_state(&self, room_id: &RoomId) -> Result<Vec<Raw<AnyStrippedStateEvent>>> {
+        let mut state = Vec::new();
+        for event_type in &[
+            EventType::RoomCreate,
+            EventType::RoomJoinRules,
+            EventType::RoomCanonicalAlias,
+            EventType::RoomAvatar,
+            EventType::RoomName,
+            EventType::RoomEncryption,
+        ] {
+            if let Some(pdu) = self.room_state_get(room_id, event_type, "")? {
+                state.push(pdu.to_stripped_state_event());
+            }
+        }
+
+        Ok(state)
+    }
+
     /// Makes a user forget a room.
     pub fn forget(&self, room_id: &RoomId, user_id: &UserId) -> Result<()> {
         let mut userroom_id = user_id.to_string().as_bytes().to_vec();
         userroom_id.push(0xff);
         userroom_id.extend_from_slice(room_id.to_string().as_bytes());
 
-        self.userroomid_left.remove(userroom_id)?;
+        self.userroomid_left.remove(&userroom_id)?;
+        self.userroomid_remoteleave.remove(&userroom_id)?;
 
         Ok(())
     }
@@ -1128,6 +1854,47 @@ impl Rooms {
             })
     }
 
+    /// Returns the servers of all joined members of a room.
+    pub fn room_servers(&self, room_id: &RoomId) -> Result<HashSet<Box<ServerName>>> {
+        let mut servers = HashSet::new();
+        for user_id in self.room_members(room_id) {
+            servers.insert(user_id?.server_name().to_owned());
+        }
+
+        Ok(servers)
+    }
+
+    /// Checks if the `m.room.server_acl` event of a room allows a server to participate.
+    pub fn server_allowed_by_acl(&self, room_id: &RoomId, server: &ServerName) -> Result<bool> {
+        let acl = match self.room_state_get(room_id, &EventType::RoomServerAcl, "")? {
+            Some(acl_event) => {
+                serde_json::from_value::<server_acl::ServerAclEventContent>(acl_event.content)
+                    .map_err(|_| Error::bad_database("Invalid server ACL event in db."))?
+            }
+            None => return Ok(true),
+        };
+
+        // ACLs don't include the port and server names are case-insensitive
+        let hostname = utils::split_host_port(server.as_str()).0.to_lowercase();
+
+        if !acl.allow_ip_literals && utils::is_ip_literal(&hostname) {
+            return Ok(false);
+        }
+
+        if acl
+            .deny
+            .iter()
+            .any(|glob| utils::glob_matches(&glob.to_lowercase(), &hostname))
+        {
+            return Ok(false);
+        }
+
+        Ok(acl
+            .allow
+            .iter()
+            .any(|glob| utils::glob_matches(&glob.to_lowercase(), &hostname)))
+    }
+
     /// Returns an iterator over all User IDs who ever joined a room.
     pub fn room_useroncejoined(&self, room_id: &RoomId) -> impl Iterator<Item = Result<UserId>> {
         self.roomuseroncejoinedids
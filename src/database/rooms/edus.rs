@@ -8,11 +8,13 @@ use ruma::{
     presence::PresenceState,
     Raw, RoomId, UserId,
 };
+use serde_json::json;
 use std::{
     collections::HashMap,
     convert::{TryFrom, TryInto},
 };
 
+#[derive(Clone)]
 pub struct RoomEdus {
     pub(in super::super) readreceiptid_readreceipt: sled::Tree, // ReadReceiptId = RoomId + Count + UserId
     pub(in super::super) roomuserid_privateread: sled::Tree, // RoomUserId = Room + User, PrivateRead = Count
@@ -25,12 +27,16 @@ pub struct RoomEdus {
 
 impl RoomEdus {
     /// Adds an event which will be saved until a new event replaces it (e.g. read receipt).
+    ///
+    /// Receipts of our users are sent to the other servers in the room.
     pub fn readreceipt_update(
         &self,
         user_id: &UserId,
         room_id: &RoomId,
         event: EduEvent,
+        rooms: &super::Rooms,
         globals: &super::super::globals::Globals<'_>,
+        sending: &super::super::sending::Sending,
     ) -> Result<()> {
         let mut prefix = room_id.to_string().as_bytes().to_vec();
         prefix.push(0xff);
@@ -59,10 +65,49 @@ impl RoomEdus {
         room_latest_id.push(0xff);
         room_latest_id.extend_from_slice(&user_id.to_string().as_bytes());
 
-        self.readreceiptid_readreceipt.insert(
-            room_latest_id,
-            &*serde_json::to_string(&event).expect("EduEvent::to_string always works"),
-        )?;
+        let event_json = serde_json::to_value(&event).expect("EduEvent::to_value always works");
+
+        self.readreceiptid_readreceipt
+            .insert(room_latest_id, &*event_json.to_string())?;
+
+        if user_id.server_name() == globals.server_name() {
+            // Federation receipts list the event ids per user instead of the users per event
+            let mut event_ids = Vec::new();
+            let mut data = serde_json::Value::Null;
+            if let Some(content) = event_json.get("content").and_then(|c| c.as_object()) {
+                for (event_id, receipts) in content {
+                    if let Some(receipt) = receipts
+                        .get("m.read")
+                        .and_then(|read| read.get(user_id.as_str()))
+                    {
+                        event_ids.push(event_id.clone());
+                        data = receipt.clone();
+                    }
+                }
+            }
+
+            if !event_ids.is_empty() {
+                self.send_edu_to_room_servers(
+                    room_id,
+                    &json!({
+                        "edu_type": "m.receipt",
+                        "content": {
+                            room_id.as_str(): {
+                                "m.read": {
+                                    user_id.as_str(): {
+                                        "event_ids": event_ids,
+                                        "data": data,
+                                    }
+                                }
+                            }
+                        }
+                    }),
+                    rooms,
+                    globals,
+                    sending,
+                )?;
+            }
+        }
 
         Ok(())
     }
@@ -144,12 +189,16 @@ impl RoomEdus {
 
     /// Sets a user as typing until the timeout timestamp is reached or roomtyping_remove is
     /// called.
+    ///
+    /// Our users' typing notifications are sent to the other servers in the room.
     pub fn typing_add(
         &self,
         user_id: &UserId,
         room_id: &RoomId,
         timeout: u64,
+        rooms: &super::Rooms,
         globals: &super::super::globals::Globals<'_>,
+        sending: &super::super::sending::Sending,
     ) -> Result<()> {
         let mut prefix = room_id.to_string().as_bytes().to_vec();
         prefix.push(0xff);
@@ -167,6 +216,8 @@ impl RoomEdus {
         self.roomid_lasttypingupdate
             .insert(&room_id.to_string().as_bytes(), &count)?;
 
+        self.send_typing_edu(user_id, room_id, true, rooms, globals, sending)?;
+
         Ok(())
     }
 
@@ -175,13 +226,13 @@ impl RoomEdus {
         &self,
         user_id: &UserId,
         room_id: &RoomId,
+        rooms: &super::Rooms,
         globals: &super::super::globals::Globals<'_>,
+        sending: &super::super::sending::Sending,
     ) -> Result<()> {
         let mut prefix = room_id.to_string().as_bytes().to_vec();
         prefix.push(0xff);
 
-        let user_id = user_id.to_string();
-
         let mut found_outdated = false;
 
         // Maybe there are multiple ones from calling roomtyping_add multiple times
@@ -189,7 +240,7 @@ impl RoomEdus {
             .typingid_userid
             .scan_prefix(&prefix)
             .filter_map(|r| r.ok())
-            .filter(|(_, v)| v == user_id.as_bytes())
+            .filter(|(_, v)| v == user_id.as_str().as_bytes())
         {
             self.typingid_userid.remove(outdated_edu.0)?;
             found_outdated = true;
@@ -202,9 +253,40 @@ impl RoomEdus {
             )?;
         }
 
+        self.send_typing_edu(user_id, room_id, false, rooms, globals, sending)?;
+
         Ok(())
     }
 
+    fn send_typing_edu(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        typing: bool,
+        rooms: &super::Rooms,
+        globals: &super::super::globals::Globals<'_>,
+        sending: &super::super::sending::Sending,
+    ) -> Result<()> {
+        if user_id.server_name() != globals.server_name() {
+            return Ok(());
+        }
+
+        self.send_edu_to_room_servers(
+            room_id,
+            &json!({
+                "edu_type": "m.typing",
+                "content": {
+                    "room_id": room_id,
+                    "user_id": user_id,
+                    "typing": typing,
+                }
+            }),
+            rooms,
+            globals,
+            sending,
+        )
+    }
+
     /// Makes sure that typing events with old timestamps get removed.
     fn typings_maintain(
         &self,
@@ -304,12 +386,16 @@ impl RoomEdus {
     ///
     /// Note: This method takes a RoomId because presence updates are always bound to rooms to
     /// make sure users outside these rooms can't see them.
+    ///
+    /// Presence updates of our users are sent to the other servers in the room.
     pub fn update_presence(
         &self,
         user_id: &UserId,
         room_id: &RoomId,
         presence: ruma::events::presence::PresenceEvent,
+        rooms: &super::Rooms,
         globals: &super::super::globals::Globals<'_>,
+        sending: &super::super::sending::Sending,
     ) -> Result<()> {
         // TODO: Remove old entry? Or maybe just wipe completely from time to time?
 
@@ -331,6 +417,57 @@ impl RoomEdus {
             &utils::millis_since_unix_epoch().to_be_bytes(),
         )?;
 
+        if user_id.server_name() == globals.server_name() {
+            // We store when the user was last active, other servers get the duration since then
+            let last_active_ago = presence
+                .content
+                .last_active_ago
+                .map(|timestamp| utils::millis_since_unix_epoch().saturating_sub(timestamp.into()));
+
+            self.send_edu_to_room_servers(
+                room_id,
+                &json!({
+                    "edu_type": "m.presence",
+                    "content": {
+                        "push": [{
+                            "user_id": user_id,
+                            "presence": presence.content.presence,
+                            "last_active_ago": last_active_ago,
+                            "status_msg": presence.content.status_msg,
+                            "currently_active": presence.content.currently_active,
+                        }]
+                    }
+                }),
+                rooms,
+                globals,
+                sending,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Queues an edu for sending to all servers in the room, except for ours.
+    fn send_edu_to_room_servers(
+        &self,
+        room_id: &RoomId,
+        edu: &serde_json::Value,
+        rooms: &super::Rooms,
+        globals: &super::super::globals::Globals<'_>,
+        sending: &super::super::sending::Sending,
+    ) -> Result<()> {
+        for server in rooms
+            .room_servers(room_id)?
+            .iter()
+            .filter(|server| &***server != globals.server_name())
+        {
+            if !rooms.server_allowed_by_acl(room_id, server)? {
+                continue;
+            }
+
+            sending.send_edu(&server, edu, globals)?;
+        }
+
         Ok(())
     }
 
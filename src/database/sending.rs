@@ -0,0 +1,396 @@
+use crate::{server_server, utils, Error, PduEvent, Result};
+use log::warn;
+use rocket::futures::stream::{FuturesUnordered, StreamExt};
+use ruma::{
+    api::federation::transactions::send_transaction_message, events::pdu::PduStub, Raw, RoomId,
+    ServerName,
+};
+use serde::{Deserialize, Serialize};
+use sled::IVec;
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// The maximum number of pdus the spec allows in one transaction.
+const MAX_PDUS_PER_TRANSACTION: usize = 50;
+/// The maximum number of edus the spec allows in one transaction.
+const MAX_EDUS_PER_TRANSACTION: usize = 100;
+
+#[derive(Clone)]
+pub struct Sending {
+    pub(super) servernamepduids: sled::Tree, // ServernamePduId = ServerName + PduId
+    pub(super) servernameeduids: sled::Tree, // ServernameEduId = ServerName + Count, Value = Edu json
+    pub(super) servername_retrystate: sled::Tree, // Value = RetryState json
+}
+
+/// The events that are sent to a server in one transaction.
+struct Transaction {
+    /// Retries of the transaction keep this id, so the destination can deduplicate them.
+    id: String,
+    pdu_keys: Vec<IVec>,
+    edu_keys: Vec<IVec>,
+    pdus: Vec<Raw<PduStub>>,
+    edus: Vec<IVec>,
+}
+
+/// A transaction that a server failed to accept. It is stored, so the backoff and the retried
+/// transaction survive restarts.
+#[derive(Deserialize, Serialize)]
+struct RetryState {
+    tries: u32,
+    /// Milliseconds since the unix epoch after which the transaction is sent again.
+    retry_at: u64,
+    transaction_id: String,
+    pdu_keys: Vec<Vec<u8>>,
+    edu_keys: Vec<Vec<u8>>,
+}
+
+impl Sending {
+    /// Starts a background task that sends all queued events to the other servers.
+    ///
+    /// Events stay queued until the destination accepted the transaction, so they are retried
+    /// after restarts. Servers that fail are retried with exponential backoff.
+    pub fn start_handler(
+        &self,
+        globals: &super::globals::Globals<'static>,
+        rooms: &super::rooms::Rooms,
+    ) {
+        let sending = self.clone();
+        let globals = globals.clone();
+        let rooms = rooms.clone();
+
+        tokio::spawn(async move {
+            let mut futures = FuturesUnordered::new();
+
+            // Subscribing first makes sure we don't miss events queued during the scan below
+            let mut pdu_subscriber = sending.servernamepduids.watch_prefix(b"");
+            let mut edu_subscriber = sending.servernameeduids.watch_prefix(b"");
+
+            // Servers that have queued events. The queues are only scanned once, afterwards we
+            // learn about new events from the subscribers
+            let mut pending_servers = sending.queued_servers();
+            // Servers that currently have a transaction in flight
+            let mut current_servers = HashSet::new();
+
+            loop {
+                // The earliest time a server we backed off from can be tried again
+                let mut next_retry: Option<Instant> = None;
+
+                let ready_servers = pending_servers
+                    .iter()
+                    .filter(|server| !current_servers.contains(*server))
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                for server in ready_servers {
+                    let transaction = match sending.retry_state(&server) {
+                        Ok(Some(retry_state)) => {
+                            let now = utils::millis_since_unix_epoch();
+                            if now < retry_state.retry_at {
+                                let retry_at = Instant::now()
+                                    + Duration::from_millis(retry_state.retry_at - now);
+                                next_retry =
+                                    Some(next_retry.map_or(retry_at, |next| next.min(retry_at)));
+                                continue;
+                            }
+
+                            // Failed transactions are retried unchanged
+                            sending
+                                .load_transaction(
+                                    &server,
+                                    retry_state.transaction_id,
+                                    retry_state.pdu_keys.into_iter().map(IVec::from).collect(),
+                                    retry_state.edu_keys.into_iter().map(IVec::from).collect(),
+                                    &rooms,
+                                )
+                                .map(Some)
+                        }
+                        Ok(None) => sending.next_transaction(&server, &rooms, &globals),
+                        Err(e) => Err(e),
+                    };
+
+                    let transaction = match transaction {
+                        Ok(Some(transaction)) => transaction,
+                        Ok(None) => {
+                            pending_servers.remove(&server);
+                            continue;
+                        }
+                        Err(e) => {
+                            warn!("Failed to prepare transaction for {}: {}", server, e);
+                            continue;
+                        }
+                    };
+
+                    current_servers.insert(server.clone());
+                    futures.push(Self::handle_transaction(server, transaction, &globals));
+                }
+
+                tokio::select! {
+                    Some(result) = futures.next() => {
+                        match result {
+                            Ok((server, transaction)) => {
+                                for key in transaction.pdu_keys {
+                                    if let Err(e) = sending.servernamepduids.remove(key) {
+                                        warn!("Failed to remove sent pdu from queue: {}", e);
+                                    }
+                                }
+                                for key in transaction.edu_keys {
+                                    if let Err(e) = sending.servernameeduids.remove(key) {
+                                        warn!("Failed to remove sent edu from queue: {}", e);
+                                    }
+                                }
+                                if let Err(e) = sending
+                                    .servername_retrystate
+                                    .remove(server.as_str().as_bytes())
+                                {
+                                    warn!("Failed to remove retry state of {}: {}", server, e);
+                                }
+
+                                // The server stays pending until it has no queued events left
+                                current_servers.remove(&server);
+                            }
+                            Err((server, transaction, e)) => {
+                                warn!("Failed to send transaction to {}: {}", server, e);
+
+                                if let Err(e) = sending.add_failed_try(&server, transaction) {
+                                    warn!("Failed to store retry state of {}: {}", server, e);
+                                }
+                                current_servers.remove(&server);
+                            }
+                        }
+                    }
+                    Some(event) = &mut pdu_subscriber => {
+                        if let sled::Event::Insert(key, _) = event {
+                            pending_servers.extend(Self::queued_server(&key));
+                        }
+                    }
+                    Some(event) = &mut edu_subscriber => {
+                        if let sled::Event::Insert(key, _) = event {
+                            pending_servers.extend(Self::queued_server(&key));
+                        }
+                    }
+                    _ = tokio::time::delay_until(tokio::time::Instant::from_std(
+                        next_retry.unwrap_or_else(Instant::now),
+                    )), if next_retry.is_some() => {}
+                }
+            }
+        });
+    }
+
+    /// Returns the stored retry state of a server that failed to accept a transaction.
+    fn retry_state(&self, server: &ServerName) -> Result<Option<RetryState>> {
+        self.servername_retrystate
+            .get(server.as_str().as_bytes())?
+            .map_or(Ok(None), |bytes| {
+                serde_json::from_slice(&bytes)
+                    .map(Some)
+                    .map_err(|_| Error::bad_database("Invalid retry state in db."))
+            })
+    }
+
+    /// Stores that a server failed to accept a transaction and when it is sent again.
+    fn add_failed_try(&self, server: &ServerName, transaction: Transaction) -> Result<()> {
+        let tries = self.retry_state(server)?.map_or(0, |state| state.tries) + 1;
+
+        // Exponential backoff, capped at one day
+        let backoff = Duration::from_secs(30)
+            .checked_mul(2_u32.saturating_pow(tries))
+            .map_or(Duration::from_secs(60 * 60 * 24), |d| {
+                d.min(Duration::from_secs(60 * 60 * 24))
+            });
+
+        let retry_state = RetryState {
+            tries,
+            retry_at: utils::millis_since_unix_epoch() + backoff.as_millis() as u64,
+            transaction_id: transaction.id,
+            pdu_keys: transaction
+                .pdu_keys
+                .iter()
+                .map(|key| key.to_vec())
+                .collect(),
+            edu_keys: transaction
+                .edu_keys
+                .iter()
+                .map(|key| key.to_vec())
+                .collect(),
+        };
+
+        self.servername_retrystate.insert(
+            server.as_str().as_bytes(),
+            &*serde_json::to_string(&retry_state).expect("RetryState::to_string always works"),
+        )?;
+
+        Ok(())
+    }
+
+    /// Queues a pdu for sending to a server.
+    pub fn send_pdu(&self, server: &ServerName, pdu_id: &[u8]) -> Result<()> {
+        let mut key = server.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(pdu_id);
+        self.servernamepduids.insert(key, &[])?;
+
+        Ok(())
+    }
+
+    /// Queues an edu for sending to a server.
+    pub fn send_edu<T: Serialize>(
+        &self,
+        server: &ServerName,
+        edu: &T,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<()> {
+        let mut key = server.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&globals.next_count()?.to_be_bytes());
+        self.servernameeduids.insert(
+            key,
+            &*serde_json::to_string(edu).expect("Edu::to_string always works"),
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns all servers that have queued events.
+    fn queued_servers(&self) -> HashSet<Box<ServerName>> {
+        self.servernamepduids
+            .iter()
+            .keys()
+            .chain(self.servernameeduids.iter().keys())
+            .filter_map(|r| r.ok())
+            .filter_map(|key| Self::queued_server(&key))
+            .collect()
+    }
+
+    /// Returns the server of a key in the queue trees.
+    fn queued_server(key: &[u8]) -> Option<Box<ServerName>> {
+        let server = key.split(|&b| b == 0xff).next()?;
+        Box::<ServerName>::try_from(utils::string_from_bytes(server).ok()?).ok()
+    }
+
+    /// Collects the oldest queued events of a server into a transaction.
+    fn next_transaction(
+        &self,
+        server: &ServerName,
+        rooms: &super::rooms::Rooms,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<Option<Transaction>> {
+        let mut prefix = server.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let pdu_keys = self
+            .servernamepduids
+            .scan_prefix(&prefix)
+            .keys()
+            .take(MAX_PDUS_PER_TRANSACTION)
+            .collect::<sled::Result<Vec<_>>>()?;
+        let edu_keys = self
+            .servernameeduids
+            .scan_prefix(&prefix)
+            .keys()
+            .take(MAX_EDUS_PER_TRANSACTION)
+            .collect::<sled::Result<Vec<_>>>()?;
+
+        if pdu_keys.is_empty() && edu_keys.is_empty() {
+            return Ok(None);
+        }
+
+        self.load_transaction(
+            server,
+            globals.next_count()?.to_string(),
+            pdu_keys,
+            edu_keys,
+            rooms,
+        )
+        .map(Some)
+    }
+
+    /// Loads the queued events with the given keys into a transaction.
+    fn load_transaction(
+        &self,
+        server: &ServerName,
+        id: String,
+        pdu_keys: Vec<IVec>,
+        edu_keys: Vec<IVec>,
+        rooms: &super::rooms::Rooms,
+    ) -> Result<Transaction> {
+        let prefix_len = server.as_str().len() + 1;
+
+        let mut pdus = Vec::new();
+        for key in &pdu_keys {
+            let pdu_id = key.subslice(prefix_len, key.len() - prefix_len);
+
+            // The pdu might not exist anymore, we still remove it from the queue
+            if let Some(pdu_json) = rooms.get_pdu_json_from_id(&pdu_id)? {
+                let room_id = pdu_json
+                    .get("room_id")
+                    .and_then(|room_id| room_id.as_str())
+                    .and_then(|room_id| RoomId::try_from(room_id).ok())
+                    .ok_or_else(|| Error::bad_database("Pdu in db has an invalid room id."))?;
+                let room_version = rooms.room_version_rules(&room_id)?;
+
+                pdus.push(PduEvent::convert_to_outgoing_federation_event(
+                    pdu_json,
+                    &room_version,
+                ));
+            }
+        }
+
+        let mut edus = Vec::new();
+        for key in &edu_keys {
+            edus.extend(self.servernameeduids.get(key)?);
+        }
+
+        Ok(Transaction {
+            id,
+            pdu_keys,
+            edu_keys,
+            pdus,
+            edus,
+        })
+    }
+
+    async fn handle_transaction(
+        server: Box<ServerName>,
+        transaction: Transaction,
+        globals: &super::globals::Globals<'_>,
+    ) -> std::result::Result<(Box<ServerName>, Transaction), (Box<ServerName>, Transaction, Error)>
+    {
+        // Events for servers we no longer federate with are dropped from the queue
+        if !globals.federation_allowed(&server) {
+            return Ok((server, transaction));
+        }
+
+        let response = server_server::send_request(
+            globals,
+            server.to_string(),
+            send_transaction_message::v1::Request {
+                origin: globals.server_name().to_owned(),
+                pdus: transaction.pdus.clone(),
+                edus: transaction
+                    .edus
+                    .iter()
+                    .filter_map(|edu| serde_json::from_slice(edu).ok())
+                    .collect(),
+                origin_server_ts: SystemTime::now(),
+                transaction_id: transaction.id.clone(),
+            },
+        )
+        .await;
+
+        match response {
+            Ok(response) => {
+                for (event_id, result) in response.pdus {
+                    if let Err(e) = result {
+                        warn!("{} rejected our pdu {}: {}", server, event_id, e);
+                    }
+                }
+
+                Ok((server, transaction))
+            }
+            Err(e) => Err((server, transaction, e)),
+        }
+    }
+}
@@ -12,7 +12,13 @@ use ruma::{
     events::{AnyToDeviceEvent, EventType},
     DeviceId, DeviceKeyAlgorithm, DeviceKeyId, Raw, UserId,
 };
-use std::{collections::BTreeMap, convert::TryFrom, mem, time::SystemTime};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashSet},
+    convert::TryFrom,
+    mem,
+    time::SystemTime,
+};
 
 pub struct Users {
     pub(super) userid_password: sled::Tree,
@@ -25,6 +31,7 @@ pub struct Users {
     pub(super) onetimekeyid_onetimekeys: sled::Tree, // OneTimeKeyId = UserId + DeviceKeyId
     pub(super) userid_lastonetimekeyupdate: sled::Tree, // LastOneTimeKeyUpdate = Count
     pub(super) keychangeid_userid: sled::Tree,       // KeyChangeId = UserId/RoomId + Count
+    pub(super) userid_devicelistversion: sled::Tree, // DeviceListVersion = Count
     pub(super) keyid_key: sled::Tree,                // KeyId = UserId + KeyId (depends on key type)
     pub(super) userid_masterkeyid: sled::Tree,
     pub(super) userid_selfsigningkeyid: sled::Tree,
@@ -196,7 +203,14 @@ impl Users {
     }
 
     /// Removes a device from a user.
-    pub fn remove_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+    pub fn remove_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        rooms: &super::rooms::Rooms,
+        globals: &super::globals::Globals<'_>,
+        sending: &super::sending::Sending,
+    ) -> Result<()> {
         let mut userdeviceid = user_id.to_string().as_bytes().to_vec();
         userdeviceid.push(0xff);
         userdeviceid.extend_from_slice(device_id.as_bytes());
@@ -218,6 +232,8 @@ impl Users {
 
         self.userdeviceid_metadata.remove(&userdeviceid)?;
 
+        self.mark_device_list_update(user_id, Some(device_id), rooms, globals, sending)?;
+
         Ok(())
     }
 
@@ -395,6 +411,7 @@ impl Users {
         device_keys: &DeviceKeys,
         rooms: &super::rooms::Rooms,
         globals: &super::globals::Globals<'_>,
+        sending: &super::sending::Sending,
     ) -> Result<()> {
         let mut userdeviceid = user_id.to_string().as_bytes().to_vec();
         userdeviceid.push(0xff);
@@ -405,7 +422,7 @@ impl Users {
             &*serde_json::to_string(&device_keys).expect("DeviceKeys::to_string always works"),
         )?;
 
-        self.mark_device_key_update(user_id, rooms, globals)?;
+        self.mark_device_key_update(user_id, rooms, globals, sending)?;
 
         Ok(())
     }
@@ -418,6 +435,7 @@ impl Users {
         user_signing_key: &Option<CrossSigningKey>,
         rooms: &super::rooms::Rooms,
         globals: &super::globals::Globals<'_>,
+        sending: &super::sending::Sending,
     ) -> Result<()> {
         // TODO: Check signatures
 
@@ -505,7 +523,7 @@ impl Users {
                 .insert(&*user_id.to_string(), user_signing_key_key)?;
         }
 
-        self.mark_device_key_update(user_id, rooms, globals)?;
+        self.mark_device_key_update(user_id, rooms, globals, sending)?;
 
         Ok(())
     }
@@ -518,6 +536,7 @@ impl Users {
         sender_id: &UserId,
         rooms: &super::rooms::Rooms,
         globals: &super::globals::Globals<'_>,
+        sending: &super::sending::Sending,
     ) -> Result<()> {
         let mut key = target_id.to_string().as_bytes().to_vec();
         key.push(0xff);
@@ -549,7 +568,7 @@ impl Users {
         )?;
 
         // TODO: Should we notify about this change?
-        self.mark_device_key_update(target_id, rooms, globals)?;
+        self.mark_device_key_update(target_id, rooms, globals, sending)?;
 
         Ok(())
     }
@@ -587,37 +606,127 @@ impl Users {
             })
     }
 
-    fn mark_device_key_update(
+    /// Lets the user and everyone sharing an encrypted room with them know that their keys
+    /// changed. Changes of our users are also sent to the other servers in these rooms.
+    pub fn mark_device_key_update(
         &self,
         user_id: &UserId,
         rooms: &super::rooms::Rooms,
         globals: &super::globals::Globals<'_>,
+        sending: &super::sending::Sending,
     ) -> Result<()> {
-        let count = globals.next_count()?.to_be_bytes();
+        self.mark_device_list_update(user_id, None, rooms, globals, sending)
+    }
+
+    /// Like `mark_device_key_update`, but if `deleted_device` is set, other servers are only told
+    /// that this device was deleted.
+    fn mark_device_list_update(
+        &self,
+        user_id: &UserId,
+        deleted_device: Option<&DeviceId>,
+        rooms: &super::rooms::Rooms,
+        globals: &super::globals::Globals<'_>,
+        sending: &super::sending::Sending,
+    ) -> Result<()> {
+        let count = globals.next_count()?;
+        let mut servers = HashSet::new();
         for room_id in rooms.rooms_joined(&user_id).filter_map(|r| r.ok()) {
             // Don't send key updates to unencrypted rooms
             if rooms
                 .room_state_get(&room_id, &EventType::RoomEncryption, "")?
                 .is_none()
             {
-                return Ok(());
+                continue;
             }
 
             let mut key = room_id.to_string().as_bytes().to_vec();
             key.push(0xff);
-            key.extend_from_slice(&count);
+            key.extend_from_slice(&count.to_be_bytes());
 
             self.keychangeid_userid.insert(key, &*user_id.to_string())?;
+
+            for server in rooms.room_servers(&room_id)? {
+                if rooms.server_allowed_by_acl(&room_id, &server)? {
+                    servers.insert(server);
+                }
+            }
         }
 
         let mut key = user_id.to_string().as_bytes().to_vec();
         key.push(0xff);
-        key.extend_from_slice(&count);
+        key.extend_from_slice(&count.to_be_bytes());
         self.keychangeid_userid.insert(key, &*user_id.to_string())?;
 
+        if user_id.server_name() == globals.server_name() {
+            servers.remove(globals.server_name());
+
+            let device_ids: Vec<Box<DeviceId>> = match deleted_device {
+                Some(device_id) => vec![device_id.into()],
+                None => self.all_device_ids(user_id).collect::<Result<Vec<_>>>()?,
+            };
+
+            for device_id in device_ids {
+                // Every update gets its own stream id, so other servers can notice missed updates
+                let stream_id = globals.next_count()?;
+                let prev_id = self
+                    .userid_devicelistversion
+                    .insert(user_id.to_string(), &stream_id.to_be_bytes())?
+                    .map(|bytes| {
+                        utils::u64_from_bytes(&bytes).map_err(|_| {
+                            Error::bad_database("Count in userid_devicelistversion is invalid.")
+                        })
+                    })
+                    .transpose()?;
+
+                let edu = if deleted_device.is_some() {
+                    json!({
+                        "edu_type": "m.device_list_update",
+                        "content": {
+                            "user_id": user_id,
+                            "device_id": device_id,
+                            "stream_id": stream_id,
+                            "prev_id": prev_id.into_iter().collect::<Vec<_>>(),
+                            "deleted": true,
+                        }
+                    })
+                } else {
+                    json!({
+                        "edu_type": "m.device_list_update",
+                        "content": {
+                            "user_id": user_id,
+                            "device_id": device_id,
+                            "device_display_name": self
+                                .get_device_metadata(user_id, &device_id)?
+                                .and_then(|device| device.display_name),
+                            "stream_id": stream_id,
+                            "prev_id": prev_id.into_iter().collect::<Vec<_>>(),
+                            "deleted": false,
+                            "keys": self.get_device_keys(user_id, &device_id)?,
+                        }
+                    })
+                };
+
+                for server in &servers {
+                    sending.send_edu(server, &edu, globals)?;
+                }
+            }
+        }
+
         Ok(())
     }
 
+    /// Returns the stream id of the last device list update of this user that was sent to other
+    /// servers.
+    pub fn device_list_version(&self, user_id: &UserId) -> Result<u64> {
+        self.userid_devicelistversion
+            .get(user_id.to_string())?
+            .map_or(Ok(0), |bytes| {
+                utils::u64_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Count in userid_devicelistversion is invalid.")
+                })
+            })
+    }
+
     pub fn get_device_keys(
         &self,
         user_id: &UserId,
@@ -843,10 +952,16 @@ impl Users {
     }
 
     /// Deactivate account
-    pub fn deactivate_account(&self, user_id: &UserId) -> Result<()> {
+    pub fn deactivate_account(
+        &self,
+        user_id: &UserId,
+        rooms: &super::rooms::Rooms,
+        globals: &super::globals::Globals<'_>,
+        sending: &super::sending::Sending,
+    ) -> Result<()> {
         // Remove all associated devices
         for device_id in self.all_device_ids(user_id) {
-            self.remove_device(&user_id, &device_id?)?;
+            self.remove_device(&user_id, &device_id?, rooms, globals, sending)?;
         }
 
         // Set the password to "" to indicate a deactivated account. Hashes will never result in an
@@ -0,0 +1,548 @@
+use crate::{room_version::RoomVersion, Error, PduEvent, Result};
+use js_int::Int;
+use ruma::{
+    events::{
+        room::{
+            create::CreateEventContent,
+            join_rules::{JoinRule, JoinRulesEventContent},
+            member::MembershipState,
+            power_levels::{NotificationPowerLevels, PowerLevelsEventContent},
+        },
+        EventType,
+    },
+    RoomVersionId, UserId,
+};
+use serde::de::DeserializeOwned;
+use std::{
+    collections::{BTreeMap, HashSet},
+    convert::TryFrom,
+};
+
+/// Checks if an event is allowed by the authorization rules of its room.
+///
+/// `state` returns the state before the event for an event type and state key.
+pub fn auth_check(
+    pdu: &PduEvent,
+    state: &dyn Fn(&EventType, &str) -> Result<Option<PduEvent>>,
+) -> Result<bool> {
+    if pdu.kind == EventType::RoomCreate {
+        return Ok(check_create(pdu));
+    }
+
+    let create_event = match state(&EventType::RoomCreate, "")? {
+        Some(create_event) => create_event,
+        None => return Ok(false),
+    };
+    let create_content = deserialize::<CreateEventContent>(&create_event.content)
+        .ok_or_else(|| Error::bad_database("Invalid create event in db."))?;
+    let room_version = RoomVersion::new(&create_content.room_version)
+        .ok_or_else(|| Error::bad_database("Room in db has an unsupported room version."))?;
+
+    // Rooms that don't federate only accept events of the creator's server
+    if !create_content.federate && pdu.sender.server_name() != create_event.sender.server_name() {
+        return Ok(false);
+    }
+
+    if pdu.kind == EventType::RoomAliases && room_version.special_case_aliases_auth {
+        return Ok(pdu.state_key.as_deref() == Some(pdu.sender.server_name().as_str()));
+    }
+
+    let has_power_levels_event;
+    let power_levels = match state(&EventType::RoomPowerLevels, "")? {
+        Some(power_levels_event) => {
+            has_power_levels_event = true;
+            deserialize::<PowerLevelsEventContent>(&power_levels_event.content)
+                .ok_or_else(|| Error::bad_database("Invalid power levels event in db."))?
+        }
+        None => {
+            has_power_levels_event = false;
+            default_power_levels(&create_content.creator)
+        }
+    };
+
+    let sender_membership = membership(state(&EventType::RoomMember, &pdu.sender.to_string())?);
+    let sender_power = power_level(&power_levels, &pdu.sender);
+
+    if pdu.kind == EventType::RoomMember {
+        return check_member(
+            pdu,
+            &create_event,
+            &create_content,
+            &power_levels,
+            sender_membership,
+            state,
+        );
+    }
+
+    if sender_membership != MembershipState::Join {
+        return Ok(false);
+    }
+
+    if pdu.kind == EventType::RoomThirdPartyInvite {
+        return Ok(sender_power >= power_levels.invite);
+    }
+
+    let required_power =
+        power_levels
+            .events
+            .get(&pdu.kind)
+            .copied()
+            .unwrap_or(if pdu.state_key.is_some() {
+                power_levels.state_default
+            } else {
+                power_levels.events_default
+            });
+    if sender_power < required_power {
+        return Ok(false);
+    }
+
+    // Only the user itself can send state with its user id as the state key
+    if let Some(state_key) = &pdu.state_key {
+        if state_key.starts_with('@') && state_key != &pdu.sender.to_string() {
+            return Ok(false);
+        }
+    }
+
+    Ok(match pdu.kind {
+        EventType::RoomPowerLevels => check_power_levels(
+            pdu,
+            if has_power_levels_event {
+                Some(&power_levels)
+            } else {
+                None
+            },
+            sender_power,
+            &room_version,
+        ),
+        EventType::RoomRedaction => {
+            sender_power >= power_levels.redact || !room_version.redaction_auth_on_receive || {
+                // Servers can redact events that were sent by them
+                let redacts_server = pdu.redacts.as_ref().and_then(|r| r.server_name());
+                redacts_server.is_some() && redacts_server == pdu.event_id.server_name()
+            }
+        }
+        _ => true,
+    })
+}
+
+/// Checks if a redaction may be applied to the event it redacts. In room versions that
+/// authorize redactions when they are received this was already checked by `auth_check`.
+///
+/// `state` returns the state before the redaction for an event type and state key.
+pub fn redaction_allowed(
+    redaction: &PduEvent,
+    redacted: &PduEvent,
+    state: &dyn Fn(&EventType, &str) -> Result<Option<PduEvent>>,
+) -> Result<bool> {
+    // Users and servers can always redact their own events
+    if redaction.sender.server_name() == redacted.sender.server_name() {
+        return Ok(true);
+    }
+
+    let power_levels = match state(&EventType::RoomPowerLevels, "")? {
+        Some(power_levels_event) => {
+            deserialize::<PowerLevelsEventContent>(&power_levels_event.content)
+                .ok_or_else(|| Error::bad_database("Invalid power levels event in db."))?
+        }
+        None => {
+            let create_event = match state(&EventType::RoomCreate, "")? {
+                Some(create_event) => create_event,
+                None => return Ok(false),
+            };
+            let create_content = deserialize::<CreateEventContent>(&create_event.content)
+                .ok_or_else(|| Error::bad_database("Invalid create event in db."))?;
+            default_power_levels(&create_content.creator)
+        }
+    };
+
+    Ok(power_level(&power_levels, &redaction.sender) >= power_levels.redact)
+}
+
+/// Returns the state entries that should be the auth events of an event, according to the auth
+/// events selection rules.
+pub fn auth_types(
+    kind: &EventType,
+    sender: &UserId,
+    state_key: Option<&str>,
+    content: &serde_json::Value,
+) -> Vec<(EventType, String)> {
+    // The create event is the only event without auth events
+    if *kind == EventType::RoomCreate {
+        return Vec::new();
+    }
+
+    let mut auth_types = vec![
+        (EventType::RoomCreate, "".to_owned()),
+        (EventType::RoomPowerLevels, "".to_owned()),
+        (EventType::RoomMember, sender.to_string()),
+    ];
+
+    if *kind == EventType::RoomMember {
+        let membership = content.get("membership").and_then(|m| m.as_str());
+
+        if let Some(state_key) = state_key {
+            auth_types.push((EventType::RoomMember, state_key.to_owned()));
+        }
+
+        if membership == Some("join") || membership == Some("invite") {
+            auth_types.push((EventType::RoomJoinRules, "".to_owned()));
+        }
+
+        if membership == Some("invite") {
+            if let Some(token) = content
+                .get("third_party_invite")
+                .and_then(|invite| invite.get("signed"))
+                .and_then(|signed| signed.get("token"))
+                .and_then(|token| token.as_str())
+            {
+                auth_types.push((EventType::RoomThirdPartyInvite, token.to_owned()));
+            }
+        }
+    }
+
+    auth_types
+}
+
+/// Checks that the auth events of a pdu have no duplicates, contain the create event and only
+/// contain events that the auth events selection rules allow.
+pub fn valid_auth_events(pdu: &PduEvent, auth_events: &[PduEvent]) -> bool {
+    let allowed = auth_types(
+        &pdu.kind,
+        &pdu.sender,
+        pdu.state_key.as_deref(),
+        &pdu.content,
+    );
+
+    let mut seen = HashSet::new();
+    for auth_event in auth_events {
+        let key = match &auth_event.state_key {
+            Some(state_key) => (auth_event.kind.clone(), state_key.clone()),
+            None => return false,
+        };
+
+        if !allowed.contains(&key) || !seen.insert(key) {
+            return false;
+        }
+    }
+
+    pdu.kind == EventType::RoomCreate || seen.contains(&(EventType::RoomCreate, "".to_owned()))
+}
+
+/// Checks if a pdu is allowed by the auth rules, using only its auth events as state.
+pub fn auth_check_with_auth_events(pdu: &PduEvent, auth_events: &[PduEvent]) -> Result<bool> {
+    if !valid_auth_events(pdu, auth_events) {
+        return Ok(false);
+    }
+
+    auth_check(pdu, &|event_type, state_key| {
+        Ok(auth_events
+            .iter()
+            .find(|auth_event| {
+                &auth_event.kind == event_type && auth_event.state_key.as_deref() == Some(state_key)
+            })
+            .cloned())
+    })
+}
+
+fn check_create(pdu: &PduEvent) -> bool {
+    if !pdu.prev_events.is_empty() || pdu.room_id.server_name() != pdu.sender.server_name() {
+        return false;
+    }
+
+    if let Some(room_version) = pdu.content.get("room_version") {
+        match room_version
+            .as_str()
+            .and_then(|v| RoomVersionId::try_from(v).ok())
+        {
+            Some(room_version) if RoomVersion::new(&room_version).is_some() => {}
+            _ => return false,
+        }
+    }
+
+    pdu.content.get("creator").is_some()
+}
+
+fn check_member(
+    pdu: &PduEvent,
+    create_event: &PduEvent,
+    create_content: &CreateEventContent,
+    power_levels: &PowerLevelsEventContent,
+    sender_membership: MembershipState,
+    state: &dyn Fn(&EventType, &str) -> Result<Option<PduEvent>>,
+) -> Result<bool> {
+    let target_user_id = match pdu
+        .state_key
+        .as_ref()
+        .and_then(|state_key| UserId::try_from(state_key.clone()).ok())
+    {
+        Some(target_user_id) => target_user_id,
+        None => return Ok(false),
+    };
+
+    let new_membership = match pdu
+        .content
+        .get("membership")
+        .and_then(|m| serde_json::from_value::<MembershipState>(m.clone()).ok())
+    {
+        Some(new_membership) => new_membership,
+        None => return Ok(false),
+    };
+
+    let target_membership = membership(state(&EventType::RoomMember, &target_user_id.to_string())?);
+    let sender_power = power_level(power_levels, &pdu.sender);
+    let target_power = power_level(power_levels, &target_user_id);
+
+    Ok(match new_membership {
+        MembershipState::Join => {
+            // The creator joins right after creating the room
+            if pdu.prev_events.len() == 1
+                && pdu.prev_events[0] == create_event.event_id
+                && create_content.creator == target_user_id
+            {
+                return Ok(true);
+            }
+
+            if pdu.sender != target_user_id || target_membership == MembershipState::Ban {
+                return Ok(false);
+            }
+
+            let join_rule = state(&EventType::RoomJoinRules, "")?
+                .and_then(|pdu| deserialize::<JoinRulesEventContent>(&pdu.content))
+                .map_or(JoinRule::Invite, |content| content.join_rule);
+
+            match join_rule {
+                JoinRule::Invite => {
+                    target_membership == MembershipState::Join
+                        || target_membership == MembershipState::Invite
+                }
+                JoinRule::Public => true,
+                _ => false,
+            }
+        }
+        MembershipState::Invite => {
+            if pdu.content.get("third_party_invite").is_some() {
+                check_third_party_invite(pdu, &target_user_id, target_membership, state)?
+            } else if sender_membership != MembershipState::Join
+                || target_membership == MembershipState::Join
+                || target_membership == MembershipState::Ban
+            {
+                false
+            } else {
+                sender_power >= power_levels.invite
+            }
+        }
+        MembershipState::Leave => {
+            if pdu.sender == target_user_id {
+                target_membership == MembershipState::Join
+                    || target_membership == MembershipState::Invite
+            } else if sender_membership != MembershipState::Join
+                || target_membership == MembershipState::Ban && sender_power < power_levels.ban
+            {
+                false
+            } else {
+                sender_power >= power_levels.kick && target_power < sender_power
+            }
+        }
+        MembershipState::Ban => {
+            sender_membership == MembershipState::Join
+                && sender_power >= power_levels.ban
+                && target_power < sender_power
+        }
+        _ => false,
+    })
+}
+
+/// Checks an invite that was created from an invite to a third party identifier, like an email
+/// address, which the invited user accepted.
+fn check_third_party_invite(
+    pdu: &PduEvent,
+    target_user_id: &UserId,
+    target_membership: MembershipState,
+    state: &dyn Fn(&EventType, &str) -> Result<Option<PduEvent>>,
+) -> Result<bool> {
+    if target_membership == MembershipState::Ban {
+        return Ok(false);
+    }
+
+    let signed = match pdu
+        .content
+        .get("third_party_invite")
+        .and_then(|invite| invite.get("signed"))
+    {
+        Some(signed) => signed,
+        None => return Ok(false),
+    };
+
+    let (mxid, token) = match (
+        signed.get("mxid").and_then(|mxid| mxid.as_str()),
+        signed.get("token").and_then(|token| token.as_str()),
+    ) {
+        (Some(mxid), Some(token)) => (mxid, token),
+        _ => return Ok(false),
+    };
+
+    if mxid != target_user_id.to_string() {
+        return Ok(false);
+    }
+
+    let invite_event = match state(&EventType::RoomThirdPartyInvite, token)? {
+        Some(invite_event) if invite_event.sender == pdu.sender => invite_event,
+        _ => return Ok(false),
+    };
+
+    let public_keys = invite_event
+        .content
+        .get("public_key")
+        .and_then(|key| key.as_str())
+        .into_iter()
+        .chain(
+            invite_event
+                .content
+                .get("public_keys")
+                .and_then(|keys| keys.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|key| key.get("public_key")?.as_str()),
+        )
+        .collect::<Vec<_>>();
+
+    let signatures = match signed.get("signatures").and_then(|s| s.as_object()) {
+        Some(signatures) => signatures,
+        None => return Ok(false),
+    };
+
+    // The identity server signed the invite with one of the keys in the invite event
+    for (server, server_signatures) in signatures {
+        for key_id in server_signatures
+            .as_object()
+            .into_iter()
+            .flat_map(|s| s.keys())
+        {
+            for public_key in &public_keys {
+                let mut keys = BTreeMap::new();
+                keys.insert(key_id.clone(), (*public_key).to_owned());
+                let mut pub_key_map = BTreeMap::new();
+                pub_key_map.insert(server.clone(), keys);
+
+                if ruma::signatures::verify_json(&pub_key_map, signed).is_ok() {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// Checks that the sender only changes power levels that are not higher than its own.
+fn check_power_levels(
+    pdu: &PduEvent,
+    current: Option<&PowerLevelsEventContent>,
+    sender_power: Int,
+    room_version: &RoomVersion,
+) -> bool {
+    let new = match deserialize::<PowerLevelsEventContent>(&pdu.content) {
+        Some(new) => new,
+        None => return false,
+    };
+
+    // The first power levels event can set anything
+    let current = match current {
+        Some(current) => current,
+        None => return true,
+    };
+
+    let too_high = |old: Option<Int>, new: Option<Int>| {
+        old != new
+            && (old.map_or(false, |level| level > sender_power)
+                || new.map_or(false, |level| level > sender_power))
+    };
+
+    let mut levels = vec![
+        (current.users_default, new.users_default),
+        (current.events_default, new.events_default),
+        (current.state_default, new.state_default),
+        (current.ban, new.ban),
+        (current.redact, new.redact),
+        (current.kick, new.kick),
+        (current.invite, new.invite),
+    ];
+    if room_version.limit_notifications_power_levels {
+        levels.push((current.notifications.room, new.notifications.room));
+    }
+
+    if levels
+        .into_iter()
+        .any(|(old, new)| too_high(Some(old), Some(new)))
+    {
+        return false;
+    }
+
+    for event_type in current.events.keys().chain(new.events.keys()) {
+        if too_high(
+            current.events.get(event_type).copied(),
+            new.events.get(event_type).copied(),
+        ) {
+            return false;
+        }
+    }
+
+    for user_id in current.users.keys().chain(new.users.keys()) {
+        let old_level = current.users.get(user_id).copied();
+        let new_level = new.users.get(user_id).copied();
+
+        if too_high(old_level, new_level) {
+            return false;
+        }
+
+        // Users can't change the level of others with the same power
+        if user_id != &pdu.sender && old_level != new_level && old_level == Some(sender_power) {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// The power levels of a room without power levels event: the creator has full power, everyone
+/// else has none.
+fn default_power_levels(creator: &UserId) -> PowerLevelsEventContent {
+    let mut users = BTreeMap::new();
+    users.insert(creator.clone(), 100.into());
+
+    PowerLevelsEventContent {
+        ban: 50.into(),
+        events: BTreeMap::new(),
+        events_default: 0.into(),
+        invite: 50.into(),
+        kick: 50.into(),
+        redact: 50.into(),
+        state_default: 0.into(),
+        users,
+        users_default: 0.into(),
+        notifications: NotificationPowerLevels { room: 50.into() },
+    }
+}
+
+fn power_level(power_levels: &PowerLevelsEventContent, user_id: &UserId) -> Int {
+    power_levels
+        .users
+        .get(user_id)
+        .copied()
+        .unwrap_or(power_levels.users_default)
+}
+
+/// Returns the membership of a member event. Users without member event have left.
+fn membership(member_event: Option<PduEvent>) -> MembershipState {
+    member_event
+        .and_then(|pdu| {
+            pdu.content
+                .get("membership")
+                .and_then(|m| serde_json::from_value(m.clone()).ok())
+        })
+        .unwrap_or(MembershipState::Leave)
+}
+
+fn deserialize<T: DeserializeOwned>(content: &serde_json::Value) -> Option<T> {
+    serde_json::from_value(content.clone()).ok()
+}
@@ -1,17 +1,20 @@
 pub mod client_server;
 mod database;
 mod error;
+mod event_auth;
 mod pdu;
 mod push_rules;
+mod room_version;
 mod ruma_wrapper;
 pub mod server_server;
+mod stateres;
 mod utils;
 
 pub use database::Database;
 pub use error::{Error, Result};
 pub use pdu::PduEvent;
 pub use rocket::Config;
-pub use ruma_wrapper::{ConduitResult, Ruma, RumaResponse};
+pub use ruma_wrapper::{AccessToken, ConduitResult, Ruma, RumaResponse};
 use std::ops::Deref;
 
 pub struct State<'r, T: Send + Sync + 'static>(pub &'r T);
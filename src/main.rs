@@ -5,16 +5,19 @@ pub mod server_server;
 
 mod database;
 mod error;
+mod event_auth;
 mod pdu;
 mod push_rules;
+mod room_version;
 mod ruma_wrapper;
+mod stateres;
 mod utils;
 
 pub use database::Database;
 pub use error::{Error, Result};
 pub use pdu::PduEvent;
 pub use rocket::State;
-pub use ruma_wrapper::{ConduitResult, Ruma, RumaResponse};
+pub use ruma_wrapper::{AccessToken, ConduitResult, Ruma, RumaResponse};
 
 use rocket::{fairing::AdHoc, routes};
 
@@ -119,17 +122,37 @@ fn setup_rocket() -> rocket::Rocket {
                 client_server::get_pushers_route,
                 client_server::set_pushers_route,
                 client_server::upgrade_room_route,
+                client_server::get_failed_events_route,
+                client_server::clear_remote_media_cache_route,
                 server_server::well_known_server,
                 server_server::get_server_version,
                 server_server::get_server_keys,
                 server_server::get_server_keys_deprecated,
+                server_server::get_remote_server_keys_batch_route,
+                server_server::get_remote_server_keys_route,
                 server_server::get_public_rooms_route,
+                server_server::get_room_information_route,
+                server_server::get_profile_information_route,
                 server_server::send_transaction_message_route,
+                server_server::create_join_event_template_route,
+                server_server::create_join_event_route,
+                server_server::get_keys_route,
+                server_server::claim_keys_route,
+                server_server::get_devices_route,
+                server_server::create_leave_event_template_route,
+                server_server::create_leave_event_route,
+                server_server::create_invite_route,
+                server_server::get_backfill_route,
+                server_server::get_missing_events_route,
+                server_server::get_room_state_route,
+                server_server::get_room_state_ids_route,
             ],
         )
         .attach(AdHoc::on_attach("Config", |mut rocket| async {
             let data = Database::load_or_create(rocket.config().await).expect("valid config");
 
+            data.sending.start_handler(&data.globals, &data.rooms);
+
             Ok(rocket.manage(data))
         }))
 }
@@ -1,4 +1,7 @@
-use crate::{Error, Result};
+use crate::{
+    room_version::{EventIdFormat, RoomVersion},
+    Error, Result,
+};
 use js_int::UInt;
 use ruma::{
     events::{
@@ -11,7 +14,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct PduEvent {
     pub event_id: EventId,
     pub room_id: RoomId,
@@ -35,39 +38,16 @@ pub struct PduEvent {
 }
 
 impl PduEvent {
-    pub fn redact(&mut self, reason: &PduEvent) -> Result<()> {
+    pub fn redact(&mut self, reason: &PduEvent, room_version: &RoomVersion) -> Result<()> {
         self.unsigned.clear();
 
-        let allowed: &[&str] = match self.kind {
-            EventType::RoomMember => &["membership"],
-            EventType::RoomCreate => &["creator"],
-            EventType::RoomJoinRules => &["join_rule"],
-            EventType::RoomPowerLevels => &[
-                "ban",
-                "events",
-                "events_default",
-                "kick",
-                "redact",
-                "state_default",
-                "users",
-                "users_default",
-            ],
-            EventType::RoomHistoryVisibility => &["history_visibility"],
-            _ => &[],
-        };
-
         let old_content = self
             .content
-            .as_object_mut()
-            .ok_or_else(|| Error::bad_database("PDU in db has invalid content."))?;
+            .as_object()
+            .ok_or_else(|| Error::bad_database("PDU in db has invalid content."))?
+            .clone();
 
-        let mut new_content = serde_json::Map::new();
-
-        for key in allowed {
-            if let Some(value) = old_content.remove(*key) {
-                new_content.insert((*key).to_owned(), value);
-            }
-        }
+        let new_content = room_version.redact_content(&self.kind, old_content);
 
         self.unsigned.insert(
             "redacted_because".to_owned(),
@@ -177,10 +157,35 @@ impl PduEvent {
 
         serde_json::from_value(json).expect("Raw::from_value always works")
     }
+
+    /// Removes the fields that only we care about before a pdu is sent to other servers.
+    pub fn convert_to_outgoing_federation_event(
+        mut pdu_json: serde_json::Value,
+        room_version: &RoomVersion,
+    ) -> Raw<ruma::events::pdu::PduStub> {
+        if let Some(unsigned) = pdu_json
+            .as_object_mut()
+            .expect("pdu json is an object")
+            .get_mut("unsigned")
+            .and_then(|u| u.as_object_mut())
+        {
+            unsigned.remove("transaction_id");
+        }
+
+        // The event id is calculated by the receiving server, unless it was chosen by us
+        if room_version.event_id_format != EventIdFormat::ServerChosen {
+            pdu_json
+                .as_object_mut()
+                .expect("pdu json is an object")
+                .remove("event_id");
+        }
+
+        serde_json::from_value(pdu_json).expect("Raw::from_value always works")
+    }
 }
 
 /// Build the start of a PDU in order to add it to the `Database`.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct PduBuilder {
     pub room_id: RoomId,
     pub sender: UserId,
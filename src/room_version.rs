@@ -0,0 +1,193 @@
+use crate::utils;
+use ruma::{events::EventType, signatures::Ed25519KeyPair, EventId, RoomVersionId, ServerName};
+use std::convert::TryFrom;
+
+/// The largest integer that canonical json allows in strict room versions (2^53 - 1).
+const MAX_SAFE_INT: i64 = 9_007_199_254_740_991;
+
+/// How the id of an event is determined.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EventIdFormat {
+    /// `$opaque_id:server_name`, chosen by the creating server and part of the signed pdu.
+    ServerChosen,
+    /// The reference hash of the pdu in unpadded base64.
+    Base64ReferenceHash,
+    /// The reference hash of the pdu in unpadded url-safe base64.
+    UrlSafeReferenceHash,
+}
+
+/// The rules that differ between the room versions we support.
+///
+/// Room version 1 specifies the first state resolution algorithm, but we resolve the state of
+/// all rooms with version 2.
+#[derive(Clone)]
+pub struct RoomVersion {
+    pub id: RoomVersionId,
+    pub event_id_format: EventIdFormat,
+    /// Servers may only send `m.room.aliases` events for their own domain.
+    pub special_case_aliases_auth: bool,
+    /// Redactions are authorized when they are received instead of when they are served.
+    pub redaction_auth_on_receive: bool,
+    /// The `notifications` power levels are checked like the other power levels.
+    pub limit_notifications_power_levels: bool,
+    /// Redacting `m.room.aliases` events keeps the aliases.
+    pub redaction_keeps_aliases: bool,
+    /// Pdus may only contain integers in the range of [-(2^53)+1, (2^53)-1] and no floats.
+    pub strict_canonical_json: bool,
+}
+
+impl RoomVersion {
+    /// Returns the rules of a room version or None if we don't support it.
+    pub fn new(id: &RoomVersionId) -> Option<Self> {
+        let (event_id_format, special_case_aliases_auth, redaction_auth_on_receive, strict) =
+            match id {
+                RoomVersionId::Version1 | RoomVersionId::Version2 => {
+                    (EventIdFormat::ServerChosen, true, true, false)
+                }
+                RoomVersionId::Version3 => (EventIdFormat::Base64ReferenceHash, true, false, false),
+                RoomVersionId::Version4 | RoomVersionId::Version5 => {
+                    (EventIdFormat::UrlSafeReferenceHash, true, false, false)
+                }
+                RoomVersionId::Version6 => {
+                    (EventIdFormat::UrlSafeReferenceHash, false, false, true)
+                }
+                _ => return None,
+            };
+
+        Some(Self {
+            id: id.clone(),
+            event_id_format,
+            special_case_aliases_auth,
+            redaction_auth_on_receive,
+            limit_notifications_power_levels: strict,
+            redaction_keeps_aliases: special_case_aliases_auth,
+            strict_canonical_json: strict,
+        })
+    }
+
+    /// Returns the ids of all room versions we support.
+    pub fn supported() -> Vec<RoomVersionId> {
+        vec![
+            RoomVersionId::Version1,
+            RoomVersionId::Version2,
+            RoomVersionId::Version3,
+            RoomVersionId::Version4,
+            RoomVersionId::Version5,
+            RoomVersionId::Version6,
+        ]
+    }
+
+    /// Returns the version new rooms are created with.
+    pub fn default_id() -> RoomVersionId {
+        RoomVersionId::Version6
+    }
+
+    /// Returns the id of a pdu we received. None if the pdu has no valid event id.
+    pub fn event_id(&self, pdu_json: &serde_json::Value) -> Option<EventId> {
+        match self.event_id_format {
+            EventIdFormat::ServerChosen => pdu_json
+                .get("event_id")
+                .and_then(|event_id| event_id.as_str())
+                .and_then(|event_id| EventId::try_from(event_id).ok()),
+            _ => {
+                let reference_hash = ruma::signatures::reference_hash(pdu_json).ok()?;
+                EventId::try_from(&*format!("${}", self.encode_reference_hash(reference_hash))).ok()
+            }
+        }
+    }
+
+    /// Adds the content hash and our signature to a pdu we created and returns its event id.
+    ///
+    /// The event id is part of the pdu json afterwards.
+    pub fn hash_and_sign_event(
+        &self,
+        server_name: &ServerName,
+        keypair: &Ed25519KeyPair,
+        pdu_json: &mut serde_json::Value,
+    ) -> EventId {
+        let pdu_object = pdu_json.as_object_mut().expect("pdu is a json object");
+
+        // The event id is only signed in versions where we can choose it
+        let event_id = if self.event_id_format == EventIdFormat::ServerChosen {
+            let event_id =
+                EventId::try_from(&*format!("${}:{}", utils::random_string(18), server_name))
+                    .expect("random strings and server names are valid in event ids");
+            pdu_object.insert("event_id".to_owned(), event_id.to_string().into());
+            Some(event_id)
+        } else {
+            pdu_object.remove("event_id");
+            None
+        };
+
+        ruma::signatures::hash_and_sign_event(server_name.as_str(), keypair, pdu_json)
+            .expect("event is valid, we just created it");
+
+        event_id.unwrap_or_else(|| {
+            // The reference hash covers the content hash, so it has to be calculated last
+            let event_id = self
+                .event_id(pdu_json)
+                .expect("ruma's reference hashes are valid event ids");
+            pdu_json
+                .as_object_mut()
+                .expect("pdu is a json object")
+                .insert("event_id".to_owned(), event_id.to_string().into());
+            event_id
+        })
+    }
+
+    /// Removes all content keys that don't survive a redaction.
+    pub fn redact_content(
+        &self,
+        kind: &EventType,
+        content: serde_json::Map<String, serde_json::Value>,
+    ) -> serde_json::Map<String, serde_json::Value> {
+        let allowed: &[&str] = match kind {
+            EventType::RoomMember => &["membership"],
+            EventType::RoomCreate => &["creator"],
+            EventType::RoomJoinRules => &["join_rule"],
+            EventType::RoomPowerLevels => &[
+                "ban",
+                "events",
+                "events_default",
+                "kick",
+                "redact",
+                "state_default",
+                "users",
+                "users_default",
+            ],
+            EventType::RoomAliases if self.redaction_keeps_aliases => &["aliases"],
+            EventType::RoomHistoryVisibility => &["history_visibility"],
+            _ => &[],
+        };
+
+        content
+            .into_iter()
+            .filter(|(key, _)| allowed.contains(&key.as_str()))
+            .collect()
+    }
+
+    /// Checks if a pdu is within the limits of the canonical json of this room version.
+    pub fn is_canonical_json(&self, pdu_json: &serde_json::Value) -> bool {
+        !self.strict_canonical_json || is_strict_canonical_json(pdu_json)
+    }
+
+    fn encode_reference_hash(&self, reference_hash: String) -> String {
+        // ruma encodes reference hashes url-safe
+        if self.event_id_format == EventIdFormat::Base64ReferenceHash {
+            reference_hash.replace('-', "+").replace('_', "/")
+        } else {
+            reference_hash
+        }
+    }
+}
+
+fn is_strict_canonical_json(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::Number(number) => number.as_i64().map_or(false, |number| {
+            (-MAX_SAFE_INT..=MAX_SAFE_INT).contains(&number)
+        }),
+        serde_json::Value::Array(values) => values.iter().all(is_strict_canonical_json),
+        serde_json::Value::Object(object) => object.values().all(is_strict_canonical_json),
+        _ => true,
+    }
+}
@@ -1,10 +1,10 @@
 use crate::Error;
-use ruma::identifiers::{DeviceId, UserId};
+use ruma::identifiers::{DeviceId, ServerName, UserId};
 use std::{convert::TryInto, ops::Deref};
 
 #[cfg(feature = "conduit_bin")]
 use {
-    crate::utils,
+    crate::{server_server, utils},
     log::warn,
     rocket::{
         data::{
@@ -12,12 +12,13 @@ use {
         },
         http::Status,
         outcome::Outcome::*,
+        request::{self, FromRequest},
         response::{self, Responder},
         tokio::io::AsyncReadExt,
         Request, State,
     },
     ruma::api::IncomingRequest,
-    std::io::Cursor,
+    std::{collections::BTreeMap, convert::TryFrom, io::Cursor},
 };
 
 /// This struct converts rocket requests into ruma structs by converting them into http requests
@@ -26,9 +27,35 @@ pub struct Ruma<T> {
     pub body: T,
     pub sender_id: Option<UserId>,
     pub device_id: Option<Box<DeviceId>>,
+    pub origin: Option<Box<ServerName>>, // The authenticated server of federation requests
     pub json_body: Option<Box<serde_json::value::RawValue>>, // This is None when body is not a valid string
 }
 
+/// The access token of a request, if it sent one. Routes that don't take a `Ruma` body use this
+/// to authenticate the same way.
+pub struct AccessToken(pub Option<String>);
+
+#[cfg(feature = "conduit_bin")]
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for AccessToken {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Success(AccessToken(access_token(request)))
+    }
+}
+
+/// Reads the access token from the `Authorization` header or the `access_token` query parameter.
+#[cfg(feature = "conduit_bin")]
+fn access_token(request: &Request<'_>) -> Option<String> {
+    request
+        .headers()
+        .get_one("Authorization")
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .map(|s| s.to_owned())
+        .or_else(|| request.get_query_value("access_token").and_then(|r| r.ok()))
+}
+
 #[cfg(feature = "conduit_bin")]
 impl<'a, T: IncomingRequest> FromTransformedData<'a> for Ruma<T> {
     type Error = (); // TODO: Better error handling
@@ -53,14 +80,31 @@ impl<'a, T: IncomingRequest> FromTransformedData<'a> for Ruma<T> {
                 .await
                 .expect("database was loaded");
 
-            let (user_id, device_id) = if T::METADATA.requires_authentication {
+            let limit = db.globals.max_request_size();
+            let mut handle = data.open().take(limit.into());
+            let mut body = Vec::new();
+            handle.read_to_end(&mut body).await.unwrap();
+
+            let (user_id, device_id, origin) = if T::METADATA.requires_authentication
+                && T::METADATA.path.starts_with("/_matrix/federation/")
+            {
+                let mut origin = None;
+
+                // There is one header for every key the request was signed with
+                for header in request.headers().get("Authorization") {
+                    if let Some(server) = verify_x_matrix(&*db, request, header, &body).await {
+                        origin = Some(server);
+                        break;
+                    }
+                }
+
+                match origin {
+                    None => return Failure((Status::Unauthorized, ())),
+                    Some(origin) => (None, None, Some(origin)),
+                }
+            } else if T::METADATA.requires_authentication {
                 // Get token from header or query value
-                let token = match request
-                    .headers()
-                    .get_one("Authorization")
-                    .map(|s| s[7..].to_owned()) // Split off "Bearer "
-                    .or_else(|| request.get_query_value("access_token").and_then(|r| r.ok()))
-                {
+                let token = match access_token(request) {
                     // TODO: M_MISSING_TOKEN
                     None => return Failure((Status::Unauthorized, ())),
                     Some(token) => token,
@@ -70,10 +114,10 @@ impl<'a, T: IncomingRequest> FromTransformedData<'a> for Ruma<T> {
                 match db.users.find_from_token(&token).unwrap() {
                     // TODO: M_UNKNOWN_TOKEN
                     None => return Failure((Status::Unauthorized, ())),
-                    Some((user_id, device_id)) => (Some(user_id), Some(device_id.into())),
+                    Some((user_id, device_id)) => (Some(user_id), Some(device_id.into()), None),
                 }
             } else {
-                (None, None)
+                (None, None, None)
             };
 
             let mut http_request = http::Request::builder()
@@ -83,11 +127,6 @@ impl<'a, T: IncomingRequest> FromTransformedData<'a> for Ruma<T> {
                 http_request = http_request.header(header.name.as_str(), &*header.value);
             }
 
-            let limit = db.globals.max_request_size();
-            let mut handle = data.open().take(limit.into());
-            let mut body = Vec::new();
-            handle.read_to_end(&mut body).await.unwrap();
-
             let http_request = http_request.body(body.clone()).unwrap();
             log::info!("{:?}", http_request);
 
@@ -96,6 +135,7 @@ impl<'a, T: IncomingRequest> FromTransformedData<'a> for Ruma<T> {
                     body: t,
                     sender_id: user_id,
                     device_id,
+                    origin,
                     // TODO: Can we avoid parsing it again? (We only need this for append_pdu)
                     json_body: utils::string_from_bytes(&body)
                         .ok()
@@ -110,6 +150,85 @@ impl<'a, T: IncomingRequest> FromTransformedData<'a> for Ruma<T> {
     }
 }
 
+/// Checks an `X-Matrix origin=...,key="...",sig="..."` header against the signing keys of the
+/// origin and returns the origin if the signature is valid.
+#[cfg(feature = "conduit_bin")]
+async fn verify_x_matrix(
+    db: &crate::Database<'_>,
+    request: &Request<'_>,
+    header: &str,
+    body: &[u8],
+) -> Option<Box<ServerName>> {
+    if !header.starts_with("X-Matrix ") {
+        return None;
+    }
+
+    let mut origin = None;
+    let mut key = None;
+    let mut sig = None;
+
+    for param in header["X-Matrix ".len()..].split(',') {
+        let mut parts = param.trim().splitn(2, '=');
+        let name = parts.next()?;
+        let value = parts.next()?.trim_matches('"');
+
+        match name {
+            "origin" => origin = Some(Box::<ServerName>::try_from(value).ok()?),
+            "key" => key = Some(value),
+            "sig" => sig = Some(value),
+            _ => {}
+        }
+    }
+
+    let (origin, key, sig) = (origin?, key?, sig?);
+
+    // We don't even fetch the keys of servers we don't federate with
+    if !db.globals.federation_allowed(&origin) {
+        warn!(
+            "Rejected request from {}, federation with it is disabled",
+            origin
+        );
+        return None;
+    }
+
+    let mut request_map = serde_json::Map::new();
+    if !body.is_empty() {
+        request_map.insert("content".to_owned(), serde_json::from_slice(body).ok()?);
+    }
+    request_map.insert("method".to_owned(), request.method().to_string().into());
+    request_map.insert("uri".to_owned(), request.uri().to_string().into());
+    request_map.insert("origin".to_owned(), origin.as_str().into());
+    request_map.insert(
+        "destination".to_owned(),
+        db.globals.server_name().as_str().into(),
+    );
+
+    let mut signatures = serde_json::Map::new();
+    signatures.insert(key.to_owned(), sig.into());
+    let mut origin_signatures = serde_json::Map::new();
+    origin_signatures.insert(origin.to_string(), signatures.into());
+    request_map.insert("signatures".to_owned(), origin_signatures.into());
+
+    // Requests have to be signed with a key that is valid now
+    let keys = match server_server::fetch_current_signing_keys(&db.globals, &origin).await {
+        Ok(keys) => keys,
+        Err(e) => {
+            warn!("Failed to fetch current signing keys of {}: {}", origin, e);
+            return None;
+        }
+    };
+    let mut pub_key_map = BTreeMap::new();
+    pub_key_map.insert(origin.to_string(), keys);
+
+    match ruma::signatures::verify_json(&pub_key_map, &request_map.into()) {
+        Ok(()) => Some(origin),
+        Err(e) => {
+            warn!("Invalid X-Matrix signature from {}: {}", origin, e);
+            None
+        }
+    }
+}
+
 impl<T> Deref for Ruma<T> {
     type Target = T;
 
@@ -1,25 +1,168 @@
-use crate::{client_server, ConduitResult, Database, Error, Result, Ruma};
-use http::header::{HeaderValue, AUTHORIZATION};
+use crate::{
+    client_server,
+    database::rooms::PduFailure,
+    event_auth,
+    pdu::PduBuilder,
+    room_version::{EventIdFormat, RoomVersion},
+    stateres::StateMap,
+    utils, ConduitResult, Database, Error, PduEvent, Result, Ruma,
+};
+use http::header::{HeaderValue, AUTHORIZATION, HOST};
+use log::warn;
 use rocket::{get, post, put, response::content::Json, State};
 use ruma::api::federation::{
+    backfill::get_backfill,
+    device::get_devices,
     directory::get_public_rooms,
     discovery::{
-        get_server_keys, get_server_version::v1 as get_server_version, ServerKey, VerifyKey,
+        get_remote_server_keys, get_remote_server_keys_batch, get_server_keys,
+        get_server_version::v1 as get_server_version, ServerKey, VerifyKey,
+    },
+    event::{get_missing_events, get_room_state, get_room_state_ids},
+    keys::{claim_keys, get_keys},
+    membership::{
+        create_invite, create_join_event, create_join_event_template, create_leave_event,
+        create_leave_event_template,
     },
+    query::{get_profile_information, get_room_information},
     transactions::send_transaction_message,
 };
-use ruma::api::{client, OutgoingRequest};
+use ruma::{
+    api::{
+        client::{self, error::ErrorKind},
+        OutgoingRequest,
+    },
+    events::{
+        ignored_user_list,
+        pdu::PduStub,
+        presence::{PresenceEvent, PresenceEventContent},
+        receipt,
+        room::{history_visibility, member},
+        AnyEphemeralRoomEvent, AnyEvent, EventType,
+    },
+    presence::PresenceState,
+    EventId, Raw, RoomAliasId, RoomId, ServerName, UserId,
+};
 use serde_json::json;
 use std::{
-    collections::BTreeMap,
-    convert::TryFrom,
+    collections::{BTreeMap, HashSet, VecDeque},
+    convert::{TryFrom, TryInto},
     fmt::Debug,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
-pub async fn request_well_known(db: &crate::Database<'_>, destination: &str) -> Option<String> {
+/// The port federation requests go to when nothing else is specified.
+const DEFAULT_FEDERATION_PORT: u16 = 8448;
+/// How long resolved destinations are cached if no record says otherwise.
+const DESTINATION_CACHE_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+/// How long destinations are cached when a well-known or SRV lookup failed.
+const DESTINATION_ERROR_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+/// How long the answers of other servers to alias and profile queries are cached.
+const QUERY_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+/// The most pdus we return for one backfill or get_missing_events request.
+const MAX_PDUS_PER_REQUEST: u64 = 100;
+/// How many missing prev events we ask for before handling an incoming pdu.
+const MISSING_EVENTS_LIMIT: u32 = 10;
+/// How long remote users are shown as typing if their server doesn't tell us they stopped.
+const REMOTE_TYPING_TIMEOUT: u64 = 30_000;
+
+/// Looks up the `_matrix._tcp` SRV record of a hostname and returns the target with its port
+/// and how long the record is valid.
+async fn query_srv_record(hostname: &str) -> Option<(String, Instant)> {
+    let resolver = trust_dns_resolver::TokioAsyncResolver::tokio_from_system_conf()
+        .await
+        .ok()?;
+    let lookup = resolver
+        .srv_lookup(format!("_matrix._tcp.{}.", hostname))
+        .await
+        .ok()?;
+
+    // Lowest priority first, then highest weight
+    let record = lookup
+        .iter()
+        .min_by_key(|srv| (srv.priority(), std::cmp::Reverse(srv.weight())))?;
+
+    Some((
+        format!(
+            "{}:{}",
+            record.target().to_string().trim_end_matches('.'),
+            record.port()
+        ),
+        lookup.as_lookup().valid_until(),
+    ))
+}
+
+/// Resolves a server name to the address we should connect to and the value of the Host header,
+/// following the server discovery rules of the server-server spec.
+async fn find_actual_destination(
+    globals: &crate::database::globals::Globals<'_>,
+    destination: &str,
+) -> (String, String) {
+    if let Some(cached) = globals.cached_destination(destination) {
+        return cached;
+    }
+
+    let mut valid_until = Instant::now() + DESTINATION_CACHE_TTL;
+    let error_valid_until = Instant::now() + DESTINATION_ERROR_CACHE_TTL;
+    let (hostname, port) = utils::split_host_port(destination);
+
+    let (actual_destination, host) = if utils::is_ip_literal(hostname) || port.is_some() {
+        (
+            format!("{}:{}", hostname, port.unwrap_or(DEFAULT_FEDERATION_PORT)),
+            destination.to_owned(),
+        )
+    } else if let Some(delegated) = request_well_known(globals, hostname).await {
+        let (delegated_hostname, delegated_port) = utils::split_host_port(&delegated);
+
+        if utils::is_ip_literal(delegated_hostname) || delegated_port.is_some() {
+            (
+                format!(
+                    "{}:{}",
+                    delegated_hostname,
+                    delegated_port.unwrap_or(DEFAULT_FEDERATION_PORT)
+                ),
+                delegated.clone(),
+            )
+        } else if let Some((srv, srv_valid_until)) = query_srv_record(delegated_hostname).await {
+            valid_until = valid_until.min(srv_valid_until);
+            (srv, delegated.clone())
+        } else {
+            valid_until = error_valid_until;
+            (
+                format!("{}:{}", delegated_hostname, DEFAULT_FEDERATION_PORT),
+                delegated.clone(),
+            )
+        }
+    } else if let Some((srv, srv_valid_until)) = query_srv_record(hostname).await {
+        // The well-known lookup failed, so we try it again soon
+        valid_until = error_valid_until.min(srv_valid_until);
+        (srv, destination.to_owned())
+    } else {
+        valid_until = error_valid_until;
+        (
+            format!("{}:{}", hostname, DEFAULT_FEDERATION_PORT),
+            destination.to_owned(),
+        )
+    };
+
+    let actual_destination = "https://".to_owned() + &actual_destination;
+
+    globals.cache_destination(
+        destination.to_owned(),
+        actual_destination.clone(),
+        host.clone(),
+        valid_until,
+    );
+
+    (actual_destination, host)
+}
+
+pub async fn request_well_known(
+    globals: &crate::database::globals::Globals<'_>,
+    destination: &str,
+) -> Option<String> {
     let body: serde_json::Value = serde_json::from_str(
-        &db.globals
+        &globals
             .reqwest_client()
             .get(&format!(
                 "https://{}/.well-known/matrix/server",
@@ -36,23 +179,184 @@ pub async fn request_well_known(db: &crate::Database<'_>, destination: &str) ->
     Some(body.get("m.server")?.as_str()?.to_owned())
 }
 
+/// Returns the signing keys of a server, including old keys which are needed for older events.
+///
+/// Stored keys are used as long as they are valid. Otherwise they are fetched again.
+pub async fn fetch_signing_keys(
+    globals: &crate::database::globals::Globals<'_>,
+    origin: &ServerName,
+) -> Result<BTreeMap<String, String>> {
+    let has_valid_keys = globals
+        .signing_keys_for(origin)?
+        .map_or(false, |keys| keys.valid_until_ts > SystemTime::now());
+
+    if !has_valid_keys {
+        update_signing_keys(globals, origin).await?;
+    }
+
+    // Expired keys are still better than nothing, the signatures may be old
+    let keys = globals
+        .signing_keys_for(origin)?
+        .ok_or(Error::BadServerResponse(
+            "Failed to find signing keys of server.",
+        ))?;
+
+    Ok(keys
+        .verify_keys
+        .into_iter()
+        .map(|(key_id, verify_key)| (key_id, verify_key.key))
+        .chain(
+            keys.old_verify_keys
+                .into_iter()
+                .map(|(key_id, old_key)| (key_id, old_key.key)),
+        )
+        .collect())
+}
+
+/// Returns the signing keys of a server that are valid right now, fetching them if needed.
+///
+/// Only these keys can authenticate requests, old and expired keys are only good for verifying
+/// old event signatures.
+pub async fn fetch_current_signing_keys(
+    globals: &crate::database::globals::Globals<'_>,
+    origin: &ServerName,
+) -> Result<BTreeMap<String, String>> {
+    let is_valid = |keys: &ServerKey| keys.valid_until_ts > SystemTime::now();
+
+    if !globals
+        .signing_keys_for(origin)?
+        .map_or(false, |keys| is_valid(&keys))
+    {
+        update_signing_keys(globals, origin).await?;
+    }
+
+    let keys = globals
+        .signing_keys_for(origin)?
+        .filter(is_valid)
+        .map(|keys| {
+            keys.verify_keys
+                .into_iter()
+                .map(|(key_id, verify_key)| (key_id, verify_key.key))
+                .collect::<BTreeMap<_, _>>()
+        })
+        .unwrap_or_default();
+
+    if keys.is_empty() {
+        return Err(Error::BadServerResponse(
+            "Failed to find current signing keys of server.",
+        ));
+    }
+
+    Ok(keys)
+}
+
+/// Fetches the signing keys of a server from the server itself and, if that fails, from the
+/// trusted servers and stores them.
+async fn update_signing_keys(
+    globals: &crate::database::globals::Globals<'_>,
+    origin: &ServerName,
+) -> Result<()> {
+    match send_request(globals, origin.to_string(), get_server_keys::v2::Request).await {
+        Ok(response) if validate_server_key(origin, &response.server_key) => {
+            globals.add_signing_key(origin, response.server_key)?;
+        }
+        _ => {
+            for notary in globals.trusted_servers() {
+                let response = send_request(
+                    globals,
+                    notary.to_string(),
+                    get_remote_server_keys::v2::Request {
+                        server_name: origin.to_owned(),
+                        minimum_valid_until_ts: SystemTime::now() + Duration::from_secs(60),
+                    },
+                )
+                .await;
+
+                match response {
+                    Ok(response) => {
+                        let mut found_keys = false;
+                        for server_key in response.server_keys {
+                            if validate_server_key(origin, &server_key) {
+                                globals.add_signing_key(origin, server_key)?;
+                                found_keys = true;
+                            }
+                        }
+
+                        if found_keys {
+                            break;
+                        }
+                    }
+                    Err(e) => warn!(
+                        "Failed to fetch keys of {} from notary {}: {}",
+                        origin, notary, e
+                    ),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the keys belong to the server, are still valid and are signed by the server
+/// itself.
+fn validate_server_key(origin: &ServerName, server_key: &ServerKey) -> bool {
+    if &*server_key.server_name != origin || server_key.valid_until_ts < SystemTime::now() {
+        return false;
+    }
+
+    let mut pub_key_map = BTreeMap::new();
+    pub_key_map.insert(
+        origin.to_string(),
+        server_key
+            .verify_keys
+            .iter()
+            .map(|(key_id, verify_key)| (key_id.clone(), verify_key.key.clone()))
+            .collect::<BTreeMap<_, _>>(),
+    );
+
+    serde_json::to_value(server_key).map_or(false, |value| {
+        ruma::signatures::verify_json(&pub_key_map, &value).is_ok()
+    })
+}
+
 pub async fn send_request<T: OutgoingRequest>(
-    db: &crate::Database<'static>,
+    globals: &crate::database::globals::Globals<'_>,
     destination: String,
     request: T,
 ) -> Result<T::IncomingResponse>
 where
     T: Debug,
 {
-    let actual_destination = "https://".to_owned()
-        + &request_well_known(db, &destination)
-            .await
-            .unwrap_or(destination.clone() + ":8448");
+    send_request_with_limit(globals, destination, request, None).await
+}
+
+/// Like `send_request`, but fails if the response body is larger than `max_response_size`
+/// bytes. The body is not read any further once it is too large.
+pub async fn send_request_with_limit<T: OutgoingRequest>(
+    globals: &crate::database::globals::Globals<'_>,
+    destination: String,
+    request: T,
+    max_response_size: Option<u64>,
+) -> Result<T::IncomingResponse>
+where
+    T: Debug,
+{
+    check_federation_allowed(globals, &destination)?;
+
+    let (actual_destination, host) = find_actual_destination(globals, &destination).await;
 
     let mut http_request = request
         .try_into_http_request(&actual_destination, Some(""))
         .unwrap();
 
+    http_request.headers_mut().insert(
+        HOST,
+        HeaderValue::from_str(&host).map_err(|_| {
+            Error::BadServerResponse("Resolved an invalid Host header for destination.")
+        })?,
+    );
+
     let mut request_map = serde_json::Map::new();
 
     if !http_request.body().is_empty() {
@@ -72,16 +376,13 @@ where
             .to_string()
             .into(),
     );
-    request_map.insert(
-        "origin".to_owned(),
-        db.globals.server_name().as_str().into(),
-    );
-    request_map.insert("destination".to_owned(), destination.into());
+    request_map.insert("origin".to_owned(), globals.server_name().as_str().into());
+    request_map.insert("destination".to_owned(), destination.as_str().into());
 
     let mut request_json = request_map.into();
     ruma::signatures::sign_json(
-        db.globals.server_name().as_str(),
-        db.globals.keypair(),
+        globals.server_name().as_str(),
+        globals.keypair(),
         &mut request_json,
     )
     .unwrap();
@@ -103,7 +404,7 @@ where
                 AUTHORIZATION,
                 HeaderValue::from_str(&format!(
                     "X-Matrix origin={},key=\"{}\",sig=\"{}\"",
-                    db.globals.server_name(),
+                    globals.server_name(),
                     s.0,
                     s.1
                 ))
@@ -115,11 +416,20 @@ where
     let reqwest_request = reqwest::Request::try_from(http_request)
         .expect("all http requests are valid reqwest requests");
 
-    let reqwest_response = db.globals.reqwest_client().execute(reqwest_request).await;
+    let reqwest_response = globals.reqwest_client().execute(reqwest_request).await;
 
     // Because reqwest::Response -> http::Response is complicated:
     match reqwest_response {
         Ok(mut reqwest_response) => {
+            let is_too_large = |size: u64| max_response_size.map_or(false, |max| size > max);
+
+            if reqwest_response
+                .content_length()
+                .map_or(false, is_too_large)
+            {
+                return Err(Error::BadServerResponse("Server response is too large."));
+            }
+
             let status = reqwest_response.status();
             let mut http_response = http::Response::builder().status(status);
             let headers = http_response.headers_mut().unwrap();
@@ -130,24 +440,40 @@ where
                 }
             }
 
-            let body = reqwest_response
-                .bytes()
-                .await
-                .unwrap()
-                .into_iter()
-                .collect();
-            Ok(
-                T::IncomingResponse::try_from(http_response.body(body).unwrap())
-                    .expect("TODO: error handle other server errors"),
-            )
+            let mut body = Vec::new();
+            while let Some(chunk) = reqwest_response.chunk().await.map_err(|e| {
+                warn!("Failed to read response body from {}: {}", destination, e);
+                Error::BadServerResponse("Failed to read response body from server.")
+            })? {
+                body.extend_from_slice(&chunk);
+
+                if is_too_large(body.len() as u64) {
+                    return Err(Error::BadServerResponse("Server response is too large."));
+                }
+            }
+
+            let http_response = http_response.body(body).map_err(|_| {
+                Error::BadServerResponse("Server returned an invalid http response.")
+            })?;
+
+            T::IncomingResponse::try_from(http_response).map_err(|e| {
+                warn!(
+                    "Server {} returned an error or invalid response ({}): {:?}",
+                    destination, status, e
+                );
+                Error::BadServerResponse("Server returned an error or invalid response.")
+            })
         }
         Err(e) => Err(e.into()),
     }
 }
 
 #[cfg_attr(feature = "conduit_bin", get("/.well-known/matrix/server"))]
-pub fn well_known_server() -> Json<String> {
-    rocket::response::content::Json(json!({ "m.server": "pc.koesters.xyz:59003"}).to_string())
+pub fn well_known_server(db: State<'_, Database<'_>>) -> Option<Json<String>> {
+    // Without a configured delegation, other servers fall back to SRV records or port 8448
+    db.globals
+        .well_known_server()
+        .map(|server| Json(json!({ "m.server": server }).to_string()))
 }
 
 #[cfg_attr(feature = "conduit_bin", get("/_matrix/federation/v1/version"))]
@@ -161,24 +487,30 @@ pub fn get_server_version() -> ConduitResult<get_server_version::Response> {
     .into())
 }
 
-#[cfg_attr(feature = "conduit_bin", get("/_matrix/key/v2/server"))]
-pub fn get_server_keys(db: State<'_, Database<'_>>) -> Json<String> {
+/// Returns the unsigned keys of this server.
+fn own_server_key(globals: &crate::database::globals::Globals<'_>) -> ServerKey {
     let mut verify_keys = BTreeMap::new();
     verify_keys.insert(
-        format!("ed25519:{}", db.globals.keypair().version()),
+        format!("ed25519:{}", globals.keypair().version()),
         VerifyKey {
-            key: base64::encode_config(db.globals.keypair().public_key(), base64::STANDARD_NO_PAD),
+            key: base64::encode_config(globals.keypair().public_key(), base64::STANDARD_NO_PAD),
         },
     );
+
+    ServerKey {
+        server_name: globals.server_name().to_owned(),
+        verify_keys,
+        old_verify_keys: BTreeMap::new(),
+        signatures: BTreeMap::new(),
+        valid_until_ts: SystemTime::now() + Duration::from_secs(60 * 2),
+    }
+}
+
+#[cfg_attr(feature = "conduit_bin", get("/_matrix/key/v2/server"))]
+pub fn get_server_keys(db: State<'_, Database<'_>>) -> Json<String> {
     let mut response = serde_json::from_slice(
         http::Response::try_from(get_server_keys::v2::Response {
-            server_key: ServerKey {
-                server_name: db.globals.server_name().to_owned(),
-                verify_keys,
-                old_verify_keys: BTreeMap::new(),
-                signatures: BTreeMap::new(),
-                valid_until_ts: SystemTime::now() + Duration::from_secs(60 * 2),
-            },
+            server_key: own_server_key(&db.globals),
         })
         .unwrap()
         .body(),
@@ -198,6 +530,88 @@ pub fn get_server_keys_deprecated(db: State<'_, Database<'_>>) -> Json<String> {
     get_server_keys(db)
 }
 
+/// Returns the keys of a server as a notary, countersigned by us.
+///
+/// Stored keys that are not valid until `minimum_valid_until_ts` are fetched again. If that
+/// fails, the stored keys are returned anyway and the requesting server can decide.
+async fn notary_server_keys(
+    globals: &crate::database::globals::Globals<'_>,
+    server_name: &ServerName,
+    minimum_valid_until_ts: SystemTime,
+) -> Result<Option<serde_json::Value>> {
+    let server_key = if server_name == globals.server_name() {
+        Some(own_server_key(globals))
+    } else {
+        let is_valid = globals
+            .signing_keys_for(server_name)?
+            .map_or(false, |keys| keys.valid_until_ts >= minimum_valid_until_ts);
+
+        if !is_valid {
+            if let Err(e) = update_signing_keys(globals, server_name).await {
+                warn!("Failed to update signing keys of {}: {}", server_name, e);
+            }
+        }
+
+        globals.signing_keys_for(server_name)?
+    };
+
+    Ok(server_key.map(|server_key| {
+        let mut value = serde_json::to_value(server_key).expect("ServerKey::to_value always works");
+        ruma::signatures::sign_json(
+            globals.server_name().as_str(),
+            globals.keypair(),
+            &mut value,
+        )
+        .expect("our keypair can sign json objects");
+        value
+    }))
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/key/v2/query", data = "<body>")
+)]
+pub async fn get_remote_server_keys_batch_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_remote_server_keys_batch::v2::Request>,
+) -> Result<Json<String>> {
+    let mut server_keys = Vec::new();
+
+    for (server_name, criteria) in &body.server_keys {
+        // Without criteria the keys only need to be valid now
+        let minimum_valid_until_ts = criteria
+            .values()
+            .filter_map(|criteria| criteria.minimum_valid_until_ts)
+            .max()
+            .unwrap_or_else(SystemTime::now);
+
+        if let Some(server_key) =
+            notary_server_keys(&db.globals, server_name, minimum_valid_until_ts).await?
+        {
+            server_keys.push(server_key);
+        }
+    }
+
+    Ok(Json(json!({ "server_keys": server_keys }).to_string()))
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/key/v2/query/<_>/<_>", data = "<body>")
+)]
+pub async fn get_remote_server_keys_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_remote_server_keys::v2::Request>,
+) -> Result<Json<String>> {
+    let server_keys =
+        notary_server_keys(&db.globals, &body.server_name, body.minimum_valid_until_ts)
+            .await?
+            .into_iter()
+            .collect::<Vec<_>>();
+
+    Ok(Json(json!({ "server_keys": server_keys }).to_string()))
+}
+
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/federation/v1/publicRooms", data = "<body>")
@@ -215,6 +629,7 @@ pub async fn get_public_rooms_route(
             },
         sender_id,
         device_id,
+        origin,
         json_body,
     } = body;
 
@@ -235,6 +650,7 @@ pub async fn get_public_rooms_route(
             },
             sender_id,
             device_id,
+            origin,
             json_body,
         },
     )
@@ -264,17 +680,1855 @@ pub async fn get_public_rooms_route(
     .into())
 }
 
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/federation/v1/query/directory", data = "<body>")
+)]
+pub fn get_room_information_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_room_information::v1::Request>,
+) -> ConduitResult<get_room_information::v1::Response> {
+    let room_alias = RoomAliasId::try_from(&*body.room_alias)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid room alias."))?;
+
+    if room_alias.server_name() != db.globals.server_name() {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room alias is not on this server.",
+        ));
+    }
+
+    let room_id = db
+        .rooms
+        .id_from_alias(&room_alias)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room with alias not found.",
+        ))?;
+
+    // Our server is always able to help with joins
+    let mut servers = vec![db.globals.server_name().to_string()];
+    servers.extend(
+        db.rooms
+            .room_servers(&room_id)?
+            .into_iter()
+            .filter(|server| &**server != db.globals.server_name())
+            .map(|server| server.to_string()),
+    );
+
+    Ok(get_room_information::v1::Response { room_id, servers }.into())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/federation/v1/query/profile", data = "<body>")
+)]
+pub fn get_profile_information_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_profile_information::v1::Request>,
+) -> ConduitResult<get_profile_information::v1::Response> {
+    if body.user_id.server_name() != db.globals.server_name() || !db.users.exists(&body.user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Profile was not found.",
+        ));
+    }
+
+    let mut displayname = None;
+    let mut avatar_url = None;
+
+    match &body.field {
+        Some(get_profile_information::v1::ProfileField::DisplayName) => {
+            displayname = db.users.displayname(&body.user_id)?
+        }
+        Some(get_profile_information::v1::ProfileField::AvatarUrl) => {
+            avatar_url = db.users.avatar_url(&body.user_id)?
+        }
+        _ => {
+            displayname = db.users.displayname(&body.user_id)?;
+            avatar_url = db.users.avatar_url(&body.user_id)?;
+        }
+    }
+
+    Ok(get_profile_information::v1::Response {
+        displayname,
+        avatar_url,
+    }
+    .into())
+}
+
+/// Asks the server of an alias which room it points to. Answers are cached for a few minutes.
+pub async fn query_remote_alias(
+    globals: &crate::database::globals::Globals<'_>,
+    room_alias: &RoomAliasId,
+) -> Result<(RoomId, Vec<String>)> {
+    if let Some(cached) = globals.cached_remote_alias(room_alias) {
+        return Ok(cached);
+    }
+
+    let response = send_request(
+        globals,
+        room_alias.server_name().to_string(),
+        get_room_information::v1::Request {
+            room_alias: room_alias.to_string(),
+        },
+    )
+    .await?;
+
+    globals.cache_remote_alias(
+        room_alias.clone(),
+        response.room_id.clone(),
+        response.servers.clone(),
+        Instant::now() + QUERY_CACHE_TTL,
+    );
+
+    Ok((response.room_id, response.servers))
+}
+
+/// Asks the server of a user for their displayname and avatar url. Answers are cached for a few
+/// minutes.
+pub async fn query_remote_profile(
+    globals: &crate::database::globals::Globals<'_>,
+    user_id: &UserId,
+) -> Result<(Option<String>, Option<String>)> {
+    if let Some(cached) = globals.cached_remote_profile(user_id) {
+        return Ok(cached);
+    }
+
+    let response = send_request(
+        globals,
+        user_id.server_name().to_string(),
+        get_profile_information::v1::Request {
+            user_id: user_id.clone(),
+            field: None,
+        },
+    )
+    .await?;
+
+    globals.cache_remote_profile(
+        user_id.clone(),
+        response.displayname.clone(),
+        response.avatar_url.clone(),
+        Instant::now() + QUERY_CACHE_TTL,
+    );
+
+    Ok((response.displayname, response.avatar_url))
+}
+
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/federation/v1/send/<_>", data = "<body>")
 )]
-pub fn send_transaction_message_route(
-    _db: State<'_, Database<'_>>,
+pub async fn send_transaction_message_route(
+    db: State<'_, Database<'_>>,
     body: Ruma<send_transaction_message::v1::Request>,
 ) -> ConduitResult<send_transaction_message::v1::Response> {
-    dbg!(&*body);
-    Ok(send_transaction_message::v1::Response {
-        pdus: BTreeMap::new(),
+    if body.origin.as_deref() != Some(&*body.body.origin) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Transaction origin does not match the authenticated server.",
+        ));
+    }
+
+    let mut pub_key_map = BTreeMap::new();
+    let mut resolved_map = BTreeMap::new();
+
+    for pdu in &body.pdus {
+        let value = serde_json::from_str::<serde_json::Value>(pdu.json().get())
+            .expect("converting raw jsons to values always works");
+
+        let room_id = value
+            .get("room_id")
+            .and_then(|room_id| room_id.as_str())
+            .and_then(|room_id| RoomId::try_from(room_id).ok());
+
+        // The event id format depends on the version of the room
+        let room_version = match &room_id {
+            Some(room_id) if db.rooms.exists(room_id)? => db.rooms.room_version_rules(room_id)?,
+            // The pdu will be rejected, but we still need an id to report that
+            _ => RoomVersion::new(&RoomVersion::default_id())
+                .expect("the default room version is supported"),
+        };
+
+        let event_id = calculate_event_id(&value, &room_version)?;
+
+        if let Some(room_id) = &room_id {
+            if !db.rooms.server_allowed_by_acl(room_id, &body.body.origin)? {
+                warn!("Rejected pdu {} from server denied by ACL", event_id);
+                resolved_map.insert(
+                    event_id,
+                    Err("Server was denied by the room's server ACL.".to_owned()),
+                );
+                continue;
+            }
+        }
+
+        // The events between our leaves and the pdu have to be handled first
+        for (missing_event_id, missing_value) in
+            fetch_missing_prev_events(&db, &body.body.origin, &room_version, &event_id, &value)
+                .await
+        {
+            if let Err(e) = handle_incoming_pdu(
+                &db,
+                &body.body.origin,
+                &mut pub_key_map,
+                &room_version,
+                &missing_event_id,
+                missing_value,
+            )
+            .await
+            {
+                warn!("Rejected missing pdu {}: {}", missing_event_id, e);
+            }
+        }
+
+        let result = handle_incoming_pdu(
+            &db,
+            &body.body.origin,
+            &mut pub_key_map,
+            &room_version,
+            &event_id,
+            value,
+        )
+        .await;
+        if let Err(e) = &result {
+            warn!("Rejected incoming pdu {}: {}", event_id, e);
+        }
+        resolved_map.insert(event_id, result);
+    }
+
+    for edu in &body.edus {
+        let edu = serde_json::to_value(edu).expect("edus can be serialized");
+        if let Err(e) = handle_incoming_edu(&db, &body.body.origin, &edu) {
+            warn!(
+                "Failed to handle incoming edu from {}: {}",
+                body.body.origin, e
+            );
+        }
+    }
+
+    Ok(send_transaction_message::v1::Response { pdus: resolved_map }.into())
+}
+
+/// Writes the typing notifications, read receipts and presence updates of remote users into
+/// the same trees as the ones of our users. Servers can only send edus of their own users.
+fn handle_incoming_edu(
+    db: &Database<'_>,
+    origin: &ServerName,
+    edu: &serde_json::Value,
+) -> Result<()> {
+    let content = edu.get("content").cloned().unwrap_or_default();
+
+    match edu.get("edu_type").and_then(|t| t.as_str()) {
+        Some("m.typing") => {
+            let room_id = content
+                .get("room_id")
+                .and_then(|r| r.as_str())
+                .and_then(|r| RoomId::try_from(r).ok());
+            let user_id = content
+                .get("user_id")
+                .and_then(|u| u.as_str())
+                .and_then(|u| UserId::try_from(u).ok());
+
+            if let (Some(room_id), Some(user_id)) = (room_id, user_id) {
+                if user_id.server_name() != origin
+                    || !db.rooms.is_joined(&user_id, &room_id)?
+                    || !db.rooms.server_allowed_by_acl(&room_id, origin)?
+                {
+                    return Ok(());
+                }
+
+                if content.get("typing").and_then(|t| t.as_bool()) == Some(true) {
+                    db.rooms.edus.typing_add(
+                        &user_id,
+                        &room_id,
+                        utils::millis_since_unix_epoch() + REMOTE_TYPING_TIMEOUT,
+                        &db.rooms,
+                        &db.globals,
+                        &db.sending,
+                    )?;
+                } else {
+                    db.rooms.edus.typing_remove(
+                        &user_id,
+                        &room_id,
+                        &db.rooms,
+                        &db.globals,
+                        &db.sending,
+                    )?;
+                }
+            }
+        }
+        Some("m.receipt") => {
+            for (room_id, receipts) in content.as_object().into_iter().flatten() {
+                let room_id = match RoomId::try_from(&**room_id) {
+                    Ok(room_id) if db.rooms.server_allowed_by_acl(&room_id, origin)? => room_id,
+                    _ => continue,
+                };
+
+                let read_receipts = receipts.get("m.read").and_then(|r| r.as_object());
+                for (user_id, receipt) in read_receipts.into_iter().flatten() {
+                    let user_id = match UserId::try_from(&**user_id) {
+                        Ok(user_id) => user_id,
+                        Err(_) => continue,
+                    };
+
+                    if user_id.server_name() != origin || !db.rooms.is_joined(&user_id, &room_id)? {
+                        continue;
+                    }
+
+                    let ts = receipt
+                        .get("data")
+                        .and_then(|data| data.get("ts"))
+                        .and_then(|ts| ts.as_u64())
+                        .map(|ts| SystemTime::UNIX_EPOCH + Duration::from_millis(ts));
+
+                    let mut receipt_content = BTreeMap::new();
+                    for event_id in receipt
+                        .get("event_ids")
+                        .and_then(|e| e.as_array())
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|e| e.as_str())
+                        .filter_map(|e| EventId::try_from(e).ok())
+                    {
+                        let mut user_receipts = BTreeMap::new();
+                        user_receipts.insert(user_id.clone(), receipt::Receipt { ts });
+                        receipt_content.insert(
+                            event_id,
+                            receipt::Receipts {
+                                read: Some(user_receipts),
+                            },
+                        );
+                    }
+
+                    if receipt_content.is_empty() {
+                        continue;
+                    }
+
+                    db.rooms.edus.readreceipt_update(
+                        &user_id,
+                        &room_id,
+                        AnyEvent::Ephemeral(AnyEphemeralRoomEvent::Receipt(
+                            receipt::ReceiptEvent {
+                                content: receipt::ReceiptEventContent(receipt_content),
+                                room_id: room_id.clone(),
+                            },
+                        )),
+                        &db.rooms,
+                        &db.globals,
+                        &db.sending,
+                    )?;
+                }
+            }
+        }
+        Some("m.device_list_update") => {
+            if let Some(user_id) = content
+                .get("user_id")
+                .and_then(|u| u.as_str())
+                .and_then(|u| UserId::try_from(u).ok())
+                .filter(|user_id| user_id.server_name() == origin)
+            {
+                // We don't cache the keys of remote users, so clients only need to query again
+                db.users
+                    .mark_device_key_update(&user_id, &db.rooms, &db.globals, &db.sending)?;
+            }
+        }
+        Some("m.direct_to_device") => {
+            let sender = match content
+                .get("sender")
+                .and_then(|s| s.as_str())
+                .and_then(|s| UserId::try_from(s).ok())
+            {
+                Some(sender) if sender.server_name() == origin => sender,
+                _ => return Ok(()),
+            };
+
+            let event_type = match content
+                .get("type")
+                .and_then(|t| serde_json::from_value::<EventType>(t.clone()).ok())
+            {
+                Some(event_type) => event_type,
+                None => return Ok(()),
+            };
+
+            let messages = content.get("messages").and_then(|m| m.as_object());
+            for (target_user_id, map) in messages.into_iter().flatten() {
+                let target_user_id = match UserId::try_from(&**target_user_id) {
+                    Ok(user_id) if user_id.server_name() == db.globals.server_name() => user_id,
+                    _ => continue,
+                };
+
+                for (target_device_id, event) in map.as_object().into_iter().flatten() {
+                    let target_device_ids = if target_device_id == "*" {
+                        db.users
+                            .all_device_ids(&target_user_id)
+                            .collect::<Result<Vec<_>>>()?
+                    } else {
+                        vec![target_device_id.as_str().into()]
+                    };
+
+                    for target_device_id in target_device_ids {
+                        db.users.add_to_device_event(
+                            &sender,
+                            &target_user_id,
+                            &target_device_id,
+                            &event_type,
+                            event.clone(),
+                            &db.globals,
+                        )?;
+                    }
+                }
+            }
+        }
+        Some("m.presence") => {
+            let updates = content.get("push").and_then(|p| p.as_array());
+            for update in updates.into_iter().flatten() {
+                let user_id = match update
+                    .get("user_id")
+                    .and_then(|u| u.as_str())
+                    .and_then(|u| UserId::try_from(u).ok())
+                {
+                    Some(user_id) if user_id.server_name() == origin => user_id,
+                    _ => continue,
+                };
+
+                let presence = match update
+                    .get("presence")
+                    .and_then(|p| serde_json::from_value::<PresenceState>(p.clone()).ok())
+                {
+                    Some(presence) => presence,
+                    None => continue,
+                };
+
+                // We store when the user was last active instead of the duration
+                let last_active = utils::millis_since_unix_epoch().saturating_sub(
+                    update
+                        .get("last_active_ago")
+                        .and_then(|l| l.as_u64())
+                        .unwrap_or(0),
+                );
+
+                for room_id in db.rooms.rooms_joined(&user_id) {
+                    let room_id = room_id?;
+                    if !db.rooms.server_allowed_by_acl(&room_id, origin)? {
+                        continue;
+                    }
+
+                    db.rooms.edus.update_presence(
+                        &user_id,
+                        &room_id,
+                        PresenceEvent {
+                            content: PresenceEventContent {
+                                avatar_url: None,
+                                currently_active: update
+                                    .get("currently_active")
+                                    .and_then(|c| c.as_bool()),
+                                displayname: None,
+                                last_active_ago: Some(
+                                    last_active.try_into().expect("time is valid"),
+                                ),
+                                presence,
+                                status_msg: update
+                                    .get("status_msg")
+                                    .and_then(|s| s.as_str())
+                                    .map(|s| s.to_owned()),
+                            },
+                            sender: user_id.clone(),
+                        },
+                        &db.rooms,
+                        &db.globals,
+                        &db.sending,
+                    )?;
+                }
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Calculates the event id of a pdu we got from another server.
+pub fn calculate_event_id(
+    value: &serde_json::Value,
+    room_version: &RoomVersion,
+) -> Result<EventId> {
+    room_version.event_id(value).ok_or(Error::BadRequest(
+        ErrorKind::BadJson,
+        "Could not calculate event id of pdu.",
+    ))
+}
+
+/// Verifies the signatures and the content hash of a pdu we got from another server and adds
+/// the event id to it.
+///
+/// The signing keys of all servers that signed the pdu are fetched and added to `pub_key_map`
+/// if they are not in there yet. Pdus with a wrong content hash are redacted.
+pub async fn verify_pdu(
+    globals: &crate::database::globals::Globals<'_>,
+    pub_key_map: &mut BTreeMap<String, BTreeMap<String, String>>,
+    room_version: &RoomVersion,
+    event_id: &EventId,
+    mut value: serde_json::Value,
+) -> std::result::Result<serde_json::Value, String> {
+    if !room_version.is_canonical_json(&value) {
+        return Err("Pdu is not valid canonical json for its room version.".to_owned());
+    }
+
+    let servers = value
+        .get("signatures")
+        .and_then(|s| s.as_object())
+        .ok_or("Pdu has no signatures.")?
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for server in servers {
+        if pub_key_map.contains_key(&server) {
+            continue;
+        }
+
+        let server_name =
+            Box::<ServerName>::try_from(&*server).map_err(|_| "Invalid server in signatures.")?;
+        let keys = fetch_signing_keys(globals, &server_name)
+            .await
+            .map_err(|_| format!("Failed to fetch signing keys of {}.", server))?;
+        pub_key_map.insert(server, keys);
+    }
+
+    match ruma::signatures::verify_event(pub_key_map, &value) {
+        Ok(ruma::signatures::Verified::All) => {}
+        Ok(ruma::signatures::Verified::Signatures) => {
+            // The content hash is wrong, so we only keep the redacted form of the event
+            value = ruma::signatures::redact(&value).map_err(|_| "Failed to redact pdu.")?;
+        }
+        Err(_) => return Err("Signature verification failed.".to_owned()),
+    }
+
+    value
+        .as_object_mut()
+        .ok_or("Pdu is not a json object.")?
+        .insert("event_id".to_owned(), event_id.to_string().into());
+
+    Ok(value)
+}
+
+/// Verifies, authorizes and persists a pdu that was sent to us by another server.
+async fn handle_incoming_pdu(
+    db: &Database<'_>,
+    origin: &ServerName,
+    pub_key_map: &mut BTreeMap<String, BTreeMap<String, String>>,
+    room_version: &RoomVersion,
+    event_id: &EventId,
+    value: serde_json::Value,
+) -> std::result::Result<(), String> {
+    // Skip the pdu if we already know it
+    if db
+        .rooms
+        .get_pdu_id(event_id)
+        .map_err(|_| "Failed to access database.")?
+        .is_some()
+    {
+        return Ok(());
+    }
+
+    let value = verify_pdu(&db.globals, pub_key_map, room_version, event_id, value).await?;
+
+    let pdu = serde_json::from_value::<PduEvent>(value.clone()).map_err(|_| "Invalid pdu.")?;
+
+    if !db
+        .rooms
+        .exists(&pdu.room_id)
+        .map_err(|_| "Failed to access database.")?
+    {
+        return Err("Room is unknown to this server.".to_owned());
+    }
+
+    // The origin knows the auth events of its pdu, so we ask it for the ones we don't know
+    let mut missing_auth_events = Vec::new();
+    for auth_event_id in &pdu.auth_events {
+        let known = db
+            .rooms
+            .get_pdu_json(auth_event_id)
+            .map_err(|_| "Failed to access database.")?
+            .is_some()
+            || db
+                .rooms
+                .is_rejected(auth_event_id)
+                .map_err(|_| "Failed to access database.")?;
+        if !known {
+            missing_auth_events.push(auth_event_id.clone());
+        }
+    }
+
+    // We can't check the pdu without its auth events, but it might be valid, so it is not
+    // rejected
+    if !fetch_missing_auth_events(
+        db,
+        origin,
+        pub_key_map,
+        room_version,
+        &pdu,
+        &missing_auth_events,
+    )
+    .await
+    .map_err(|_| "Failed to fetch auth events.")?
+    {
+        return Err("Auth events are unknown to this server.".to_owned());
+    }
+
+    // The room must not change between the auth checks and appending the pdu
+    let mutex = db.rooms.room_mutex(&pdu.room_id);
+    let _lock = mutex.lock().unwrap();
+
+    match db
+        .rooms
+        .pdu_failure(&pdu.room_id, &pdu.event_id)
+        .map_err(|_| "Failed to access database.")?
+    {
+        Some(PduFailure::Rejected) => return Err("Event was rejected before.".to_owned()),
+        Some(PduFailure::SoftFailed) => return Ok(()),
+        None => {}
+    }
+
+    let mut auth_events = Vec::new();
+    for auth_event_id in &pdu.auth_events {
+        if db
+            .rooms
+            .is_rejected(auth_event_id)
+            .map_err(|_| "Failed to access database.")?
+        {
+            db.rooms
+                .add_rejected_pdu(&pdu, &value)
+                .map_err(|_| "Failed to persist pdu.")?;
+            return Err("Event references a rejected auth event.".to_owned());
+        }
+
+        auth_events.push(
+            db.rooms
+                .get_pdu(auth_event_id)
+                .map_err(|_| "Failed to access database.")?
+                .ok_or("Auth event is unknown to this server.")?,
+        );
+    }
+
+    // Events that are not allowed by their auth events or the state before them are rejected
+    let allowed_by_auth_events = event_auth::auth_check_with_auth_events(&pdu, &auth_events)
+        .map_err(|_| "Failed to check auth rules.")?;
+
+    let allowed_by_state_before = allowed_by_auth_events && {
+        let state_before = db
+            .rooms
+            .state_at_prev_events(&pdu)
+            .map_err(|_| "Failed to resolve state before event.")?;
+
+        event_auth::auth_check(&pdu, &|event_type, state_key| {
+            state_before
+                .get(&(event_type.clone(), state_key.to_owned()))
+                .map_or(Ok(None), |event_id| db.rooms.get_pdu(event_id))
+        })
+        .map_err(|_| "Failed to check auth rules.")?
+    };
+
+    if !allowed_by_state_before {
+        db.rooms
+            .add_rejected_pdu(&pdu, &value)
+            .map_err(|_| "Failed to persist pdu.")?;
+        return Err("Event is not authorized.".to_owned());
+    }
+
+    // Events that are only forbidden by the current state are soft failed. The sending server
+    // did nothing wrong, so we don't report an error
+    if !db
+        .rooms
+        .auth_check(&pdu)
+        .map_err(|_| "Failed to check auth rules.")?
+    {
+        warn!("Soft failing pdu {}", pdu.event_id);
+        db.rooms
+            .add_soft_failed_pdu(&pdu, &value, &db.globals)
+            .map_err(|_| "Failed to persist pdu.")?;
+        return Ok(());
+    }
+
+    let count = db
+        .globals
+        .next_count()
+        .map_err(|_| "Failed to access database.")?;
+    db.rooms
+        .append_pdu(
+            &pdu,
+            &value,
+            count,
+            &db.globals,
+            &db.account_data,
+            &db.sending,
+        )
+        .map_err(|_| "Failed to persist pdu.")?;
+
+    Ok(())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/federation/v1/make_join/<_>/<_>", data = "<body>")
+)]
+pub fn create_join_event_template_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<create_join_event_template::v1::Request>,
+) -> ConduitResult<create_join_event_template::v1::Response> {
+    if !db.rooms.exists(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room is unknown to this server.",
+        ));
+    }
+
+    if body.origin.as_deref() != Some(body.user_id.server_name()) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Servers can only make joins for their own users.",
+        ));
+    }
+
+    check_server_acl(&db, &body.room_id, body.user_id.server_name())?;
+
+    let room_version = db.rooms.room_version(&body.room_id)?;
+    if !body.ver.contains(&room_version) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Room version is not supported by the joining server.",
+        ));
+    }
+
+    let pdu_json = membership_event_template(
+        &db,
+        &body.room_id,
+        &body.user_id,
+        member::MembershipState::Join,
+    )?;
+
+    Ok(create_join_event_template::v1::Response {
+        room_version: Some(room_version),
+        event: serde_json::from_value(pdu_json).expect("Raw::from_value always works"),
+    }
+    .into())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    put("/_matrix/federation/v2/send_join/<_>/<_>", data = "<body>")
+)]
+pub async fn create_join_event_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<create_join_event::v2::Request>,
+) -> ConduitResult<create_join_event::v2::Response> {
+    if !db.rooms.exists(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room is unknown to this server.",
+        ));
+    }
+
+    let (pdu, value) = verify_membership_event(
+        &db,
+        body.origin.as_deref(),
+        &body.room_id,
+        &body.event_id,
+        &body.pdu_stub,
+        member::MembershipState::Join,
+    )
+    .await?;
+
+    let mutex = db.rooms.room_mutex(&body.room_id);
+    let _lock = mutex.lock().unwrap();
+
+    // The joining server gets the state before the join
+    let state_event_ids = db
+        .rooms
+        .room_state_full(&body.room_id)?
+        .values()
+        .map(|pdu| pdu.event_id.clone())
+        .collect::<Vec<_>>();
+
+    if !db.rooms.auth_check(&pdu)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Event is not authorized.",
+        ));
+    }
+
+    let count = db.globals.next_count()?;
+    let pdu_id = db.rooms.append_pdu(
+        &pdu,
+        &value,
+        count,
+        &db.globals,
+        &db.account_data,
+        &db.sending,
+    )?;
+    db.rooms
+        .send_to_room_servers(&pdu, &pdu_id, &db.globals, &db.sending)?;
+
+    let room_version = db.rooms.room_version_rules(&body.room_id)?;
+
+    let mut state = Vec::new();
+    for event_id in &state_event_ids {
+        if let Some(pdu_json) = db.rooms.get_pdu_json(event_id)? {
+            state.push(PduEvent::convert_to_outgoing_federation_event(
+                pdu_json,
+                &room_version,
+            ));
+        }
+    }
+
+    Ok(create_join_event::v2::Response {
+        room_state: create_join_event::RoomState {
+            origin: db.globals.server_name().to_string(),
+            auth_chain: db
+                .rooms
+                .auth_chain(&state_event_ids)?
+                .into_iter()
+                .map(|pdu_json| {
+                    PduEvent::convert_to_outgoing_federation_event(pdu_json, &room_version)
+                })
+                .collect(),
+            state,
+        },
+    }
+    .into())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/federation/v1/user/keys/query", data = "<body>")
+)]
+pub fn get_keys_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_keys::v1::Request>,
+) -> ConduitResult<get_keys::v1::Response> {
+    let mut device_keys = BTreeMap::new();
+    for (user_id, device_ids) in &body.device_keys {
+        if user_id.server_name() != db.globals.server_name() {
+            continue;
+        }
+
+        device_keys.insert(
+            user_id.clone(),
+            client_server::get_local_device_keys(&db, user_id, device_ids)?,
+        );
+    }
+
+    Ok(get_keys::v1::Response { device_keys }.into())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/federation/v1/user/keys/claim", data = "<body>")
+)]
+pub fn claim_keys_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<claim_keys::v1::Request>,
+) -> ConduitResult<claim_keys::v1::Response> {
+    let mut one_time_keys = BTreeMap::new();
+    for (user_id, devices) in &body.one_time_keys {
+        if user_id.server_name() != db.globals.server_name() {
+            continue;
+        }
+
+        one_time_keys.insert(
+            user_id.clone(),
+            client_server::claim_local_one_time_keys(&db, user_id, devices)?,
+        );
+    }
+
+    Ok(claim_keys::v1::Response { one_time_keys }.into())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/federation/v1/user/devices/<_>", data = "<body>")
+)]
+pub fn get_devices_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_devices::v1::Request>,
+) -> ConduitResult<get_devices::v1::Response> {
+    if body.user_id.server_name() != db.globals.server_name() || !db.users.exists(&body.user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "User is unknown to this server.",
+        ));
+    }
+
+    let mut devices = Vec::new();
+    for device in db.users.all_devices_metadata(&body.user_id) {
+        let device = device?;
+        if let Some(keys) = db.users.get_device_keys(&body.user_id, &device.device_id)? {
+            devices.push(get_devices::v1::UserDevice {
+                device_id: device.device_id,
+                keys,
+                device_display_name: device.display_name,
+            });
+        }
+    }
+
+    Ok(get_devices::v1::Response {
+        user_id: body.user_id.clone(),
+        stream_id: db
+            .users
+            .device_list_version(&body.user_id)?
+            .try_into()
+            .map_err(|_| Error::bad_database("Count is too large."))?,
+        devices,
+    }
+    .into())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/federation/v1/make_leave/<_>/<_>", data = "<body>")
+)]
+pub fn create_leave_event_template_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<create_leave_event_template::v1::Request>,
+) -> ConduitResult<create_leave_event_template::v1::Response> {
+    if !db.rooms.exists(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room is unknown to this server.",
+        ));
+    }
+
+    if body.origin.as_deref() != Some(body.user_id.server_name()) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Servers can only make leaves for their own users.",
+        ));
+    }
+
+    check_server_acl(&db, &body.room_id, body.user_id.server_name())?;
+
+    let pdu_json = membership_event_template(
+        &db,
+        &body.room_id,
+        &body.user_id,
+        member::MembershipState::Leave,
+    )?;
+
+    Ok(create_leave_event_template::v1::Response {
+        room_version: Some(db.rooms.room_version(&body.room_id)?),
+        event: serde_json::from_value(pdu_json).expect("Raw::from_value always works"),
+    }
+    .into())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    put("/_matrix/federation/v2/send_leave/<_>/<_>", data = "<body>")
+)]
+pub async fn create_leave_event_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<create_leave_event::v2::Request>,
+) -> ConduitResult<create_leave_event::v2::Response> {
+    if !db.rooms.exists(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room is unknown to this server.",
+        ));
+    }
+
+    let (pdu, value) = verify_membership_event(
+        &db,
+        body.origin.as_deref(),
+        &body.room_id,
+        &body.event_id,
+        &body.pdu_stub,
+        member::MembershipState::Leave,
+    )
+    .await?;
+
+    let mutex = db.rooms.room_mutex(&body.room_id);
+    let _lock = mutex.lock().unwrap();
+
+    if !db.rooms.auth_check(&pdu)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Event is not authorized.",
+        ));
+    }
+
+    let count = db.globals.next_count()?;
+    let pdu_id = db.rooms.append_pdu(
+        &pdu,
+        &value,
+        count,
+        &db.globals,
+        &db.account_data,
+        &db.sending,
+    )?;
+    db.rooms
+        .send_to_room_servers(&pdu, &pdu_id, &db.globals, &db.sending)?;
+
+    Ok(create_leave_event::v2::Response.into())
+}
+
+/// Creates an unsigned membership event of a remote user for make_join and make_leave. The
+/// server of the user fills in the rest and signs it.
+fn membership_event_template(
+    db: &Database<'_>,
+    room_id: &RoomId,
+    user_id: &UserId,
+    membership: member::MembershipState,
+) -> Result<serde_json::Value> {
+    let pdu = db.rooms.create_pdu(
+        PduBuilder {
+            room_id: room_id.clone(),
+            sender: user_id.clone(),
+            event_type: EventType::RoomMember,
+            content: serde_json::to_value(member::MemberEventContent {
+                membership,
+                displayname: None,
+                avatar_url: None,
+                is_direct: None,
+                third_party_invite: None,
+            })
+            .expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: Some(user_id.to_string()),
+            redacts: None,
+        },
+        &db.globals,
+    )?;
+
+    let mut pdu_json = serde_json::to_value(&pdu).expect("event is valid, we just created it");
+    let pdu_stub = pdu_json.as_object_mut().expect("pdu is a json object");
+    pdu_stub.remove("event_id");
+    pdu_stub.remove("hashes");
+    pdu_stub.remove("signatures");
+    pdu_stub.remove("unsigned");
+
+    Ok(pdu_json)
+}
+
+/// Verifies a membership event that a server sent with send_join or send_leave. The event has
+/// to change the membership of a user of that server in this room.
+async fn verify_membership_event(
+    db: &Database<'_>,
+    origin: Option<&ServerName>,
+    room_id: &RoomId,
+    event_id: &EventId,
+    pdu_stub: &Raw<PduStub>,
+    membership: member::MembershipState,
+) -> Result<(PduEvent, serde_json::Value)> {
+    check_server_acl(
+        db,
+        room_id,
+        origin.expect("federation requests are authenticated"),
+    )?;
+
+    let value = serde_json::from_str::<serde_json::Value>(pdu_stub.json().get())
+        .expect("converting raw jsons to values always works");
+
+    let room_version = db.rooms.room_version_rules(room_id)?;
+    if calculate_event_id(&value, &room_version)? != *event_id {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event id does not match the membership event.",
+        ));
+    }
+
+    let mut pub_key_map = BTreeMap::new();
+    let value = verify_pdu(
+        &db.globals,
+        &mut pub_key_map,
+        &room_version,
+        event_id,
+        value,
+    )
+    .await
+    .map_err(|e| {
+        warn!("Invalid membership event {}: {}", event_id, e);
+        Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Membership event could not be verified.",
+        )
+    })?;
+
+    let pdu = serde_json::from_value::<PduEvent>(value.clone())
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid membership event."))?;
+
+    let content = serde_json::from_value::<member::MemberEventContent>(pdu.content.clone())
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid member event content."))?;
+
+    if pdu.room_id != *room_id
+        || pdu.kind != EventType::RoomMember
+        || pdu.state_key.as_ref() != Some(&pdu.sender.to_string())
+        || content.membership != membership
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event is not the expected membership event for this room.",
+        ));
+    }
+
+    if origin != Some(pdu.sender.server_name()) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Servers can only send membership events of their own users.",
+        ));
+    }
+
+    Ok((pdu, value))
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    put("/_matrix/federation/v2/invite/<_>/<_>", data = "<body>")
+)]
+pub async fn create_invite_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<create_invite::v2::Request>,
+) -> ConduitResult<create_invite::v2::Response> {
+    let room_version = RoomVersion::new(&body.room_version).ok_or(Error::BadRequest(
+        ErrorKind::IncompatibleRoomVersion {
+            room_version: body.room_version.clone(),
+        },
+        "Room version is not supported by this server.",
+    ))?;
+
+    let value = serde_json::from_str::<serde_json::Value>(body.event.json().get())
+        .expect("converting raw jsons to values always works");
+
+    let event_id = calculate_event_id(&value, &room_version)?;
+    if event_id != body.event_id {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event id does not match the invite event.",
+        ));
+    }
+
+    let pdu = serde_json::from_value::<PduEvent>(value.clone())
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid invite event."))?;
+
+    let membership = serde_json::from_value::<member::MemberEventContent>(pdu.content.clone())
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid member event content."))?
+        .membership;
+
+    let invited_user = pdu
+        .state_key
+        .as_ref()
+        .and_then(|state_key| UserId::try_from(&**state_key).ok())
+        .filter(|_| {
+            pdu.room_id == body.room_id
+                && pdu.kind == EventType::RoomMember
+                && membership == member::MembershipState::Invite
+        })
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event is not an invite event for this room.",
+        ))?;
+
+    if invited_user.server_name() != db.globals.server_name() || !db.users.exists(&invited_user)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Invited user is unknown to this server.",
+        ));
+    }
+
+    if body.origin.as_deref() != Some(pdu.sender.server_name()) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Servers can only send invites of their own users.",
+        ));
+    }
+
+    // We only know the ACL if we are in the room already
+    check_server_acl(&db, &body.room_id, pdu.sender.server_name())?;
+
+    let mut pub_key_map = BTreeMap::new();
+    verify_pdu(
+        &db.globals,
+        &mut pub_key_map,
+        &room_version,
+        &event_id,
+        value.clone(),
+    )
+    .await
+    .map_err(|e| {
+        warn!("Invalid invite event {}: {}", event_id, e);
+        Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Invite event could not be verified.",
+        )
+    })?;
+
+    // We countersign the event as it was sent, so the inviting server can add our signature
+    let mut signed_value = value;
+    if room_version.event_id_format != EventIdFormat::ServerChosen {
+        signed_value
+            .as_object_mut()
+            .ok_or(Error::BadRequest(
+                ErrorKind::BadJson,
+                "Invite event is not a json object.",
+            ))?
+            .remove("event_id");
+    }
+    ruma::signatures::hash_and_sign_event(
+        db.globals.server_name().as_str(),
+        db.globals.keypair(),
+        &mut signed_value,
+    )
+    .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Failed to sign invite event."))?;
+
+    let is_ignored = db
+        .account_data
+        .get::<ignored_user_list::IgnoredUserListEvent>(
+            None,
+            &invited_user,
+            EventType::IgnoredUserList,
+        )?
+        .map_or(false, |ignored| {
+            ignored.content.ignored_users.contains(&pdu.sender)
+        });
+
+    // Invites from ignored users are accepted, but never shown to the user
+    if !is_ignored {
+        let mut invite_state = body.invite_room_state.clone();
+        invite_state.push(pdu.to_stripped_state_event());
+
+        db.rooms
+            .add_remote_invite(&invited_user, &body.room_id, &invite_state, &db.globals)?;
+    }
+
+    Ok(create_invite::v2::Response {
+        event: PduEvent::convert_to_outgoing_federation_event(signed_value, &room_version),
+    }
+    .into())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/federation/v1/backfill/<_>", data = "<body>")
+)]
+pub fn get_backfill_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_backfill::v1::Request>,
+) -> ConduitResult<get_backfill::v1::Response> {
+    let origin = body
+        .origin
+        .as_ref()
+        .expect("federation requests are authenticated");
+
+    check_server_acl(&db, &body.room_id, origin)?;
+
+    if !db.rooms.room_servers(&body.room_id)?.contains(origin) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Server is not in this room.",
+        ));
+    }
+
+    let limit = u64::from(body.limit).min(MAX_PDUS_PER_REQUEST) as usize;
+    let room_version = db.rooms.room_version_rules(&body.room_id)?;
+
+    let pdus = walk_prev_events(&db, origin, &body.room_id, body.v.clone(), &[], 0, limit)?
+        .into_iter()
+        .map(|pdu_json| PduEvent::convert_to_outgoing_federation_event(pdu_json, &room_version))
+        .collect();
+
+    Ok(get_backfill::v1::Response {
+        origin: db.globals.server_name().to_owned(),
+        origin_server_ts: SystemTime::now(),
+        pdus,
+    }
+    .into())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/federation/v1/get_missing_events/<_>", data = "<body>")
+)]
+pub fn get_missing_events_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_missing_events::v1::Request>,
+) -> ConduitResult<get_missing_events::v1::Response> {
+    let origin = body
+        .origin
+        .as_ref()
+        .expect("federation requests are authenticated");
+
+    check_server_acl(&db, &body.room_id, origin)?;
+
+    if !db.rooms.room_servers(&body.room_id)?.contains(origin) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Server is not in this room.",
+        ));
+    }
+
+    // The latest events are known to the other server, we start at their prev events
+    let mut start = Vec::new();
+    for event_id in &body.latest_events {
+        if let Some(pdu) = db.rooms.get_pdu(event_id)? {
+            start.extend(pdu.prev_events);
+        }
+    }
+
+    let limit = u64::from(body.limit).min(MAX_PDUS_PER_REQUEST) as usize;
+    let room_version = db.rooms.room_version_rules(&body.room_id)?;
+
+    let events = walk_prev_events(
+        &db,
+        origin,
+        &body.room_id,
+        start,
+        &body.earliest_events,
+        body.min_depth.into(),
+        limit,
+    )?
+    .into_iter()
+    .map(|pdu_json| PduEvent::convert_to_outgoing_federation_event(pdu_json, &room_version))
+    .collect();
+
+    Ok(get_missing_events::v1::Response { events }.into())
+}
+
+/// Walks the event graph of a room backwards, starting at (and including) `start`, and returns
+/// up to `limit` pdus. Events in `stop_at` and events below `min_depth` are not returned and not
+/// walked past.
+///
+/// Events that `origin` may not see because of the history visibility are redacted.
+fn walk_prev_events(
+    db: &Database<'_>,
+    origin: &ServerName,
+    room_id: &RoomId,
+    start: Vec<EventId>,
+    stop_at: &[EventId],
+    min_depth: u64,
+    limit: usize,
+) -> Result<Vec<serde_json::Value>> {
+    let mut pdus = Vec::new();
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::from(start);
+
+    while let Some(event_id) = queue.pop_front() {
+        if pdus.len() >= limit {
+            break;
+        }
+
+        if stop_at.contains(&event_id) || !seen.insert(event_id.clone()) {
+            continue;
+        }
+
+        let pdu_json = match db.rooms.get_pdu_json(&event_id)? {
+            Some(pdu_json) => pdu_json,
+            None => continue,
+        };
+        let pdu = serde_json::from_value::<PduEvent>(pdu_json.clone())
+            .map_err(|_| Error::bad_database("Invalid PDU in db."))?;
+
+        if &pdu.room_id != room_id || u64::from(pdu.depth) < min_depth {
+            continue;
+        }
+
+        queue.extend(pdu.prev_events);
+
+        // We don't know the state at backfilled events, so they are redacted too
+        let visible = match db.rooms.state_before(&event_id)? {
+            Some(state) => server_can_see_event(db, origin, room_id, &state)?,
+            None => false,
+        };
+
+        pdus.push(if visible {
+            pdu_json
+        } else {
+            ruma::signatures::redact(&pdu_json)
+                .map_err(|_| Error::bad_database("Failed to redact pdu."))?
+        });
+    }
+
+    Ok(pdus)
+}
+
+/// Asks other servers in the room for up to `limit` events from before the start of our
+/// timeline and adds them to it. Returns how many events were added.
+pub async fn backfill(db: &Database<'_>, room_id: &RoomId, limit: u32) -> Result<usize> {
+    let (first_count, first_pdu) = match db.rooms.first_pdu_in_room(room_id)? {
+        Some(first) => first,
+        None => return Ok(0),
+    };
+
+    // There is nothing before the create event
+    if first_pdu.prev_events.is_empty() {
+        return Ok(0);
+    }
+
+    let room_version = db.rooms.room_version_rules(room_id)?;
+
+    for server in db
+        .rooms
+        .room_servers(room_id)?
+        .into_iter()
+        .filter(|server| &**server != db.globals.server_name())
+    {
+        let response = match send_request(
+            &db.globals,
+            server.to_string(),
+            get_backfill::v1::Request {
+                room_id: room_id.clone(),
+                v: vec![first_pdu.event_id.clone()],
+                limit: limit.into(),
+            },
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Failed to backfill {} from {}: {}", room_id, server, e);
+                continue;
+            }
+        };
+
+        let mut pub_key_map = BTreeMap::new();
+        let mut pdus = Vec::new();
+
+        for pdu in response.pdus {
+            let value = serde_json::from_str::<serde_json::Value>(pdu.json().get())
+                .expect("converting raw jsons to values always works");
+
+            let event_id = match calculate_event_id(&value, &room_version) {
+                Ok(event_id) => event_id,
+                Err(_) => continue,
+            };
+
+            if db.rooms.get_pdu_id(&event_id)?.is_some() || db.rooms.is_rejected(&event_id)? {
+                continue;
+            }
+
+            let value = match verify_pdu(
+                &db.globals,
+                &mut pub_key_map,
+                &room_version,
+                &event_id,
+                value,
+            )
+            .await
+            {
+                Ok(value) => value,
+                Err(e) => {
+                    warn!("Dropping backfilled pdu {}: {}", event_id, e);
+                    continue;
+                }
+            };
+
+            let pdu = match serde_json::from_value::<PduEvent>(value.clone()) {
+                Ok(pdu) if &pdu.room_id == room_id => pdu,
+                _ => continue,
+            };
+
+            pdus.push((pdu, value));
+        }
+
+        // Older events are checked first, so they can be the auth events of newer ones
+        pdus.sort_by_key(|(pdu, _)| pdu.depth);
+
+        let mut authorized = Vec::<(PduEvent, serde_json::Value)>::new();
+        for (pdu, value) in pdus {
+            let batch_auth_event = |auth_event_id: &EventId| {
+                authorized
+                    .iter()
+                    .find(|(authorized_pdu, _)| &authorized_pdu.event_id == auth_event_id)
+                    .map(|(authorized_pdu, _)| authorized_pdu.clone())
+            };
+
+            let mut missing_auth_events = Vec::new();
+            for auth_event_id in &pdu.auth_events {
+                if batch_auth_event(auth_event_id).is_none()
+                    && db.rooms.get_pdu_json(auth_event_id)?.is_none()
+                {
+                    missing_auth_events.push(auth_event_id.clone());
+                }
+            }
+
+            // Events whose auth events we can't get might still be valid, so they are not
+            // rejected
+            if !fetch_missing_auth_events(
+                db,
+                &server,
+                &mut pub_key_map,
+                &room_version,
+                &pdu,
+                &missing_auth_events,
+            )
+            .await?
+            {
+                warn!(
+                    "Skipping backfilled pdu {}: Unknown auth events",
+                    pdu.event_id
+                );
+                continue;
+            }
+
+            let mut auth_events = Vec::new();
+            for auth_event_id in &pdu.auth_events {
+                match batch_auth_event(auth_event_id) {
+                    Some(auth_event) => auth_events.push(auth_event),
+                    None => auth_events.extend(db.rooms.get_pdu(auth_event_id)?),
+                }
+            }
+
+            if !event_auth::auth_check_with_auth_events(&pdu, &auth_events)? {
+                warn!("Dropping unauthorized backfilled pdu {}", pdu.event_id);
+                db.rooms.add_rejected_pdu(&pdu, &value)?;
+                continue;
+            }
+
+            authorized.push((pdu, value));
+        }
+
+        // Newer events get higher counts, so they are shown after the older ones
+        let mut added = 0;
+        for (pdu, value) in authorized.into_iter().rev() {
+            let count = db.globals.next_backfill_count()?;
+
+            // Rooms joined before backfill counts existed can have a timeline below them
+            if count >= first_count {
+                warn!(
+                    "Stopped backfilling {}: No counts left below the timeline",
+                    room_id
+                );
+                break;
+            }
+
+            db.rooms.add_backfilled_pdu(&pdu, &value, count)?;
+            added += 1;
+        }
+
+        return Ok(added);
+    }
+
+    Ok(0)
+}
+
+/// Asks a server for the state at a pdu to get the auth events of the pdu that we don't know.
+/// They are stored as outliers, so the pdu can be auth checked. Returns false if some of them
+/// are still unknown afterwards.
+async fn fetch_missing_auth_events(
+    db: &Database<'_>,
+    server: &ServerName,
+    pub_key_map: &mut BTreeMap<String, BTreeMap<String, String>>,
+    room_version: &RoomVersion,
+    pdu: &PduEvent,
+    missing: &[EventId],
+) -> Result<bool> {
+    if missing.is_empty() {
+        return Ok(true);
+    }
+
+    let response = match send_request(
+        &db.globals,
+        server.to_string(),
+        get_room_state::v1::Request {
+            room_id: pdu.room_id.clone(),
+            event_id: pdu.event_id.clone(),
+        },
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            warn!(
+                "Failed to get auth events of {} from {}: {}",
+                pdu.event_id, server, e
+            );
+            return Ok(false);
+        }
+    };
+
+    // The auth events are part of the state or its auth chain
+    for raw in response.auth_chain.iter().chain(response.pdus.iter()) {
+        let value = serde_json::from_str::<serde_json::Value>(raw.json().get())
+            .expect("converting raw jsons to values always works");
+
+        let event_id = match calculate_event_id(&value, room_version) {
+            Ok(event_id) if missing.contains(&event_id) => event_id,
+            _ => continue,
+        };
+
+        let value = match verify_pdu(&db.globals, pub_key_map, room_version, &event_id, value).await
+        {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("Dropping auth event {}: {}", event_id, e);
+                continue;
+            }
+        };
+
+        if value.get("room_id").and_then(|r| r.as_str()) != Some(pdu.room_id.as_str()) {
+            warn!("Dropping auth event {}: Wrong room", event_id);
+            continue;
+        }
+
+        db.rooms.add_pdu_outlier(&value)?;
+    }
+
+    for event_id in missing {
+        if db.rooms.get_pdu_json(event_id)?.is_none() {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Asks the origin of a pdu for the events between the leaves of the room and the pdu if we
+/// don't know all of its prev events. The missing events are returned oldest first.
+async fn fetch_missing_prev_events(
+    db: &Database<'_>,
+    origin: &ServerName,
+    room_version: &RoomVersion,
+    event_id: &EventId,
+    value: &serde_json::Value,
+) -> Vec<(EventId, serde_json::Value)> {
+    let room_id = match value
+        .get("room_id")
+        .and_then(|room_id| room_id.as_str())
+        .and_then(|room_id| RoomId::try_from(room_id).ok())
+    {
+        Some(room_id) if db.rooms.exists(&room_id).unwrap_or(false) => room_id,
+        _ => return Vec::new(),
+    };
+
+    let prev_events = value
+        .get("prev_events")
+        .and_then(|prev_events| serde_json::from_value::<Vec<EventId>>(prev_events.clone()).ok())
+        .unwrap_or_default();
+
+    if prev_events
+        .iter()
+        .all(|prev_event| matches!(db.rooms.get_pdu_json(prev_event), Ok(Some(_))))
+    {
+        return Vec::new();
+    }
+
+    let earliest_events = match db.rooms.get_pdu_leaves(&room_id) {
+        Ok(leaves) => leaves,
+        Err(_) => return Vec::new(),
+    };
+
+    let response = match send_request(
+        &db.globals,
+        origin.to_string(),
+        get_missing_events::v1::Request {
+            room_id,
+            limit: MISSING_EVENTS_LIMIT.into(),
+            min_depth: 0_u32.into(),
+            earliest_events,
+            latest_events: vec![event_id.clone()],
+        },
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("Failed to get missing events before {}: {}", event_id, e);
+            return Vec::new();
+        }
+    };
+
+    let mut events = response
+        .events
+        .iter()
+        .filter_map(|pdu| {
+            let value = serde_json::from_str::<serde_json::Value>(pdu.json().get())
+                .expect("converting raw jsons to values always works");
+            let event_id = calculate_event_id(&value, room_version).ok()?;
+            Some((event_id, value))
+        })
+        .collect::<Vec<_>>();
+
+    events.sort_by_key(|(_, value)| value.get("depth").and_then(|depth| depth.as_u64()));
+
+    events
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/federation/v1/state/<_>", data = "<body>")
+)]
+pub fn get_room_state_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_room_state::v1::Request>,
+) -> ConduitResult<get_room_state::v1::Response> {
+    let origin = body
+        .origin
+        .as_ref()
+        .expect("federation requests are authenticated");
+
+    let state_event_ids = state_for_server(&db, origin, &body.room_id, &body.event_id)?;
+    let room_version = db.rooms.room_version_rules(&body.room_id)?;
+
+    let mut pdus = Vec::new();
+    for event_id in &state_event_ids {
+        if let Some(pdu_json) = db.rooms.get_pdu_json(event_id)? {
+            pdus.push(PduEvent::convert_to_outgoing_federation_event(
+                pdu_json,
+                &room_version,
+            ));
+        }
+    }
+
+    Ok(get_room_state::v1::Response {
+        auth_chain: db
+            .rooms
+            .auth_chain(&state_event_ids)?
+            .into_iter()
+            .map(|pdu_json| PduEvent::convert_to_outgoing_federation_event(pdu_json, &room_version))
+            .collect(),
+        pdus,
+    }
+    .into())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/federation/v1/state_ids/<_>", data = "<body>")
+)]
+pub fn get_room_state_ids_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_room_state_ids::v1::Request>,
+) -> ConduitResult<get_room_state_ids::v1::Response> {
+    let origin = body
+        .origin
+        .as_ref()
+        .expect("federation requests are authenticated");
+
+    let state_event_ids = state_for_server(&db, origin, &body.room_id, &body.event_id)?;
+
+    Ok(get_room_state_ids::v1::Response {
+        auth_chain_ids: db
+            .rooms
+            .auth_chain(&state_event_ids)?
+            .iter()
+            .filter_map(|pdu_json| EventId::try_from(pdu_json.get("event_id")?.as_str()?).ok())
+            .collect(),
+        pdu_ids: state_event_ids,
+    }
+    .into())
+}
+
+/// Returns the ids of the state events before an event, if the server may see the event.
+fn state_for_server(
+    db: &Database<'_>,
+    origin: &ServerName,
+    room_id: &RoomId,
+    event_id: &EventId,
+) -> Result<Vec<EventId>> {
+    check_server_acl(db, room_id, origin)?;
+
+    if db
+        .rooms
+        .get_pdu(event_id)?
+        .filter(|pdu| &pdu.room_id == room_id)
+        .is_none()
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Event not found in this room.",
+        ));
+    }
+
+    let state = db.rooms.state_before(event_id)?.ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "State at this event is unknown.",
+    ))?;
+
+    if !server_can_see_event(db, origin, room_id, &state)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Server is not allowed to see this event.",
+        ));
+    }
+
+    Ok(state.into_iter().map(|(_, event_id)| event_id).collect())
+}
+
+/// Checks if the history visibility of the state at an event allows a server to see it.
+fn server_can_see_event(
+    db: &Database<'_>,
+    origin: &ServerName,
+    room_id: &RoomId,
+    state: &StateMap,
+) -> Result<bool> {
+    let history_visibility = state
+        .get(&(EventType::RoomHistoryVisibility, "".to_owned()))
+        .map_or(Ok(None), |event_id| db.rooms.get_pdu(event_id))?
+        .and_then(|pdu| {
+            serde_json::from_value::<history_visibility::HistoryVisibilityEventContent>(pdu.content)
+                .ok()
+        })
+        .map_or(history_visibility::HistoryVisibility::Shared, |content| {
+            content.history_visibility
+        });
+
+    // The memberships of the origin's users at the event
+    let mut memberships = Vec::new();
+    for ((event_type, state_key), event_id) in state {
+        if *event_type != EventType::RoomMember
+            || UserId::try_from(state_key.as_str())
+                .map_or(true, |user_id| user_id.server_name() != origin)
+        {
+            continue;
+        }
+
+        if let Some(membership) = db.rooms.get_pdu(event_id)?.and_then(|pdu| {
+            serde_json::from_value::<member::MembershipState>(
+                pdu.content.get("membership")?.clone(),
+            )
+            .ok()
+        }) {
+            memberships.push(membership);
+        }
+    }
+
+    let was_joined = memberships.contains(&member::MembershipState::Join);
+
+    Ok(match history_visibility {
+        history_visibility::HistoryVisibility::WorldReadable => true,
+        // Shared history is visible to servers that are in the room now
+        history_visibility::HistoryVisibility::Shared => {
+            was_joined || db.rooms.room_servers(room_id)?.contains(origin)
+        }
+        history_visibility::HistoryVisibility::Invited => {
+            was_joined || memberships.contains(&member::MembershipState::Invite)
+        }
+        history_visibility::HistoryVisibility::Joined => was_joined,
+        _ => false,
+    })
+}
+
+/// Rejects requests about a room from servers that the room's server ACL denies.
+fn check_server_acl(db: &Database<'_>, room_id: &RoomId, origin: &ServerName) -> Result<()> {
+    if db.rooms.server_allowed_by_acl(room_id, origin)? {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Server was denied by the room's server ACL.",
+        ))
+    }
+}
+
+/// Rejects talking to servers that this server doesn't federate with.
+pub fn check_federation_allowed(
+    globals: &crate::database::globals::Globals<'_>,
+    server: &str,
+) -> Result<()> {
+    match Box::<ServerName>::try_from(server) {
+        Ok(server) if globals.federation_allowed(&server) => Ok(()),
+        _ => Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Federation with this server is disabled.",
+        )),
     }
-    .into())
 }
@@ -1,59 +1,433 @@
-use std::collections::HashMap;
+use crate::{database::rooms::Rooms, event_auth, PduEvent, Result};
+use js_int::Int;
+use ruma::{
+    events::{room::power_levels::PowerLevelsEventContent, EventType},
+    EventId, Raw,
+};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
 
-fn stateres(state_a: HashMap<StateTuple, PduEvent>, state_b: HashMap<StateTuple, PduEvent>) {
-    let mut unconflicted = todo!("state at fork event");
+/// Maps (event type, state key) to the id of the state event.
+pub type StateMap = HashMap<(EventType, String), EventId>;
 
-    let mut conflicted: HashMap<StateTuple, PduEvent> = state_a
-        .iter()
-        .filter(|(key_a, value_a)| match state_b.remove(key_a) {
-            Some(value_b) if value_a == value_b => unconflicted.insert(key_a, value_a),
-            _ => false,
-        })
-        .collect();
+/// Resolves the state of multiple forks of a room with version 2 of the state resolution
+/// algorithm.
+///
+/// Events that are unknown to us are ignored.
+pub fn resolve(rooms: &Rooms, state_sets: &[StateMap]) -> Result<StateMap> {
+    match state_sets {
+        [] => return Ok(StateMap::new()),
+        [state] => return Ok(state.clone()),
+        _ => {}
+    }
+
+    let mut events = EventCache {
+        rooms,
+        pdus: HashMap::new(),
+        auth_chains: HashMap::new(),
+    };
+
+    let (unconflicted, conflicted) = separate(state_sets);
+    if conflicted.is_empty() {
+        return Ok(unconflicted);
+    }
+
+    // The full conflicted set also contains the auth events that only some forks know about
+    let mut full_conflicted = HashSet::new();
+    for event_id in conflicted
+        .into_iter()
+        .chain(auth_difference(&mut events, state_sets)?)
+    {
+        if events.get(&event_id)?.is_some() {
+            full_conflicted.insert(event_id);
+        }
+    }
+
+    // Power events and the parts of their auth chains that are conflicted are resolved first
+    let mut control_events = HashSet::new();
+    for event_id in &full_conflicted {
+        if events
+            .get(event_id)?
+            .map_or(false, |pdu| is_power_event(&pdu))
+        {
+            control_events.insert(event_id.clone());
+            control_events.extend(
+                events
+                    .auth_chain(event_id)?
+                    .into_iter()
+                    .filter(|auth_id| full_conflicted.contains(auth_id)),
+            );
+        }
+    }
+
+    let sorted_control_events = reverse_topological_power_sort(&mut events, &control_events)?;
+    let mut resolved = iterative_auth_check(&mut events, &sorted_control_events, &unconflicted)?;
 
-    // We removed unconflicted from state_b, now we can easily insert all events that are only in fork b
-    conflicted.extend(state_b);
+    // All other events are ordered along the mainline of the resolved power levels
+    let other_events = full_conflicted
+        .difference(&control_events)
+        .cloned()
+        .collect::<Vec<_>>();
+    let power_levels = resolved
+        .get(&(EventType::RoomPowerLevels, "".to_owned()))
+        .cloned();
+    let sorted_other_events = mainline_sort(&mut events, &other_events, power_levels)?;
+    resolved = iterative_auth_check(&mut events, &sorted_other_events, &resolved)?;
 
-    let partial_state = unconflicted.clone();
+    // The state that all forks agree on always wins
+    resolved.extend(unconflicted);
 
-    let full_conflicted = conflicted.clone(); // TODO: auth events
+    Ok(resolved)
+}
+
+/// Loads pdus and their auth chains only once during a resolution.
+struct EventCache<'a> {
+    rooms: &'a Rooms,
+    pdus: HashMap<EventId, Option<PduEvent>>,
+    auth_chains: HashMap<EventId, HashSet<EventId>>,
+}
 
-    let output_rev = Vec::new();
-    let event_map = HashMap::new();
-    let incoming_edges = HashMap::new();
+impl EventCache<'_> {
+    fn get(&mut self, event_id: &EventId) -> Result<Option<PduEvent>> {
+        if let Some(pdu) = self.pdus.get(event_id) {
+            return Ok(pdu.clone());
+        }
 
-    for event in full_conflicted {
-        event_map.insert(event.event_id, event);
-        incoming_edges.insert(event.event_id, 0);
+        let pdu = self.rooms.get_pdu(event_id)?;
+        self.pdus.insert(event_id.clone(), pdu.clone());
+        Ok(pdu)
     }
 
-    for e in conflicted_control_events {
-        for a in e.auth_events {
-            incoming_edges[a.event_id] += 1;
+    /// Returns all auth events of an event, recursively.
+    fn auth_chain(&mut self, event_id: &EventId) -> Result<HashSet<EventId>> {
+        if let Some(auth_chain) = self.auth_chains.get(event_id) {
+            return Ok(auth_chain.clone());
+        }
+
+        let mut auth_chain = HashSet::new();
+        let mut todo = vec![event_id.clone()];
+        while let Some(current) = todo.pop() {
+            if let Some(pdu) = self.get(&current)? {
+                for auth_id in pdu.auth_events {
+                    if auth_chain.insert(auth_id.clone()) {
+                        todo.push(auth_id);
+                    }
+                }
+            }
         }
+
+        self.auth_chains
+            .insert(event_id.clone(), auth_chain.clone());
+        Ok(auth_chain)
     }
 
-    while incoming_edges.len() > 0 {
-        let mut count_0 = incoming_edges
+    /// Returns the power level of the sender of an event, based on the event's auth events.
+    fn sender_power_level(&mut self, pdu: &PduEvent) -> Result<Int> {
+        let mut creator = None;
+
+        for auth_id in &pdu.auth_events {
+            let auth_pdu = match self.get(auth_id)? {
+                Some(auth_pdu) if auth_pdu.state_key.as_deref() == Some("") => auth_pdu,
+                _ => continue,
+            };
+
+            match auth_pdu.kind {
+                EventType::RoomPowerLevels => {
+                    if let Ok(power_levels) =
+                        serde_json::from_value::<Raw<PowerLevelsEventContent>>(auth_pdu.content)
+                            .expect("Raw::from_value always works")
+                            .deserialize()
+                    {
+                        return Ok(power_levels
+                            .users
+                            .get(&pdu.sender)
+                            .copied()
+                            .unwrap_or(power_levels.users_default));
+                    }
+                }
+                EventType::RoomCreate => {
+                    creator = auth_pdu
+                        .content
+                        .get("creator")
+                        .and_then(|creator| creator.as_str())
+                        .map(|creator| creator.to_owned());
+                }
+                _ => {}
+            }
+        }
+
+        // Without power levels, the creator has full power
+        if creator == Some(pdu.sender.to_string()) {
+            Ok(100.into())
+        } else {
+            Ok(0.into())
+        }
+    }
+
+    /// Returns the power levels event among the auth events of an event.
+    fn power_levels_event(&mut self, pdu: &PduEvent) -> Result<Option<PduEvent>> {
+        for auth_id in &pdu.auth_events {
+            if let Some(auth_pdu) = self.get(auth_id)? {
+                if auth_pdu.kind == EventType::RoomPowerLevels
+                    && auth_pdu.state_key.as_deref() == Some("")
+                {
+                    return Ok(Some(auth_pdu));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Splits the state sets into the state that all forks agree on and the ids of all events
+/// that are in conflict.
+fn separate(state_sets: &[StateMap]) -> (StateMap, HashSet<EventId>) {
+    let mut unconflicted = StateMap::new();
+    let mut conflicted = HashSet::new();
+
+    let keys = state_sets
+        .iter()
+        .flat_map(|state| state.keys())
+        .collect::<HashSet<_>>();
+
+    for key in keys {
+        let event_ids = state_sets
             .iter()
-            .filter(|(_, c)| c == 0)
+            .map(|state| state.get(key))
             .collect::<Vec<_>>();
 
-        count_0.sort_by(|(x, _), (y, _)| {
-            x.power_level
-                .cmp(&a.power_level)
-                .then_with(|| x.origin_server.ts.cmp(&y.origin_server_ts))
-                .then_with(|| x.event_id.cmp(&y.event_id))
-        });
+        match event_ids[0] {
+            Some(first) if event_ids.iter().all(|event_id| *event_id == Some(first)) => {
+                unconflicted.insert(key.clone(), first.clone());
+            }
+            _ => conflicted.extend(event_ids.into_iter().flatten().cloned()),
+        }
+    }
+
+    (unconflicted, conflicted)
+}
+
+/// Returns the events that are in the auth chain of some, but not all, state sets.
+fn auth_difference(
+    events: &mut EventCache<'_>,
+    state_sets: &[StateMap],
+) -> Result<HashSet<EventId>> {
+    let mut auth_chains = Vec::new();
+    for state in state_sets {
+        let mut auth_chain = HashSet::new();
+        for event_id in state.values() {
+            auth_chain.extend(events.auth_chain(event_id)?);
+        }
+        auth_chains.push(auth_chain);
+    }
+
+    let union = auth_chains
+        .iter()
+        .flatten()
+        .cloned()
+        .collect::<HashSet<_>>();
+
+    Ok(union
+        .into_iter()
+        .filter(|event_id| !auth_chains.iter().all(|chain| chain.contains(event_id)))
+        .collect())
+}
+
+/// Power events are events that can take away power or change who can join.
+fn is_power_event(pdu: &PduEvent) -> bool {
+    match pdu.kind {
+        EventType::RoomCreate | EventType::RoomPowerLevels | EventType::RoomJoinRules => {
+            pdu.state_key.as_deref() == Some("")
+        }
+        EventType::RoomMember => {
+            let membership = pdu
+                .content
+                .get("membership")
+                .and_then(|membership| membership.as_str());
+
+            (membership == Some("leave") || membership == Some("ban"))
+                && pdu.state_key.as_ref() != Some(&pdu.sender.to_string())
+        }
+        _ => false,
+    }
+}
+
+/// Sorts events so that auth events come before the events they authorize. Ties are broken by
+/// the power level of the sender (higher first), the origin_server_ts and the event id.
+fn reverse_topological_power_sort(
+    events: &mut EventCache<'_>,
+    event_ids: &HashSet<EventId>,
+) -> Result<Vec<EventId>> {
+    // For every event, the number of its auth events that were not sorted yet
+    let mut missing_auth_events = HashMap::new();
+    let mut dependents = HashMap::<EventId, Vec<EventId>>::new();
+    let mut sort_keys = HashMap::new();
+
+    for event_id in event_ids {
+        let pdu = match events.get(event_id)? {
+            Some(pdu) => pdu,
+            None => continue,
+        };
+
+        let auth_events = events
+            .auth_chain(event_id)?
+            .into_iter()
+            .filter(|auth_id| event_ids.contains(auth_id))
+            .collect::<Vec<_>>();
+
+        missing_auth_events.insert(event_id.clone(), auth_events.len());
+        for auth_id in auth_events {
+            dependents
+                .entry(auth_id)
+                .or_default()
+                .push(event_id.clone());
+        }
+
+        sort_keys.insert(
+            event_id.clone(),
+            (
+                Reverse(events.sender_power_level(&pdu)?),
+                pdu.origin_server_ts,
+            ),
+        );
+    }
+
+    let mut ready = BinaryHeap::new();
+    for (event_id, count) in &missing_auth_events {
+        if *count == 0 {
+            let (power, ts) = sort_keys[event_id];
+            ready.push(Reverse((power, ts, event_id.clone())));
+        }
+    }
 
-        for (id, count) in count_0 {
-            output_rev.push(event_map[id]);
+    let mut sorted = Vec::new();
+    while let Some(Reverse((_, _, event_id))) = ready.pop() {
+        for dependent in dependents.remove(&event_id).unwrap_or_default() {
+            let count = missing_auth_events
+                .get_mut(&dependent)
+                .expect("all dependents were counted");
+            *count -= 1;
 
-            for auth_event in event_map[id].auth_events {
-                incoming_edges[auth_event.event_id] -= 1;
+            if *count == 0 {
+                let (power, ts) = sort_keys[&dependent];
+                ready.push(Reverse((power, ts, dependent)));
             }
+        }
 
-            incoming_edges.remove(id);
+        sorted.push(event_id);
+    }
+
+    Ok(sorted)
+}
+
+/// Sorts events by their closest ancestor on the mainline of the power levels event, then by
+/// origin_server_ts and event id.
+fn mainline_sort(
+    events: &mut EventCache<'_>,
+    event_ids: &[EventId],
+    power_levels: Option<EventId>,
+) -> Result<Vec<EventId>> {
+    let mut mainline = Vec::new();
+    let mut current = match power_levels {
+        Some(event_id) => events.get(&event_id)?,
+        None => None,
+    };
+    while let Some(pdu) = current {
+        current = events.power_levels_event(&pdu)?;
+        mainline.push(pdu.event_id);
+    }
+
+    // The oldest power levels event comes first, events without mainline ancestor before it
+    let mainline_positions = mainline
+        .into_iter()
+        .rev()
+        .enumerate()
+        .map(|(position, event_id)| (event_id, position + 1))
+        .collect::<HashMap<_, _>>();
+
+    let mut sort_keys = Vec::new();
+    for event_id in event_ids {
+        let pdu = match events.get(event_id)? {
+            Some(pdu) => pdu,
+            None => continue,
+        };
+        let origin_server_ts = pdu.origin_server_ts;
+
+        let mut position = 0;
+        let mut current = Some(pdu);
+        while let Some(pdu) = current {
+            if let Some(mainline_position) = mainline_positions.get(&pdu.event_id) {
+                position = *mainline_position;
+                break;
+            }
+            current = events.power_levels_event(&pdu)?;
         }
+
+        sort_keys.push((position, origin_server_ts, event_id.clone()));
     }
+
+    sort_keys.sort();
+
+    Ok(sort_keys
+        .into_iter()
+        .map(|(_, _, event_id)| event_id)
+        .collect())
+}
+
+/// Applies the events to the state one after another, skipping events that are not allowed by
+/// the auth rules.
+fn iterative_auth_check(
+    events: &mut EventCache<'_>,
+    event_ids: &[EventId],
+    state: &StateMap,
+) -> Result<StateMap> {
+    let mut state = state.clone();
+
+    for event_id in event_ids {
+        let pdu = match events.get(event_id)? {
+            Some(pdu) => pdu,
+            None => continue,
+        };
+        let state_key = match &pdu.state_key {
+            Some(state_key) => state_key.clone(),
+            None => continue,
+        };
+
+        // The event is checked against its auth events, replaced by what we resolved so far
+        let mut auth_state = HashMap::new();
+        for auth_id in &pdu.auth_events {
+            if let Some(auth_pdu) = events.get(auth_id)? {
+                if let Some(auth_state_key) = &auth_pdu.state_key {
+                    auth_state.insert((auth_pdu.kind.clone(), auth_state_key.clone()), auth_pdu);
+                }
+            }
+        }
+        for key in event_auth::auth_types(
+            &pdu.kind,
+            &pdu.sender,
+            pdu.state_key.as_deref(),
+            &pdu.content,
+        ) {
+            if let Some(state_event_id) = state.get(&key) {
+                if let Some(state_pdu) = events.get(state_event_id)? {
+                    auth_state.insert(key, state_pdu);
+                }
+            }
+        }
+
+        let allowed = event_auth::auth_check(&pdu, &|kind, state_key| {
+            Ok(auth_state
+                .get(&(kind.clone(), state_key.to_owned()))
+                .cloned())
+        });
+
+        if let Ok(true) = allowed {
+            state.insert((pdu.kind.clone(), state_key), pdu.event_id.clone());
+        }
+    }
+
+    Ok(state)
 }
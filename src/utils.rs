@@ -5,6 +5,7 @@ use sled::IVec;
 use std::{
     cmp,
     convert::TryInto,
+    net::{IpAddr, Ipv6Addr},
     time::{SystemTime, UNIX_EPOCH},
 };
 
@@ -27,6 +28,18 @@ pub fn increment(old: Option<&[u8]>) -> Option<Vec<u8>> {
     Some(number.to_be_bytes().to_vec())
 }
 
+/// Counts down from `start` and stays at zero.
+pub fn decrement_from(start: u64) -> impl Fn(Option<&[u8]>) -> Option<Vec<u8>> {
+    move |old| {
+        let number = match old.map(|bytes| bytes.try_into()) {
+            Some(Ok(bytes)) => u64::from_be_bytes(bytes).saturating_sub(1),
+            _ => start,
+        };
+
+        Some(number.to_be_bytes().to_vec())
+    }
+}
+
 pub fn generate_keypair(old: Option<&[u8]>) -> Option<Vec<u8>> {
     Some(old.map(|s| s.to_vec()).unwrap_or_else(|| {
         ruma::signatures::Ed25519KeyPair::generate()
@@ -90,3 +103,57 @@ pub fn common_elements(
             .all(|b| b)
     }))
 }
+
+/// Checks if a string matches a glob, where `*` matches any number of characters and `?`
+/// matches exactly one character.
+pub fn glob_matches(glob: &str, text: &str) -> bool {
+    let glob = glob.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+
+    let (mut g, mut t) = (0, 0);
+    // The position after the last star and the text position it currently matches up to
+    let mut last_star = None;
+
+    while t < text.len() {
+        if g < glob.len() && (glob[g] == '?' || glob[g] == text[t]) {
+            g += 1;
+            t += 1;
+        } else if g < glob.len() && glob[g] == '*' {
+            g += 1;
+            last_star = Some((g, t));
+        } else if let Some((star_g, star_t)) = last_star {
+            // Let the last star match one more character
+            g = star_g;
+            t = star_t + 1;
+            last_star = Some((star_g, t));
+        } else {
+            return false;
+        }
+    }
+
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+/// Splits a server name into its hostname and its port, if there is one.
+pub fn split_host_port(server_name: &str) -> (&str, Option<u16>) {
+    match server_name.rfind(':') {
+        // IPv6 literals contain colons themselves, so they need brackets to have a port
+        Some(index)
+            if !server_name[..index].contains(':') || server_name[..index].ends_with(']') =>
+        {
+            match server_name[index + 1..].parse() {
+                Ok(port) => (&server_name[..index], Some(port)),
+                Err(_) => (server_name, None),
+            }
+        }
+        _ => (server_name, None),
+    }
+}
+
+/// Checks if a hostname is an IPv4 address or a bracketed IPv6 address.
+pub fn is_ip_literal(hostname: &str) -> bool {
+    hostname.parse::<IpAddr>().is_ok()
+        || (hostname.starts_with('[')
+            && hostname.ends_with(']')
+            && hostname[1..hostname.len() - 1].parse::<Ipv6Addr>().is_ok())
+}